@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// A BED interval, with 0-based half-open coordinates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interval {
+    pub contig: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Reads the first three columns of a BED file
+/// empty lines, comments and track/browser lines are ignored
+pub fn read_bed(filename: &str) -> Vec<Interval> {
+    let file =
+        File::open(filename).unwrap_or_else(|_| panic!("Failure opening BED file {filename}"));
+    BufReader::new(file)
+        .lines()
+        .map(|line| line.unwrap_or_else(|_| panic!("Failure reading BED file {filename}")))
+        .filter(|line| {
+            !(line.trim().is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser"))
+        })
+        .map(|line| parse_bed_line(&line))
+        .collect()
+}
+
+fn parse_bed_line(line: &str) -> Interval {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 3 {
+        panic!("Expected at least three columns in BED line: {line}");
+    }
+    let parse_coordinate = |field: &str| {
+        field
+            .trim()
+            .parse::<u64>()
+            .unwrap_or_else(|_| panic!("Invalid coordinate {field} in BED line: {line}"))
+    };
+    Interval {
+        contig: fields[0].to_string(),
+        start: parse_coordinate(fields[1]),
+        end: parse_coordinate(fields[2]),
+    }
+}
+
+/// Reads a BED file as the value of a command line argument
+pub fn interval_counter(filename: &str) -> Result<IntervalCounter, String> {
+    if !std::path::Path::new(filename).is_file() {
+        return Err(format!("BED file {filename} does not exist"));
+    }
+    Ok(IntervalCounter::new(read_bed(filename)))
+}
+
+/// Counts the intervals overlapping a query interval, per contig
+/// using the sorted starts and ends: the overlapping intervals are those starting before
+/// the end of the query, minus those ending before its start
+#[derive(Debug, Default, Clone)]
+pub struct IntervalCounter {
+    contigs: HashMap<String, (Vec<u64>, Vec<u64>)>,
+}
+
+impl IntervalCounter {
+    pub fn new(intervals: Vec<Interval>) -> Self {
+        let mut contigs: HashMap<String, (Vec<u64>, Vec<u64>)> = HashMap::new();
+        for interval in intervals {
+            let (starts, ends) = contigs.entry(interval.contig).or_default();
+            starts.push(interval.start);
+            ends.push(interval.end);
+        }
+        for (starts, ends) in contigs.values_mut() {
+            starts.sort_unstable();
+            ends.sort_unstable();
+        }
+        IntervalCounter { contigs }
+    }
+
+    /// The number of intervals overlapping the 0-based half-open query interval
+    pub fn count_overlaps(&self, contig: &str, start: u64, end: u64) -> u64 {
+        match self.contigs.get(contig) {
+            Some((starts, ends)) => {
+                let started = starts.partition_point(|s| *s < end);
+                let ended = ends.partition_point(|e| *e <= start);
+                started.saturating_sub(ended) as u64
+            }
+            None => 0,
+        }
+    }
+}
+
+#[test]
+fn test_parse_bed_line() {
+    assert_eq!(
+        parse_bed_line("chr7\t100\t200\tfeature\t0\t+"),
+        Interval {
+            contig: "chr7".to_string(),
+            start: 100,
+            end: 200
+        }
+    );
+}
+
+#[test]
+fn test_count_overlaps() {
+    let interval = |contig: &str, start, end| Interval {
+        contig: contig.to_string(),
+        start,
+        end,
+    };
+    let counter = IntervalCounter::new(vec![
+        interval("chr1", 100, 200),
+        interval("chr1", 300, 400),
+        interval("chr1", 150, 350),
+        interval("chr2", 100, 200),
+    ]);
+    // spanning the end of the first and the start of the second feature
+    assert_eq!(counter.count_overlaps("chr1", 199, 301), 3);
+    assert_eq!(counter.count_overlaps("chr1", 200, 300), 1);
+    assert_eq!(counter.count_overlaps("chr1", 0, 100), 0);
+    assert_eq!(counter.count_overlaps("chr3", 0, 1000), 0);
+}
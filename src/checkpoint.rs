@@ -0,0 +1,70 @@
+use serde_json::json;
+use std::fs::File;
+
+/// The progress of an extraction, saved after every written batch with --checkpoint
+/// to --resume it after an interruption
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The input file, to avoid resuming with another input
+    pub input: String,
+    /// The virtual file offset in the input after the last written read
+    pub offset: i64,
+    /// The size of the output after the last written batch, a partial batch after it is discarded
+    pub output_bytes: u64,
+    /// The number of reads written
+    pub reads: u64,
+}
+
+impl Checkpoint {
+    /// Replaces the checkpoint file through a temporary file, so that an interruption
+    /// while saving does not corrupt the previous checkpoint
+    pub fn save(&self, filename: &str) {
+        let temporary = format!("{filename}.tmp");
+        let file = File::create(&temporary).expect("create checkpoint file error");
+        serde_json::to_writer_pretty(
+            file,
+            &json!({
+                "input": self.input,
+                "offset": self.offset,
+                "output_bytes": self.output_bytes,
+                "reads": self.reads,
+            }),
+        )
+        .expect("write checkpoint error");
+        std::fs::rename(&temporary, filename).expect("replace checkpoint file error");
+    }
+
+    pub fn load(filename: &str) -> Self {
+        let file = File::open(filename)
+            .unwrap_or_else(|e| panic!("Failure opening checkpoint {filename}: {e}"));
+        let json: serde_json::Value = serde_json::from_reader(file)
+            .unwrap_or_else(|e| panic!("Failure parsing checkpoint {filename}: {e}"));
+        let field = |name: &str| {
+            json.get(name)
+                .unwrap_or_else(|| panic!("Checkpoint {filename} has no {name}"))
+        };
+        Checkpoint {
+            input: field("input")
+                .as_str()
+                .expect("Invalid checkpoint input")
+                .to_string(),
+            offset: field("offset").as_i64().expect("Invalid checkpoint offset"),
+            output_bytes: field("output_bytes")
+                .as_u64()
+                .expect("Invalid checkpoint output_bytes"),
+            reads: field("reads").as_u64().expect("Invalid checkpoint reads"),
+        }
+    }
+}
+
+#[test]
+fn test_checkpoint_roundtrip() {
+    let checkpoint = Checkpoint {
+        input: "test-data/small-test-phased.bam".to_string(),
+        offset: 123 << 16 | 45,
+        output_bytes: 6789,
+        reads: 1000,
+    };
+    checkpoint.save("test-checkpoint.json");
+    assert_eq!(Checkpoint::load("test-checkpoint.json"), checkpoint);
+}
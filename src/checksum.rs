@@ -0,0 +1,72 @@
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::array_value_to_string;
+use std::hash::{Hash, Hasher};
+use twox_hash::XxHash64;
+
+/// A checksum of the values written, independent of the output format and of the order of the reads:
+/// every row is hashed, and the sorted row hashes are hashed together
+/// with a fixed seed, so that the checksum is the same across runs and Rust versions
+#[derive(Debug, Default)]
+pub struct Checksum {
+    rows: Vec<u64>,
+}
+
+impl Checksum {
+    pub fn add(&mut self, batch: &RecordBatch) {
+        for row in 0..batch.num_rows() {
+            let mut hasher = XxHash64::with_seed(0);
+            for column in batch.columns() {
+                if column.is_null(row) {
+                    None
+                } else {
+                    Some(array_value_to_string(column, row).expect("Failure formatting value"))
+                }
+                .hash(&mut hasher);
+            }
+            self.rows.push(hasher.finish());
+        }
+    }
+
+    pub fn finish(mut self) -> String {
+        self.rows.sort_unstable();
+        let mut hasher = XxHash64::with_seed(0);
+        self.rows.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+#[test]
+fn test_checksum_row_order() {
+    use arrow::array::{ArrayRef, UInt64Array};
+    use std::sync::Arc;
+    let batch = |values: Vec<Option<u64>>| {
+        RecordBatch::try_from_iter([("lengths", Arc::new(UInt64Array::from(values)) as ArrayRef)])
+            .unwrap()
+    };
+    let checksum = |batches: &[RecordBatch]| {
+        let mut checksum = Checksum::default();
+        batches.iter().for_each(|b| checksum.add(b));
+        checksum.finish()
+    };
+    let sorted = checksum(&[batch(vec![Some(1), None, Some(3)])]);
+    assert_eq!(
+        sorted,
+        checksum(&[batch(vec![Some(3)]), batch(vec![None, Some(1)])])
+    );
+    assert_ne!(sorted, checksum(&[batch(vec![Some(1), Some(0), Some(3)])]));
+}
+
+#[test]
+fn test_checksum_stable() {
+    use arrow::array::{ArrayRef, UInt64Array};
+    use std::sync::Arc;
+    let batch = RecordBatch::try_from_iter([(
+        "lengths",
+        Arc::new(UInt64Array::from(vec![Some(1), None, Some(3)])) as ArrayRef,
+    )])
+    .unwrap();
+    let mut checksum = Checksum::default();
+    checksum.add(&batch);
+    // the checksum of the same values does not change between runs or builds
+    assert_eq!(checksum.finish(), "951443c116dab368");
+}
@@ -0,0 +1,903 @@
+use arrow::array::{
+    ArrayRef, BooleanArray, DictionaryArray, Float64Array, Int32Array, Int64Array, Int8Array,
+    StringArray, UInt16Array, UInt32Array, UInt64Array, UInt8Array,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::metrics::{AlignmentType, ClipOrientation, ReadMetrics};
+use crate::modifications::ModType;
+use crate::pool::ThreadPool;
+use crate::{Cli, Filter};
+
+/// The columns that can be written to the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Identities,
+    Lengths,
+    AlignedLengths,
+    MapQ,
+    DistToContigStart,
+    DistToContigEnd,
+    RefStart,
+    RefEnd,
+    QueryStart,
+    QueryEnd,
+    RefName,
+    Strand,
+    Quals,
+    AlignedQuals,
+    MinQuality,
+    MaxQuality,
+    GcContent,
+    AlignedGcContent,
+    ModFraction(ModType),
+    MeanModProbability,
+    Channel,
+    StartTime,
+    Names,
+    Cigar,
+    ReadName,
+    NumPasses,
+    PredictedAccuracy,
+    LeftClip,
+    RightClip,
+    FivePrimeClip,
+    ThreePrimeClip,
+    ClippedFraction,
+    OverlappingFeatures,
+    Depth,
+    SecondaryCount,
+    SourceFile,
+    Sample,
+    Contig,
+    Flags,
+    BaseCount(usize),
+    RefConsumed,
+    QueryConsumed,
+    AlignmentType,
+    ReadGroupId,
+    MateDistance,
+    FragmentLength,
+    MismatchRate,
+    InsertionRate,
+    DeletionRate,
+    Barcode,
+    LongestMatchRun,
+    MinWindowIdentity,
+    CoreIdentity,
+    EditDistance,
+    IsSpliced,
+    OneSidedClip,
+    NumIntrons,
+    TotalIntronLength,
+    MaxIntronLength,
+    PassesFilter(Filter),
+    Pass,
+}
+
+impl Column {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Identities => "identities",
+            Column::Lengths => "lengths",
+            Column::AlignedLengths => "aligned_lengths",
+            Column::MapQ => "mapQ",
+            Column::DistToContigStart => "dist_to_contig_start",
+            Column::DistToContigEnd => "dist_to_contig_end",
+            Column::Quals => "quals",
+            Column::AlignedQuals => "aligned_quals",
+            Column::MinQuality => "min_quality",
+            Column::MaxQuality => "max_quality",
+            Column::GcContent => "gc_content",
+            Column::AlignedGcContent => "aligned_gc_content",
+            Column::ModFraction(mod_type) => mod_type.column_name(),
+            Column::MeanModProbability => "mean_mod_probability",
+            Column::Channel => "channel",
+            Column::StartTime => "start_time",
+            Column::Names => "names",
+            Column::Cigar => "cigar",
+            Column::ReadName => "read_name",
+            Column::NumPasses => "num_passes",
+            Column::PredictedAccuracy => "predicted_accuracy",
+            Column::LeftClip => "left_clip",
+            Column::RightClip => "right_clip",
+            Column::FivePrimeClip => "clip_5p",
+            Column::ThreePrimeClip => "clip_3p",
+            Column::ClippedFraction => "clipped_fraction",
+            Column::OverlappingFeatures => "overlapping_features",
+            Column::Depth => "depth",
+            Column::RefStart => "ref_start",
+            Column::RefEnd => "ref_end",
+            Column::QueryStart => "query_start",
+            Column::QueryEnd => "query_end",
+            Column::RefName => "ref_name",
+            Column::Strand => "strand",
+            Column::SecondaryCount => "secondary_count",
+            Column::SourceFile => "source_file",
+            Column::Sample => "sample",
+            Column::Contig => "contig",
+            Column::Flags => "flags",
+            Column::BaseCount(base) => {
+                ["count_a", "count_c", "count_g", "count_t", "count_n"][*base]
+            }
+            Column::RefConsumed => "ref_consumed",
+            Column::QueryConsumed => "query_consumed",
+            Column::AlignmentType => "alignment_type",
+            Column::ReadGroupId => "read_group_id",
+            Column::MateDistance => "mate_distance",
+            Column::FragmentLength => "fragment_length",
+            Column::MismatchRate => "mismatch_rate",
+            Column::InsertionRate => "insertion_rate",
+            Column::DeletionRate => "deletion_rate",
+            Column::Barcode => "barcode",
+            Column::LongestMatchRun => "longest_match_run",
+            Column::MinWindowIdentity => "min_window_identity",
+            Column::CoreIdentity => "core_identity",
+            Column::EditDistance => "edit_distance",
+            Column::IsSpliced => "is_spliced",
+            Column::OneSidedClip => "one_sided_clip",
+            Column::NumIntrons => "num_introns",
+            Column::TotalIntronLength => "total_intron_length",
+            Column::MaxIntronLength => "max_intron_length",
+            Column::PassesFilter(filter) => filter.column_name(),
+            Column::Pass => "pass",
+        }
+    }
+
+    /// The estimated size in bytes of the value of this column for one read
+    /// for variable-length columns this includes the 4 byte offset
+    /// for dictionary-encoded columns only the key is counted, booleans are counted as a byte
+    fn estimated_size(&self, read: &ReadMetrics) -> usize {
+        let data_type = match self.field().data_type() {
+            DataType::Dictionary(key, _) => *key.clone(),
+            data_type => data_type.clone(),
+        };
+        match data_type.primitive_width() {
+            Some(width) => width,
+            None if data_type == DataType::Boolean => 1,
+            None => {
+                4 + match self {
+                    Column::StartTime => read.start_time.as_ref().map_or(0, |s| s.len()),
+                    Column::Names | Column::ReadName => read.name.as_ref().map_or(0, |s| s.len()),
+                    Column::Cigar => read.cigar.as_ref().map_or(0, |s| s.len()),
+                    Column::Barcode => read.barcode.as_ref().map_or(0, |s| s.len()),
+                    Column::SourceFile => read.source_file.as_ref().map_or(0, |s| s.len()),
+                    Column::Sample => read.sample.as_ref().map_or(0, |s| s.len()),
+                    Column::Contig | Column::RefName => read.contig.as_ref().map_or(0, |s| s.len()),
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    pub fn field(&self) -> Field {
+        match self {
+            Column::Identities => Field::new(self.name(), DataType::Float64, true),
+            Column::Lengths => Field::new(self.name(), DataType::UInt64, false),
+            Column::AlignedLengths => Field::new(self.name(), DataType::UInt64, true),
+            Column::MapQ => Field::new(self.name(), DataType::UInt8, false),
+            Column::MinQuality | Column::MaxQuality => {
+                Field::new(self.name(), DataType::UInt8, true)
+            }
+            Column::DistToContigStart | Column::DistToContigEnd => {
+                Field::new(self.name(), DataType::UInt64, true)
+            }
+            Column::Quals
+            | Column::AlignedQuals
+            | Column::GcContent
+            | Column::AlignedGcContent
+            | Column::ClippedFraction
+            | Column::Depth
+            | Column::MinWindowIdentity
+            | Column::CoreIdentity
+            | Column::ModFraction(_)
+            | Column::MeanModProbability
+            | Column::MismatchRate
+            | Column::InsertionRate
+            | Column::DeletionRate => Field::new(self.name(), DataType::Float64, true),
+            Column::Channel => Field::new(self.name(), DataType::UInt32, true),
+            Column::StartTime | Column::Cigar => Field::new(self.name(), DataType::Utf8, true),
+            Column::Names | Column::ReadName => Field::new(self.name(), DataType::Utf8, false),
+            Column::NumPasses => Field::new(self.name(), DataType::Int32, true),
+            Column::PredictedAccuracy => Field::new(self.name(), DataType::Float64, true),
+            Column::LeftClip
+            | Column::RightClip
+            | Column::FivePrimeClip
+            | Column::ThreePrimeClip
+            | Column::OverlappingFeatures
+            | Column::RefStart
+            | Column::RefEnd
+            | Column::QueryStart
+            | Column::QueryEnd
+            | Column::SecondaryCount
+            | Column::BaseCount(_)
+            | Column::RefConsumed
+            | Column::QueryConsumed
+            | Column::LongestMatchRun
+            | Column::EditDistance
+            | Column::NumIntrons
+            | Column::TotalIntronLength
+            | Column::MaxIntronLength
+            | Column::ReadGroupId
+            | Column::FragmentLength => Field::new(self.name(), DataType::UInt64, true),
+            // every dictionary-encoded column needs its own dictionary id
+            Column::AlignmentType => Field::new_dict(
+                self.name(),
+                DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+                true,
+                0,
+                false,
+            ),
+            Column::Strand => Field::new_dict(
+                self.name(),
+                DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+                true,
+                1,
+                false,
+            ),
+            // dictionary-encoded with --polars-optimized
+            Column::RefName => Field::new_dict(self.name(), DataType::Utf8, true, 2, false),
+            Column::MateDistance => Field::new(self.name(), DataType::Int64, true),
+            // only dictionary-encoded in parquet output, as arrow files require the same dictionary in every batch
+            Column::Barcode => Field::new(self.name(), DataType::Utf8, true),
+            Column::SourceFile | Column::Sample => Field::new(self.name(), DataType::Utf8, false),
+            Column::Contig => Field::new(self.name(), DataType::Utf8, true),
+            Column::Flags => Field::new(self.name(), DataType::UInt16, true),
+            Column::IsSpliced | Column::OneSidedClip | Column::PassesFilter(_) => {
+                Field::new(self.name(), DataType::Boolean, true)
+            }
+            Column::Pass => Field::new(self.name(), DataType::Boolean, false),
+        }
+    }
+
+    pub fn array(&self, reads: &[ReadMetrics]) -> ArrayRef {
+        match self {
+            Column::Identities => {
+                Arc::new(Float64Array::from_iter(reads.iter().map(|r| r.identity)))
+            }
+            Column::Lengths => Arc::new(UInt64Array::from_iter_values(
+                reads.iter().map(|r| r.length),
+            )),
+            Column::AlignedLengths => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.aligned_length),
+            )),
+            Column::MapQ => Arc::new(UInt8Array::from_iter_values(reads.iter().map(|r| r.mapq))),
+            Column::DistToContigStart => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.dist_to_contig_start),
+            )),
+            Column::DistToContigEnd => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.dist_to_contig_end),
+            )),
+            Column::Quals => Arc::new(Float64Array::from_iter(reads.iter().map(|r| r.quality))),
+            Column::AlignedQuals => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.aligned_quality),
+            )),
+            Column::MinQuality => Arc::new(UInt8Array::from_iter(
+                reads.iter().map(|r| r.quality_range.map(|range| range.0)),
+            )),
+            Column::MaxQuality => Arc::new(UInt8Array::from_iter(
+                reads.iter().map(|r| r.quality_range.map(|range| range.1)),
+            )),
+            Column::GcContent => {
+                Arc::new(Float64Array::from_iter(reads.iter().map(|r| r.gc_content)))
+            }
+            Column::AlignedGcContent => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.aligned_gc_content),
+            )),
+            Column::ModFraction(mod_type) => {
+                Arc::new(Float64Array::from_iter(reads.iter().map(|r| {
+                    r.mod_fractions
+                        .iter()
+                        .find(|(t, _)| t == mod_type)
+                        .map(|(_, fraction)| *fraction)
+                })))
+            }
+            Column::MeanModProbability => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.mean_mod_probability),
+            )),
+            Column::Channel => Arc::new(UInt32Array::from_iter(reads.iter().map(|r| r.channel))),
+            Column::StartTime => Arc::new(StringArray::from_iter(
+                reads.iter().map(|r| r.start_time.as_deref()),
+            )),
+            Column::Names | Column::ReadName => Arc::new(StringArray::from_iter(
+                reads.iter().map(|r| r.name.as_deref()),
+            )),
+            Column::Cigar => Arc::new(StringArray::from_iter(
+                reads.iter().map(|r| r.cigar.as_deref()),
+            )),
+            Column::NumPasses => {
+                Arc::new(Int32Array::from_iter(reads.iter().map(|r| r.num_passes)))
+            }
+            Column::PredictedAccuracy => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.predicted_accuracy),
+            )),
+            Column::LeftClip | Column::FivePrimeClip => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.clips.map(|c| c.0)),
+            )),
+            Column::RightClip | Column::ThreePrimeClip => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.clips.map(|c| c.1)),
+            )),
+            Column::ClippedFraction => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.clipped_fraction),
+            )),
+            Column::OverlappingFeatures => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.overlapping_features),
+            )),
+            Column::Depth => Arc::new(Float64Array::from_iter(reads.iter().map(|r| r.depth))),
+            Column::RefStart => Arc::new(UInt64Array::from_iter(reads.iter().map(|r| r.ref_start))),
+            Column::RefEnd => Arc::new(UInt64Array::from_iter(reads.iter().map(|r| r.ref_end))),
+            Column::QueryStart => {
+                Arc::new(UInt64Array::from_iter(reads.iter().map(|r| r.query_start)))
+            }
+            Column::QueryEnd => Arc::new(UInt64Array::from_iter(reads.iter().map(|r| r.query_end))),
+            Column::SourceFile => Arc::new(StringArray::from_iter(
+                reads.iter().map(|r| r.source_file.as_deref()),
+            )),
+            Column::Sample => Arc::new(StringArray::from_iter(
+                reads.iter().map(|r| r.sample.as_deref()),
+            )),
+            Column::BaseCount(base) => Arc::new(UInt64Array::from_iter(
+                reads
+                    .iter()
+                    .map(|r| r.base_counts.map(|counts| counts[*base])),
+            )),
+            Column::Flags => Arc::new(UInt16Array::from_iter(reads.iter().map(|r| r.flags))),
+            Column::Contig => Arc::new(StringArray::from_iter(
+                reads.iter().map(|r| r.contig.as_deref()),
+            )),
+            Column::SecondaryCount => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.secondary_count),
+            )),
+            Column::RefConsumed => {
+                Arc::new(UInt64Array::from_iter(reads.iter().map(|r| r.ref_consumed)))
+            }
+            Column::QueryConsumed => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.query_consumed),
+            )),
+            Column::FragmentLength => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.fragment_length),
+            )),
+            Column::ReadGroupId => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.read_group_id),
+            )),
+            Column::AlignmentType => {
+                let keys =
+                    Int8Array::from_iter(reads.iter().map(|r| r.alignment_type.map(|t| t as i8)));
+                let values = StringArray::from_iter_values(AlignmentType::NAMES);
+                Arc::new(
+                    DictionaryArray::try_new(keys, Arc::new(values))
+                        .expect("Failure creating alignment type dictionary"),
+                )
+            }
+            Column::Strand => {
+                let keys = Int8Array::from_iter(reads.iter().map(|r| r.reverse.map(|v| v as i8)));
+                let values = StringArray::from_iter_values(["+", "-"]);
+                Arc::new(
+                    DictionaryArray::try_new(keys, Arc::new(values))
+                        .expect("Failure creating strand dictionary"),
+                )
+            }
+            Column::RefName => Arc::new(StringArray::from_iter(
+                reads.iter().map(|r| r.contig.as_deref()),
+            )),
+            Column::MateDistance => {
+                Arc::new(Int64Array::from_iter(reads.iter().map(|r| r.mate_distance)))
+            }
+            Column::MismatchRate => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.mismatch_rate),
+            )),
+            Column::InsertionRate => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.insertion_rate),
+            )),
+            Column::DeletionRate => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.deletion_rate),
+            )),
+            Column::Barcode => Arc::new(StringArray::from_iter(
+                reads.iter().map(|r| r.barcode.as_deref()),
+            )),
+            Column::LongestMatchRun => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.longest_match_run),
+            )),
+            Column::MinWindowIdentity => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.min_window_identity),
+            )),
+            Column::CoreIdentity => Arc::new(Float64Array::from_iter(
+                reads.iter().map(|r| r.core_identity),
+            )),
+            Column::EditDistance => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.edit_distance),
+            )),
+            Column::IsSpliced => {
+                Arc::new(BooleanArray::from_iter(reads.iter().map(|r| r.is_spliced)))
+            }
+            Column::OneSidedClip => Arc::new(BooleanArray::from_iter(
+                reads.iter().map(|r| r.one_sided_clip),
+            )),
+            Column::NumIntrons => {
+                Arc::new(UInt64Array::from_iter(reads.iter().map(|r| r.num_introns)))
+            }
+            Column::TotalIntronLength => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.total_intron_length),
+            )),
+            Column::MaxIntronLength => Arc::new(UInt64Array::from_iter(
+                reads.iter().map(|r| r.max_intron_length),
+            )),
+            Column::PassesFilter(filter) => {
+                Arc::new(BooleanArray::from_iter(reads.iter().map(|r| {
+                    r.filters
+                        .iter()
+                        .find(|(f, _)| f == filter)
+                        .map(|(_, passes)| *passes)
+                })))
+            }
+            Column::Pass => Arc::new(BooleanArray::from(
+                reads
+                    .iter()
+                    .map(|r| r.filters.iter().all(|(_, passes)| *passes))
+                    .collect::<Vec<bool>>(),
+            )),
+        }
+    }
+}
+
+/// The columns to write, in order, based on the arguments
+pub fn selected_columns(args: &Cli) -> Vec<Column> {
+    if args.intervals {
+        return vec![
+            Column::ReadName,
+            Column::RefStart,
+            Column::RefEnd,
+            Column::Strand,
+        ];
+    }
+    let mut columns = vec![
+        Column::Identities,
+        Column::Lengths,
+        Column::AlignedLengths,
+        Column::MapQ,
+    ];
+    if args.edge_distance {
+        columns.extend([Column::DistToContigStart, Column::DistToContigEnd]);
+    }
+    if args.positions {
+        columns.extend([Column::RefStart, Column::RefEnd]);
+    }
+    if args.query_coords {
+        columns.extend([Column::QueryStart, Column::QueryEnd]);
+    }
+    if args.ref_name_and_strand {
+        columns.extend([Column::RefName, Column::Strand]);
+    }
+    if args.quality {
+        columns.push(Column::Quals);
+    }
+    if args.quality_aligned {
+        columns.push(Column::AlignedQuals);
+    }
+    if args.quality_range {
+        columns.extend([Column::MinQuality, Column::MaxQuality]);
+    }
+    if args.gc_aligned {
+        columns.extend([Column::GcContent, Column::AlignedGcContent]);
+    }
+    columns.extend(args.mod_types.iter().map(|t| Column::ModFraction(*t)));
+    if args.mod_mean {
+        columns.push(Column::MeanModProbability);
+    }
+    if args.nanopore_tags {
+        columns.extend([Column::Channel, Column::StartTime]);
+    }
+    if args.include_names {
+        columns.push(Column::Names);
+    }
+    if args.include_cigar {
+        columns.push(Column::Cigar);
+    }
+    if args.hifi_tags {
+        columns.extend([Column::NumPasses, Column::PredictedAccuracy]);
+    }
+    if args.clips {
+        columns.extend(match args.clip_orientation {
+            ClipOrientation::Reference => [Column::LeftClip, Column::RightClip],
+            ClipOrientation::Read => [Column::FivePrimeClip, Column::ThreePrimeClip],
+        });
+        columns.push(Column::ClippedFraction);
+    }
+    if args.one_sided_clip {
+        columns.push(Column::OneSidedClip);
+    }
+    if args.annotation_bed.is_some() {
+        columns.push(Column::OverlappingFeatures);
+    }
+    if args.annotate_depth {
+        columns.push(Column::Depth);
+    }
+    if args.secondary_count {
+        columns.push(Column::SecondaryCount);
+    }
+    if args.include_flags_column {
+        columns.push(Column::Flags);
+    }
+    if args.base_composition {
+        columns.extend((0..5).map(Column::BaseCount));
+    }
+    if args.input_fofn.is_some() {
+        columns.push(Column::SourceFile);
+    }
+    if args.sample_label.is_some() {
+        columns.push(Column::Sample);
+    }
+    if args.cigar_consumption {
+        columns.extend([Column::RefConsumed, Column::QueryConsumed]);
+    }
+    if args.link_supplementary {
+        columns.push(Column::ReadGroupId);
+    }
+    if args.alignment_type || args.link_supplementary {
+        columns.push(Column::AlignmentType);
+    }
+    if args.mate_distance {
+        columns.push(Column::MateDistance);
+    }
+    if args.fragment_lengths {
+        columns.push(Column::FragmentLength);
+    }
+    if args.error_rates {
+        columns.extend([
+            Column::MismatchRate,
+            Column::InsertionRate,
+            Column::DeletionRate,
+        ]);
+    }
+    if args.linked_read {
+        columns.push(Column::Barcode);
+    }
+    if args.longest_match_run {
+        columns.push(Column::LongestMatchRun);
+    }
+    if args.min_window_identity {
+        columns.push(Column::MinWindowIdentity);
+    }
+    if args.core_fraction.is_some() {
+        columns.push(Column::CoreIdentity);
+    }
+    if args.edit_distance {
+        columns.push(Column::EditDistance);
+    }
+    if args.splicing {
+        columns.extend([
+            Column::IsSpliced,
+            Column::NumIntrons,
+            Column::TotalIntronLength,
+            Column::MaxIntronLength,
+        ]);
+    }
+    if args.annotate_filters {
+        columns.extend(Filter::active(args).into_iter().map(Column::PassesFilter));
+        columns.push(Column::Pass);
+    }
+    let selection = args.column_selection();
+    if !selection.is_empty() {
+        let names: Vec<String> = selection.iter().map(|(name, _)| name.clone()).collect();
+        columns = ordered_columns(&columns, &names, true);
+        for (column, (name, data_type)) in columns.iter().zip(&selection) {
+            let actual = column.field().data_type().to_string();
+            if data_type
+                .as_ref()
+                .is_some_and(|data_type| *data_type != actual)
+            {
+                panic!(
+                    "Column {name} has type {actual}, not {}",
+                    data_type.as_ref().unwrap()
+                );
+            }
+        }
+    } else if !args.column_order.is_empty() {
+        columns = ordered_columns(&columns, &args.column_order, false);
+    }
+    // the partition column is not written, but used to route the reads to the partitions
+    if args.parquet_partition_by.is_some() {
+        columns.push(Column::Contig);
+    }
+    columns
+}
+
+/// Puts the columns with the given names first, in that order
+/// followed by the remaining columns if these are not filtered out
+fn ordered_columns(columns: &[Column], names: &[String], filter: bool) -> Vec<Column> {
+    let mut ordered: Vec<Column> = names
+        .iter()
+        .map(|name| {
+            *columns
+                .iter()
+                .find(|c| c.name() == name)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "Column {name} is not available, the columns are {}",
+                        columns
+                            .iter()
+                            .map(|c| c.name())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })
+        })
+        .collect();
+    if !filter {
+        let remaining: Vec<Column> = columns
+            .iter()
+            .filter(|c| !ordered.contains(c))
+            .copied()
+            .collect();
+        ordered.extend(remaining);
+    }
+    ordered
+}
+
+/// The estimated size in bytes of one read in the output
+pub fn estimated_size(columns: &[Column], read: &ReadMetrics) -> usize {
+    columns.iter().map(|c| c.estimated_size(read)).sum()
+}
+
+pub fn schema(columns: &[Column]) -> Schema {
+    Schema::new(columns.iter().map(|c| c.field()).collect::<Vec<Field>>())
+}
+
+/// The type of a column with --polars-optimized: the most compact type that polars reads
+/// without conversion, which is a 32 bit float for fractions, 32 bit read lengths
+/// and a categorical ref_name
+fn compact_type(field: &Field) -> DataType {
+    match (field.name().as_str(), field.data_type()) {
+        (_, DataType::Float64) => DataType::Float32,
+        ("lengths" | "aligned_lengths", _) => DataType::UInt32,
+        ("ref_name", _) => {
+            DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+        }
+        (_, data_type) => data_type.clone(),
+    }
+}
+
+pub fn compact_schema(schema: &Schema) -> Schema {
+    Schema::new(
+        schema
+            .fields()
+            .iter()
+            .map(|f| f.as_ref().clone().with_data_type(compact_type(f)))
+            .collect::<Vec<Field>>(),
+    )
+}
+
+/// Converts the batch to the compact_schema, with the contigs as the values of the ref_name dictionary
+/// which is the same in every batch, as arrow files do not allow replacing a dictionary
+pub fn compact_batch(batch: &RecordBatch, contigs: &[String]) -> RecordBatch {
+    let schema = Arc::new(compact_schema(&batch.schema()));
+    let arrays = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(array, field)| {
+            if field.name() == Column::RefName.name() {
+                ref_name_dictionary(array, contigs)
+            } else {
+                cast(array, field.data_type()).expect("Failure converting column to compact type")
+            }
+        })
+        .collect();
+    RecordBatch::try_new(schema, arrays).expect("Failure creating arrow record batch")
+}
+
+fn ref_name_dictionary(names: &ArrayRef, contigs: &[String]) -> ArrayRef {
+    let index: HashMap<&str, i32> = contigs
+        .iter()
+        .enumerate()
+        .map(|(i, contig)| (contig.as_str(), i as i32))
+        .collect();
+    let keys = names
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .expect("ref_name is not a string column")
+        .iter()
+        .map(|name| {
+            name.map(|name| {
+                *index.get(name).unwrap_or_else(|| {
+                    panic!("Contig {name} is not in the header of the first input, which has the ref_name categories")
+                })
+            })
+        });
+    Arc::new(
+        DictionaryArray::try_new(
+            Int32Array::from_iter(keys),
+            Arc::new(StringArray::from_iter_values(contigs)),
+        )
+        .expect("Failure creating ref_name dictionary"),
+    )
+}
+
+/// The schema as JSON, listing the name, type and nullability of each column
+pub fn schema_json(schema: &Schema) -> String {
+    let fields: Vec<serde_json::Value> = schema
+        .fields()
+        .iter()
+        .map(|f| {
+            serde_json::json!({
+                "name": f.name(),
+                "data_type": format!("{:?}", f.data_type()),
+                "nullable": f.is_nullable(),
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&serde_json::json!({ "fields": fields }))
+        .expect("Failure serializing schema to JSON")
+}
+
+/// The columns split in at most `threads` groups of about equal size, of which the arrays are built in parallel
+fn column_groups(columns: &[Column], threads: usize) -> std::slice::Chunks<'_, Column> {
+    let threads = threads.clamp(1, columns.len().max(1));
+    columns.chunks(columns.len().div_ceil(threads).max(1))
+}
+
+/// With a thread pool, the arrays of groups of columns are built in parallel by its threads
+/// which share the reads of the batch
+pub fn record_batch(
+    columns: &[Column],
+    reads: Vec<ReadMetrics>,
+    pool: Option<&ThreadPool>,
+) -> RecordBatch {
+    let arrays = match pool {
+        Some(pool) if pool.threads() > 1 && columns.len() > 1 => {
+            let reads = Arc::new(reads);
+            let jobs = column_groups(columns, pool.threads()).map(|chunk| {
+                let (chunk, reads) = (chunk.to_vec(), Arc::clone(&reads));
+                move || chunk.iter().map(|c| c.array(&reads)).collect::<Vec<_>>()
+            });
+            pool.map(jobs).into_iter().flatten().collect()
+        }
+        _ => columns.iter().map(|c| c.array(&reads)).collect(),
+    };
+    RecordBatch::try_new(Arc::new(schema(columns)), arrays)
+        .expect("Failure creating arrow record batch")
+}
+
+#[test]
+fn test_schema_json() {
+    use clap::Parser;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--edge-distance"]);
+    let json: serde_json::Value =
+        serde_json::from_str(&schema_json(&schema(&selected_columns(&args)))).unwrap();
+    let names: Vec<&str> = json["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "identities",
+            "lengths",
+            "aligned_lengths",
+            "mapQ",
+            "dist_to_contig_start",
+            "dist_to_contig_end"
+        ]
+    );
+    assert_eq!(json["fields"][4]["data_type"], "UInt64");
+    assert_eq!(json["fields"][4]["nullable"], true);
+}
+
+#[test]
+fn test_column_order() {
+    use clap::Parser;
+    let names = |arguments: &[&str]| -> Vec<String> {
+        let args = Cli::parse_from(arguments);
+        schema(&selected_columns(&args))
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect()
+    };
+    assert_eq!(
+        names(&[
+            "make_arrow",
+            "input.bam",
+            "--quality",
+            "--columns",
+            "quals,mapQ,lengths"
+        ]),
+        ["quals", "mapQ", "lengths"]
+    );
+    assert_eq!(
+        names(&["make_arrow", "input.bam", "--column-order", "mapQ,lengths"]),
+        ["mapQ", "lengths", "identities", "aligned_lengths"]
+    );
+}
+
+#[test]
+#[should_panic(expected = "Column quals is not available")]
+fn test_unavailable_column() {
+    use clap::Parser;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--columns", "lengths,quals"]);
+    selected_columns(&args);
+}
+
+#[test]
+fn test_columns_from_file() {
+    use clap::Parser;
+    std::fs::write(
+        "test-columns.txt",
+        "# the quality first\nquals\n\nmapQ:UInt8\n  lengths : UInt64\n",
+    )
+    .unwrap();
+    let args = Cli::parse_from([
+        "make_arrow",
+        "input.bam",
+        "--quality",
+        "--columns-from-file",
+        "test-columns.txt",
+    ]);
+    let columns = selected_columns(&args);
+    assert_eq!(
+        schema(&columns),
+        Schema::new(vec![
+            Field::new("quals", DataType::Float64, true),
+            Field::new("mapQ", DataType::UInt8, false),
+            Field::new("lengths", DataType::UInt64, false),
+        ])
+    );
+}
+
+#[test]
+#[should_panic(expected = "Column mapQ has type UInt8, not UInt64")]
+fn test_columns_from_file_type() {
+    use clap::Parser;
+    std::fs::write("test-columns-type.txt", "lengths\nmapQ:UInt64\n").unwrap();
+    let args = Cli::parse_from([
+        "make_arrow",
+        "input.bam",
+        "--columns-from-file",
+        "test-columns-type.txt",
+    ]);
+    selected_columns(&args);
+}
+
+#[test]
+fn test_parallel_record_batch() {
+    use clap::Parser;
+    let args = Cli::parse_from([
+        "make_arrow",
+        "input.bam",
+        "--include-names",
+        "--clips",
+        "--alignment-type",
+        "--splicing",
+        "--edge-distance",
+    ]);
+    let columns = selected_columns(&args);
+    let reads: Vec<ReadMetrics> = (0..1000)
+        .map(|i| ReadMetrics {
+            identity: (i % 3 != 0).then_some(90.0 + i as f64 / 100.0),
+            length: i * 10,
+            mapq: (i % 60) as u8,
+            name: Some(format!("read_{i}")),
+            clips: Some((i, i / 2)),
+            alignment_type: Some(AlignmentType::Primary),
+            num_introns: Some(i % 4),
+            ..Default::default()
+        })
+        .collect();
+    let serial = record_batch(&columns, reads.clone(), None);
+    for threads in [1, 2, 4, 64] {
+        let pool = ThreadPool::new(threads);
+        // the pool is reused for every batch
+        for _ in 0..3 {
+            assert_eq!(record_batch(&columns, reads.clone(), Some(&pool)), serial);
+        }
+        assert!(column_groups(&columns, threads).len() <= threads);
+    }
+    assert_eq!(column_groups(&columns, 64).len(), columns.len());
+    assert_eq!(column_groups(&[], 4).len(), 0);
+}
@@ -0,0 +1,101 @@
+use rust_htslib::{bam, bam::ext::BamRecordExtensions, bam::HeaderView};
+use std::collections::HashMap;
+
+/// The approximate coverage depth per window of the reference, from a pass over the reads
+/// as the exact depth at a position requires a pileup
+pub struct Depth {
+    window: u64,
+    /// The aligned bases per window, by the tid of the contig
+    bases: HashMap<i32, Vec<u64>>,
+}
+
+impl Depth {
+    pub fn new(window: u64) -> Self {
+        if window == 0 {
+            panic!("--depth-window should be at least 1");
+        }
+        Depth {
+            window,
+            bases: HashMap::new(),
+        }
+    }
+
+    /// Adds the aligned bases of a read to the windows they fall in, without deletions and introns
+    pub fn add(&mut self, record: &bam::Record) {
+        if record.is_unmapped() {
+            return;
+        }
+        let bases = self.bases.entry(record.tid()).or_default();
+        for [start, end] in record.aligned_blocks() {
+            let (mut start, end) = (start as u64, end as u64);
+            while start < end {
+                let window = (start / self.window) as usize;
+                let window_end = ((window as u64 + 1) * self.window).min(end);
+                if bases.len() <= window {
+                    bases.resize(window + 1, 0);
+                }
+                bases[window] += window_end - start;
+                start = window_end;
+            }
+        }
+    }
+
+    /// The mean depth of the window with the reference midpoint of the read, None for unmapped reads
+    /// the last window of a contig is only as long as the remainder of the contig
+    pub fn at_midpoint(&self, record: &bam::Record, header: &HeaderView) -> Option<f64> {
+        if record.is_unmapped() {
+            return None;
+        }
+        let midpoint = (record.reference_start() + record.reference_end()) as u64 / 2;
+        let window = midpoint / self.window;
+        let contig_length = header.target_len(record.tid() as u32).unwrap_or(u64::MAX);
+        let length = contig_length
+            .saturating_sub(window * self.window)
+            .clamp(1, self.window);
+        let bases = self
+            .bases
+            .get(&record.tid())
+            .and_then(|bases| bases.get(window as usize))
+            .copied()
+            .unwrap_or(0);
+        Some(bases as f64 / length as f64)
+    }
+}
+
+#[cfg(test)]
+fn mapped_record(pos: i64, matches: u32) -> bam::Record {
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    let mut record = bam::Record::new();
+    let seq = vec![b'A'; matches as usize];
+    record.set(
+        b"read",
+        Some(&CigarString(vec![Cigar::Match(matches)])),
+        &seq,
+        &vec![30; seq.len()],
+    );
+    record.set_tid(0);
+    record.set_pos(pos);
+    record
+}
+
+#[test]
+fn test_depth() {
+    let header = crate::metrics::test_header();
+    let mut depth = Depth::new(300);
+    let reads = [
+        mapped_record(0, 200),
+        mapped_record(200, 200),
+        mapped_record(850, 150),
+    ];
+    for read in &reads {
+        depth.add(read);
+    }
+    // the first window has 300 aligned bases, the second and third have 100 and 50
+    assert_eq!(depth.at_midpoint(&reads[0], &header), Some(1.0));
+    assert_eq!(depth.at_midpoint(&reads[1], &header), Some(100.0 / 300.0));
+    // the last window of chr1 of 1000 bases is 100 bases long
+    assert_eq!(depth.at_midpoint(&reads[2], &header), Some(1.0));
+    let mut unmapped = mapped_record(0, 200);
+    unmapped.set_unmapped();
+    assert_eq!(depth.at_midpoint(&unmapped, &header), None);
+}
@@ -0,0 +1,292 @@
+use arrow::array::{ArrayRef, Int64Array, StringArray};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::writer::{FileWriter, IpcWriteOptions};
+use arrow::record_batch::RecordBatch;
+use log::warn;
+use rust_htslib::bam::{self, record::Aux, record::Cigar};
+use std::fs::File;
+use std::sync::Arc;
+
+/// The number of errors per record batch written to the error detail file
+const BATCH_SIZE: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorType {
+    Substitution,
+    Insertion,
+    Deletion,
+}
+
+impl ErrorType {
+    fn name(&self) -> &'static str {
+        match self {
+            ErrorType::Substitution => "substitution",
+            ErrorType::Insertion => "insertion",
+            ErrorType::Deletion => "deletion",
+        }
+    }
+}
+
+/// A single base difference between the read and the reference
+/// insertions are at the reference position after the inserted bases and have no reference base
+/// deletions have no read base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlignmentError {
+    pub ref_pos: i64,
+    pub error_type: ErrorType,
+    pub ref_base: Option<u8>,
+    pub read_base: Option<u8>,
+}
+
+/// A reference base of the alignment as described by the MD tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MdBase {
+    Match,
+    Mismatch(u8),
+    Deleted(u8),
+}
+
+/// Expands the MD tag (e.g. 10A5^AC6) into one entry per aligned or deleted reference base
+pub fn parse_md(md: &str) -> Vec<MdBase> {
+    let mut bases = vec![];
+    let mut number = 0;
+    let mut deletion = false;
+    for c in md.bytes() {
+        match c {
+            b'0'..=b'9' => {
+                number = number * 10 + (c - b'0') as usize;
+                deletion = false;
+            }
+            b'^' => {
+                bases.extend(std::iter::repeat_n(MdBase::Match, number));
+                number = 0;
+                deletion = true;
+            }
+            base => {
+                bases.extend(std::iter::repeat_n(MdBase::Match, number));
+                number = 0;
+                bases.push(if deletion {
+                    MdBase::Deleted(base)
+                } else {
+                    MdBase::Mismatch(base)
+                });
+            }
+        }
+    }
+    bases.extend(std::iter::repeat_n(MdBase::Match, number));
+    bases
+}
+
+/// The substitutions, inserted and deleted bases of the read, from its CIGAR, sequence and MD tag
+/// None without MD tag or without sequence
+pub fn alignment_errors(record: &bam::Record) -> Option<Vec<AlignmentError>> {
+    if record.seq_len() == 0 {
+        return None;
+    }
+    let md = match record.aux(b"MD") {
+        Ok(Aux::String(md)) => parse_md(md),
+        _ => return None,
+    };
+    let seq = record.seq();
+    let mut md = md.into_iter();
+    let mut errors = vec![];
+    let mut ref_pos = record.pos();
+    let mut query_pos = 0;
+    for entry in record.cigar().iter() {
+        match entry {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                for _ in 0..*len {
+                    if let Some(MdBase::Mismatch(ref_base)) = md.next() {
+                        errors.push(AlignmentError {
+                            ref_pos,
+                            error_type: ErrorType::Substitution,
+                            ref_base: Some(ref_base),
+                            read_base: Some(seq[query_pos]),
+                        });
+                    }
+                    ref_pos += 1;
+                    query_pos += 1;
+                }
+            }
+            Cigar::Ins(len) => {
+                for _ in 0..*len {
+                    errors.push(AlignmentError {
+                        ref_pos,
+                        error_type: ErrorType::Insertion,
+                        ref_base: None,
+                        read_base: Some(seq[query_pos]),
+                    });
+                    query_pos += 1;
+                }
+            }
+            Cigar::Del(len) => {
+                for _ in 0..*len {
+                    let ref_base = match md.next() {
+                        Some(MdBase::Deleted(base)) => Some(base),
+                        _ => None,
+                    };
+                    errors.push(AlignmentError {
+                        ref_pos,
+                        error_type: ErrorType::Deletion,
+                        ref_base,
+                        read_base: None,
+                    });
+                    ref_pos += 1;
+                }
+            }
+            Cigar::RefSkip(len) => ref_pos += *len as i64,
+            Cigar::SoftClip(len) => query_pos += *len as usize,
+            Cigar::HardClip(_) | Cigar::Pad(_) => (),
+        }
+    }
+    Some(errors)
+}
+
+/// Writes the alignment errors of all reads to an arrow file, in batches
+pub struct ErrorDetailWriter {
+    writer: FileWriter<File>,
+    schema: Arc<Schema>,
+    names: Vec<String>,
+    errors: Vec<AlignmentError>,
+    warned: bool,
+}
+
+impl ErrorDetailWriter {
+    pub fn new(filename: &str, write_options: IpcWriteOptions) -> Self {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("read_name", DataType::Utf8, false),
+            Field::new("ref_pos", DataType::Int64, false),
+            Field::new("error_type", DataType::Utf8, false),
+            Field::new("ref_base", DataType::Utf8, true),
+            Field::new("read_base", DataType::Utf8, true),
+        ]));
+        let buffer = File::create(filename).expect("create error detail file error");
+        let writer = FileWriter::try_new_with_options(buffer, &schema, write_options)
+            .expect("create error detail file writer error");
+        ErrorDetailWriter {
+            writer,
+            schema,
+            names: vec![],
+            errors: vec![],
+            warned: false,
+        }
+    }
+
+    pub fn add(&mut self, record: &bam::Record) {
+        let Some(errors) = alignment_errors(record) else {
+            if !self.warned {
+                warn!("Reads without MD tag are missing from the error detail output");
+                self.warned = true;
+            }
+            return;
+        };
+        let name = String::from_utf8_lossy(record.qname()).to_string();
+        self.names.extend(std::iter::repeat_n(name, errors.len()));
+        self.errors.extend(errors);
+        if self.errors.len() >= BATCH_SIZE {
+            self.write_batch();
+        }
+    }
+
+    fn write_batch(&mut self) {
+        let base = |b: Option<u8>| b.map(|b| (b as char).to_string());
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(&self.names)),
+            Arc::new(Int64Array::from_iter_values(
+                self.errors.iter().map(|e| e.ref_pos),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                self.errors.iter().map(|e| e.error_type.name()),
+            )),
+            Arc::new(StringArray::from_iter(
+                self.errors.iter().map(|e| base(e.ref_base)),
+            )),
+            Arc::new(StringArray::from_iter(
+                self.errors.iter().map(|e| base(e.read_base)),
+            )),
+        ];
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)
+            .expect("Failure creating error detail record batch");
+        self.writer
+            .write(&batch)
+            .expect("write error detail batch error");
+        self.names.clear();
+        self.errors.clear();
+    }
+
+    pub fn finish(mut self) {
+        if !self.errors.is_empty() {
+            self.write_batch();
+        }
+        self.writer
+            .finish()
+            .expect("finish write error detail error");
+    }
+}
+
+#[test]
+fn test_parse_md() {
+    use MdBase::*;
+    assert_eq!(
+        parse_md("2A1^GT0C1"),
+        vec![
+            Match,
+            Match,
+            Mismatch(b'A'),
+            Match,
+            Deleted(b'G'),
+            Deleted(b'T'),
+            Mismatch(b'C'),
+            Match
+        ]
+    );
+}
+
+#[test]
+fn test_alignment_errors() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    // reference ACGTACGTAC aligned at position 100 with a substitution of T by G at 103
+    // an insertion of A after 105 and a deletion of 107
+    record.set(
+        b"errors",
+        Some(&CigarString(vec![
+            Cigar::SoftClip(2),
+            Cigar::Match(6),
+            Cigar::Ins(1),
+            Cigar::Match(1),
+            Cigar::Del(1),
+            Cigar::Match(2),
+        ])),
+        b"TTACGGACAGAC",
+        &[30; 12],
+    );
+    record.set_pos(100);
+    record.push_aux(b"MD", Aux::String("3T3^T2")).unwrap();
+    let errors = alignment_errors(&record).unwrap();
+    assert_eq!(
+        errors,
+        vec![
+            AlignmentError {
+                ref_pos: 103,
+                error_type: ErrorType::Substitution,
+                ref_base: Some(b'T'),
+                read_base: Some(b'G'),
+            },
+            AlignmentError {
+                ref_pos: 106,
+                error_type: ErrorType::Insertion,
+                ref_base: None,
+                read_base: Some(b'A'),
+            },
+            AlignmentError {
+                ref_pos: 107,
+                error_type: ErrorType::Deletion,
+                ref_base: Some(b'T'),
+                read_base: None,
+            },
+        ]
+    );
+    record.remove_aux(b"MD").unwrap();
+    assert_eq!(alignment_errors(&record), None);
+}
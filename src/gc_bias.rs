@@ -0,0 +1,74 @@
+use arrow::datatypes::{DataType, Field, Schema};
+use std::fs::File;
+use std::sync::Arc;
+
+use arrow::{
+    array::{UInt64Array, UInt8Array},
+    ipc::writer::{FileWriter, IpcWriteOptions},
+    record_batch::RecordBatch,
+};
+
+/// GC content is binned per percent, so there are 101 bins (0% up to and including 100%)
+const GC_BINS: usize = 101;
+
+/// The number of reads and total aligned bases per GC content bin
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcBias {
+    reads: Vec<u64>,
+    aligned_bases: Vec<u64>,
+}
+
+impl Default for GcBias {
+    fn default() -> Self {
+        GcBias {
+            reads: vec![0; GC_BINS],
+            aligned_bases: vec![0; GC_BINS],
+        }
+    }
+}
+
+impl GcBias {
+    /// Adds a read with a GC content between 0 and 1
+    pub fn add(&mut self, gc_content: f64, aligned_length: u64) {
+        let bin = ((gc_content * 100.0).floor() as usize).min(GC_BINS - 1);
+        self.reads[bin] += 1;
+        self.aligned_bases[bin] += aligned_length;
+    }
+
+    pub fn save(&self, filename: &str, write_options: IpcWriteOptions) {
+        let gc_bin_array = Arc::new(UInt8Array::from_iter_values(0..GC_BINS as u8)) as _;
+        let reads_array = Arc::new(UInt64Array::from(self.reads.clone())) as _;
+        let aligned_bases_array = Arc::new(UInt64Array::from(self.aligned_bases.clone())) as _;
+        let batch = RecordBatch::try_from_iter([
+            ("gc_bin", gc_bin_array),
+            ("reads", reads_array),
+            ("aligned_bases", aligned_bases_array),
+        ])
+        .unwrap();
+
+        let schema = Schema::new(vec![
+            Field::new("gc_bin", DataType::UInt8, false),
+            Field::new("reads", DataType::UInt64, false),
+            Field::new("aligned_bases", DataType::UInt64, false),
+        ]);
+        let buffer = File::create(filename).expect("create gc bias file error");
+
+        let mut writer = FileWriter::try_new_with_options(buffer, &schema, write_options)
+            .expect("create gc bias file writer error");
+
+        writer.write(&batch).expect("write gc bias batch error");
+        writer.finish().expect("finish write gc bias error");
+    }
+}
+
+#[test]
+fn test_gc_bias_bins() {
+    let mut gc_bias = GcBias::default();
+    for (gc, aligned_length) in [(0.0, 10), (0.405, 20), (0.409, 30), (1.0, 40)] {
+        gc_bias.add(gc, aligned_length);
+    }
+    assert_eq!(gc_bias.reads[0], 1);
+    assert_eq!(gc_bias.reads[40], 2);
+    assert_eq!(gc_bias.aligned_bases[40], 50);
+    assert_eq!(gc_bias.aligned_bases[100], 40);
+}
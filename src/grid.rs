@@ -0,0 +1,105 @@
+use arrow::datatypes::{DataType, Field, Schema};
+use std::fs::File;
+use std::sync::Arc;
+
+use arrow::{
+    array::{Float64Array, UInt64Array},
+    ipc::writer::{FileWriter, IpcWriteOptions},
+    record_batch::RecordBatch,
+};
+
+/// Lengths are binned on a log10 scale up to 10 Mb, longer reads end up in the last bin
+const MAX_LOG_LENGTH: f64 = 7.0;
+
+/// The number of reads per read length and identity bin, for plotting a 2D histogram
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid {
+    length_bins: usize,
+    identity_bins: usize,
+    counts: Vec<u64>,
+}
+
+impl Grid {
+    pub fn new(length_bins: usize, identity_bins: usize) -> Self {
+        Grid {
+            length_bins,
+            identity_bins,
+            counts: vec![0; length_bins * identity_bins],
+        }
+    }
+
+    /// Adds a read with an identity between 0 and 100
+    pub fn add(&mut self, length: u64, identity: f64) {
+        let log_length = (length.max(1) as f64).log10();
+        let length_bin = ((log_length / MAX_LOG_LENGTH * self.length_bins as f64) as usize)
+            .min(self.length_bins - 1);
+        let identity_bin = ((identity.max(0.0) / 100.0 * self.identity_bins as f64) as usize)
+            .min(self.identity_bins - 1);
+        self.counts[length_bin * self.identity_bins + identity_bin] += 1;
+    }
+
+    /// The smallest length in the length bin
+    fn length_bin_start(&self, bin: usize) -> u64 {
+        10f64
+            .powf(bin as f64 * MAX_LOG_LENGTH / self.length_bins as f64)
+            .ceil() as u64
+    }
+
+    /// The smallest identity in the identity bin
+    fn identity_bin_start(&self, bin: usize) -> f64 {
+        bin as f64 * 100.0 / self.identity_bins as f64
+    }
+
+    /// Writes the non-empty bins, with the start of the length and identity bins
+    pub fn save(&self, filename: &str, write_options: IpcWriteOptions) {
+        let cells: Vec<(usize, usize, u64)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| (i / self.identity_bins, i % self.identity_bins, *count))
+            .collect();
+        let length_bin_array = Arc::new(UInt64Array::from_iter_values(
+            cells.iter().map(|c| self.length_bin_start(c.0)),
+        )) as _;
+        let identity_bin_array = Arc::new(Float64Array::from_iter_values(
+            cells.iter().map(|c| self.identity_bin_start(c.1)),
+        )) as _;
+        let count_array = Arc::new(UInt64Array::from_iter_values(cells.iter().map(|c| c.2))) as _;
+        let batch = RecordBatch::try_from_iter([
+            ("length_bin", length_bin_array),
+            ("identity_bin", identity_bin_array),
+            ("count", count_array),
+        ])
+        .unwrap();
+
+        let schema = Schema::new(vec![
+            Field::new("length_bin", DataType::UInt64, false),
+            Field::new("identity_bin", DataType::Float64, false),
+            Field::new("count", DataType::UInt64, false),
+        ]);
+        let buffer = File::create(filename).expect("create grid summary file error");
+
+        let mut writer = FileWriter::try_new_with_options(buffer, &schema, write_options)
+            .expect("create grid summary file writer error");
+
+        writer
+            .write(&batch)
+            .expect("write grid summary batch error");
+        writer.finish().expect("finish write grid summary error");
+    }
+}
+
+#[test]
+fn test_grid_bins() {
+    let mut grid = Grid::new(7, 10);
+    for (length, identity) in [(0, 0.0), (9, 95.0), (10, 99.9), (20_000_000, 100.0)] {
+        grid.add(length, identity);
+    }
+    assert_eq!(grid.counts[9], 1);
+    assert_eq!(grid.counts[19], 1);
+    assert_eq!(grid.counts[6 * 10 + 9], 1);
+    assert_eq!(grid.counts.iter().sum::<u64>(), 4);
+    assert_eq!(grid.length_bin_start(1), 10);
+    assert_eq!(grid.identity_bin_start(9), 90.0);
+}
@@ -1,44 +1,681 @@
-use arrow::datatypes::{DataType, Field, Schema};
-use clap::Parser;
-use log::info;
-use rust_htslib::bam::ext::BamRecordExtensions;
-use rust_htslib::bam::record::{Aux, Cigar};
-use rust_htslib::{bam, bam::Read, htslib};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::{error, info, warn};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use regex::bytes::Regex;
+use rust_htslib::{bam, bam::ext::BamRecordExtensions, bam::HeaderView, bam::Read, htslib};
+use std::collections::{HashMap, HashSet};
+#[cfg(test)]
 use std::fs::File;
+use std::io::BufRead;
 use std::path::PathBuf;
-use std::sync::Arc;
-use unzip_n::unzip_n;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+mod bed;
+mod checkpoint;
+mod checksum;
+mod columns;
+mod depth;
+mod error_detail;
+mod gc_bias;
+mod grid;
+mod merge;
+mod metrics;
+mod modifications;
+mod output;
+mod pool;
+mod position_bins;
+mod profile;
+mod reference;
+mod sampling;
+mod summary;
+mod tag_summary;
+mod validate;
 
 use arrow::{
     self,
-    array::{Float64Array, UInt64Array, UInt8Array},
-    ipc::writer::FileWriter,
+    ipc::{writer::IpcWriteOptions, CompressionType, MetadataVersion},
     record_batch::RecordBatch,
 };
 
 // The arguments end up in the Cli struct
 #[derive(Parser, Debug)]
 #[command(author, version, about="Tool to extract metrics from cram or bam to an arrow file", long_about = None)]
-struct Cli {
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// cram or bam file (or '-' for stdin)
-    #[arg(value_parser)]
-    input: String,
+    #[arg(value_parser, required_unless_present = "input_fofn")]
+    input: Option<String>,
+
+    /// Process the cram or bam files listed in this file, one per line, adding a source_file column
+    #[arg(long, value_parser, conflicts_with = "input")]
+    input_fofn: Option<String>,
 
-    /// Number of parallel decompression threads to use
+    /// Number of inputs of the --input-fofn processed in parallel, each with --threads decompression threads, which writes the reads of different inputs in an unpredictable order
+    #[arg(long, value_parser, default_value_t = 1, requires = "input_fofn")]
+    file_parallelism: usize,
+
+    /// Print the time spent reading, calculating metrics, building and writing record batches to stderr
+    #[arg(long, value_parser)]
+    profile: bool,
+
+    /// Add a sample column with this label for every read, e.g. to combine the output of several runs
+    #[arg(long, value_parser, value_name = "STR")]
+    sample_label: Option<String>,
+
+    /// Number of parallel BGZF/CRAM decompression threads to use, at most twice the number of cores
     #[arg(short, long, value_parser, default_value_t = 4)]
     threads: usize,
 
-    /// Output file name
+    /// Output file name, - for stdout with --format arrow-stream, or several comma-separated names of which the format is inferred from the extension
     #[arg(short, long, value_parser, default_value_t = String::from("read_metrics.arrow"))]
     output: String,
+
+    /// Save the progress to this file after every written batch, to --resume an interrupted run, for tsv and jsonl output of a bam file
+    #[arg(long, value_parser, conflicts_with_all = ["input_fofn", "shuffle", "region", "regions_bed", "per_contig_window"])]
+    checkpoint: Option<String>,
+
+    /// Continue the run of the --checkpoint where it was interrupted, appending to its output
+    #[arg(long, value_parser, requires = "checkpoint", conflicts_with_all = ["summary", "length_stats", "gc_bias", "grid_summary", "error_detail", "position_bins", "emit_checksum"])]
+    resume: bool,
+
+    /// Write the log messages to this file instead of stderr
+    #[arg(long, value_parser)]
+    log_file: Option<String>,
+
+    /// Output file format
+    #[arg(short, long, value_enum, default_value_t = output::OutputFormat::Arrow)]
+    format: output::OutputFormat,
+
+    /// Text written for missing values in tsv and jsonl output, instead of an empty field or null
+    #[arg(long, value_parser)]
+    na_string: Option<String>,
+
+    /// Write a parquet dataset to the --output directory, with a file per partition as contig=<name>/part.parquet and the names escaped as by Hive, at most 256 files are open at once and a partition written after its file was closed continues in part-1.parquet etc
+    #[arg(long, value_enum)]
+    parquet_partition_by: Option<output::PartitionBy>,
+
+    /// Write a file per mapping quality bucket, split at the lowest mapping quality of each bucket after the first, e.g. 1,31,61 for 0, 1-30, 31-60 and 61-255, named as the output with .mapq<min>-<max> before the extension
+    #[arg(long, value_parser, value_delimiter = ',', conflicts_with_all = ["parquet_partition_by", "checkpoint", "columns", "columns_from_file", "intervals"])]
+    split_by_mapq: Option<Vec<u8>>,
+
+    /// Number of reads per record batch written to the output, or its size with a unit, e.g. 256MB
+    #[arg(long, value_parser = clap::value_parser!(output::BatchSize), default_value = "100000")]
+    batch_size: output::BatchSize,
+
+    /// Size in bytes of the buffer between the arrow writer and the output file, larger buffers need fewer writes
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 8192)]
+    write_buffer_size: u64,
+
+    /// Flush the outputs after every record batch, so that a reader of a growing arrow-stream, arrow, feather, tsv or jsonl output sees the batches as they are written, such as a reader of -o - with --format arrow-stream
+    #[arg(long, value_parser)]
+    flush_each_batch: bool,
+
+    /// Build the arrays of the columns of each record batch in parallel, by a pool of --threads threads which is started once
+    #[arg(long, value_parser)]
+    prefer_parallel_batches: bool,
+
+    /// Write the reads in a random order, which requires keeping all reads in memory
+    #[arg(long, value_parser)]
+    shuffle: bool,
+
+    /// Seed of the random order of --shuffle, the same seed gives the same order
+    #[arg(long, value_parser, default_value_t = 0, requires = "shuffle")]
+    seed: u64,
+
+    /// Write a JSON summary with the mean, standard deviation, minimum and maximum per metric, and the length-weighted mean identity
+    #[arg(long, value_parser)]
+    summary: Option<String>,
+
+    /// Log the total number of bases and of aligned bases of the written reads, and add them to the --summary
+    #[arg(long, value_parser, conflicts_with = "intervals")]
+    count_bases: bool,
+
+    /// Write a JSON file with the read length N50, mean, median and total bases
+    #[arg(long, value_parser)]
+    length_stats: Option<String>,
+
+    /// Count every read once in the --length-stats, using the length of its primary alignment
+    #[arg(long, value_parser, requires = "length_stats")]
+    unique_reads_for_stats: bool,
+
+    /// Maximum number of rows per row group in parquet output
+    #[arg(long, value_parser, default_value_t = 128 * 1024)]
+    row_group_size: usize,
+
+    /// Write the number of reads and aligned bases per GC content bin to this arrow file
+    #[arg(long, value_parser)]
+    gc_bias: Option<String>,
+
+    /// Also keep alignments with these flags, which are excluded by default, e.g. 256 for secondary alignments
+    #[arg(long, value_parser, default_value_t = 0)]
+    include_flags: u16,
+
+    /// Write the number of reads per read length and identity bin to this arrow file
+    #[arg(long, value_parser)]
+    grid_summary: Option<String>,
+
+    /// Number of log10-scaled read length bins up to 10 Mb in the --grid-summary
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 50)]
+    grid_length_bins: u64,
+
+    /// Number of identity bins in the --grid-summary
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 50)]
+    grid_identity_bins: u64,
+
+    /// Write every substitution, inserted and deleted base of reads with an MD tag to this arrow file
+    #[arg(long, value_parser)]
+    error_detail: Option<String>,
+
+    /// Split the aligned part of every read with an MD tag in this number of bins, without introns and numbered from the start of the read, and write the mismatches per bin to the --position-bins-output
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), requires = "position_bins_output")]
+    position_bins: Option<u64>,
+
+    /// The arrow file to write the mismatches per --position-bins to, with a row per read and bin
+    #[arg(long, value_parser, requires = "position_bins")]
+    position_bins_output: Option<String>,
+
+    /// Only keep reads aligned to these contigs, e.g. chr1,chr2
+    #[arg(long, value_parser, value_delimiter = ',')]
+    contigs: Vec<String>,
+
+    /// Exclude reads aligned to these contigs, e.g. chrM,chrEBV
+    #[arg(long, value_parser, value_delimiter = ',', conflicts_with = "contigs")]
+    exclude_contigs: Vec<String>,
+
+    /// Exclude reads of which the reference span overlaps an interval in this BED file, e.g. the ENCODE blacklist
+    #[arg(long, value_parser = bed::interval_counter, value_name = "FILE")]
+    blacklist_bed: Option<bed::IntervalCounter>,
+
+    /// Only keep reads of which the name matches this regular expression
+    #[arg(long, value_parser = Regex::new)]
+    name_regex: Option<Regex>,
+
+    /// Add the SAM flags of each read
+    #[arg(long, value_parser)]
+    include_flags_column: bool,
+
+    /// Add the number of A, C, G, T and other bases of each read
+    #[arg(long, value_parser)]
+    base_composition: bool,
+
+    /// Add the 0-based start and exclusive end of the alignment on the reference
+    #[arg(long, value_parser)]
+    positions: bool,
+
+    /// Add the 0-based start and exclusive end of the aligned bases in the read as sequenced, including clipped bases and from the 5' end for reverse strand reads
+    #[arg(long, value_parser)]
+    query_coords: bool,
+
+    /// Only write the read name, 0-based start, exclusive end and strand of each alignment, as a BED-like interval table
+    #[arg(long, value_parser, conflicts_with_all = ["columns", "column_order", "summary", "length_stats", "gc_bias", "grid_summary"])]
+    intervals: bool,
+
+    /// Add the contig (ref_name) and strand (+ or -) of each alignment
+    #[arg(long, value_parser)]
+    ref_name_and_strand: bool,
+
+    /// Only keep reads of which the alignment spans at least this number of reference bases
+    #[arg(long, value_parser)]
+    min_ref_span: Option<u64>,
+
+    /// Only keep reads of which at least this fraction, including clipped bases, is aligned
+    #[arg(long, value_parser = metrics::parse_fraction)]
+    min_aligned_fraction: Option<f64>,
+
+    /// Only keep reads aligned to this strand
+    #[arg(long, value_enum, default_value_t = Strand::Both)]
+    strand: Strand,
+
+    /// Keep every read, including unmapped reads and secondary alignments, adding whether it passes the flag filter and each other filter as a pass_<filter> column, and whether it passes all as a pass column
+    #[arg(long, value_parser, conflicts_with_all = ["intervals", "count_only"])]
+    annotate_filters: bool,
+
+    /// Write the most compact types that polars reads without conversion: 32 bit floats and read lengths, and a categorical ref_name
+    #[arg(long, value_parser)]
+    polars_optimized: bool,
+
+    /// Arrow IPC metadata version to write, V4 can be used for compatibility with older readers
+    #[arg(long, value_enum, ignore_case = true, default_value_t = IpcVersion::V5)]
+    ipc_version: IpcVersion,
+
+    /// Add the distance of each alignment to the start and end of its contig
+    #[arg(long, value_parser)]
+    edge_distance: bool,
+
+    /// Print the output schema as JSON and exit without processing reads
+    #[arg(long, value_parser)]
+    json_schema_out: bool,
+
+    /// Only process reads overlapping this region (requires an index), or with - the regions read from stdin one per line, in order
+    #[arg(short, long, value_parser)]
+    region: Option<String>,
+
+    /// Only process reads overlapping the intervals in this BED file (requires an index)
+    #[arg(long, value_parser, conflicts_with = "region")]
+    regions_bed: Option<String>,
+
+    /// Only process reads overlapping the first this many bases of every contig (requires an index)
+    #[arg(long, value_parser, conflicts_with_all = ["region", "regions_bed"])]
+    per_contig_window: Option<u64>,
+
+    /// Add the mean base quality of each read
+    #[arg(long, value_parser)]
+    quality: bool,
+
+    /// Add the mean base quality of the aligned part of each read, excluding soft-clipped bases
+    #[arg(long, value_parser)]
+    quality_aligned: bool,
+
+    /// Add the GC content of each read, and of its aligned part excluding soft-clipped bases
+    #[arg(long, value_parser)]
+    gc_aligned: bool,
+
+    /// Add the minimum and maximum base quality of each read
+    #[arg(long, value_parser)]
+    quality_range: bool,
+
+    /// How the base qualities are combined for --quality and --quality-aligned
+    #[arg(long, value_enum, default_value_t = metrics::QualityMethod::MeanPhred)]
+    quality_method: metrics::QualityMethod,
+
+    /// The FASTA reference to decode CRAM files, uncompressed or compressed with bgzip
+    #[arg(long, value_parser)]
+    reference: Option<String>,
+
+    /// Do not download CRAM references from the EBI server if they are not found locally
+    #[arg(long, value_parser)]
+    no_ref_download: bool,
+
+    /// Number of retries with exponential backoff if decoding a CRAM file fails, e.g. when fetching the reference
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = 0,
+        conflicts_with = "no_ref_download"
+    )]
+    ref_retries: u32,
+
+    /// Add the fraction of modified bases from the MM/ML tags per modification type, e.g. 5mC,6mA
+    #[arg(long, value_enum, value_delimiter = ',')]
+    mod_types: Vec<modifications::ModType>,
+
+    /// Add the mean ML probability of the calls of the --mod-types, or of all modification calls
+    #[arg(long, value_parser)]
+    mod_mean: bool,
+
+    /// Check that the file, its index if a region is requested, the CRAM reference and the NM/de tags are available, without writing output
+    #[arg(long, value_parser)]
+    validate: bool,
+
+    /// Only print the number of reads passing the filters, without writing output
+    #[arg(long, value_parser)]
+    count_only: bool,
+
+    /// Print a checksum of the extracted values, independent of the output format and the order of the reads
+    #[arg(long, value_parser)]
+    emit_checksum: bool,
+
+    /// Only print which aux tags, of which type, are present in the first reads, without writing output
+    #[arg(long, value_parser, value_name = "READS")]
+    tag_summary: Option<usize>,
+
+    /// Always calculate the identity from the CIGAR and NM tag, ignoring the de tag
+    #[arg(long, value_parser)]
+    prefer_computed_identity: bool,
+
+    /// Take the identity from the de or NM tag, or only from the =/X CIGAR operations of an extended CIGAR
+    #[arg(long, value_enum, default_value_t = metrics::IdentitySource::Tags)]
+    identity_source: metrics::IdentitySource,
+
+    /// Identities outside this range as min:max are clamped to it, and counted in the --summary
+    #[arg(long, value_parser = metrics::parse_identity_range, default_value = "0:100")]
+    identity_range: (f64, f64),
+
+    /// Fail on reads without the NM tag required for their identity, instead of leaving the identity missing
+    #[arg(long, value_parser)]
+    fail_on_missing_tag: bool,
+
+    /// Write reads of which the identity can not be calculated, without NM tag, CIGAR or aligned bases, with a missing identity, with NaN, or drop them
+    #[arg(long, value_enum, default_value_t = metrics::MissingIdentity::Null, conflicts_with = "intervals")]
+    on_missing_identity: metrics::MissingIdentity,
+
+    /// Write reads mapped beyond the end of their contig, which are corrupt, without aligned length and positions, or drop them
+    #[arg(long, value_enum, default_value_t = metrics::ContigOverrun::Null)]
+    on_contig_overrun: metrics::ContigOverrun,
+
+    /// Do not count insertions and deletions in homopolymers as errors in the identity, ignoring the de tag (deletions require an MD tag)
+    #[arg(long, value_parser)]
+    identity_ignore_homopolymer: bool,
+
+    /// Count soft-clipped bases as mismatches in the identity, ignoring the de tag
+    #[arg(long, value_parser)]
+    clip_penalty: bool,
+
+    /// Add the channel (ch tag) and start time (st tag) of Oxford Nanopore reads
+    #[arg(long, value_parser)]
+    nanopore_tags: bool,
+
+    /// Add the read names
+    #[arg(long, value_parser)]
+    include_names: bool,
+
+    /// Add the CIGAR strings, which substantially increases the output size
+    #[arg(long, value_parser)]
+    include_cigar: bool,
+
+    /// Add the number of passes (np tag) and predicted accuracy (rq tag) of PacBio HiFi reads
+    #[arg(long, value_parser)]
+    hifi_tags: bool,
+
+    /// Add the number of soft- and hard-clipped bases at both ends of each read, and the clipped fraction of the read
+    #[arg(long, value_parser)]
+    clips: bool,
+
+    /// Report clips as left/right along the reference, or as 5'/3' of the read
+    #[arg(long, value_enum, default_value_t = metrics::ClipOrientation::Reference, requires = "clips")]
+    clip_orientation: metrics::ClipOrientation,
+
+    /// Add whether only one end of each read is soft-clipped by at least --clip-min bases, which can indicate a breakpoint
+    #[arg(long, value_parser)]
+    one_sided_clip: bool,
+
+    /// Minimal length of the soft clips of --one-sided-clip
+    #[arg(long, value_parser, default_value_t = 100, requires = "one_sided_clip")]
+    clip_min: u64,
+
+    /// Add the number of intervals in this BED file overlapped by the reference span of each read
+    #[arg(long, value_parser)]
+    annotation_bed: Option<String>,
+
+    /// Add the number of secondary alignments of each read to its primary alignment, which reads the input twice and is not possible from stdin
+    #[arg(long, value_parser)]
+    secondary_count: bool,
+
+    /// Downsample reads with a rate per length bin as min_length:rate, e.g. 0:0.1,10000:0.5,50000:1
+    #[arg(long, value_parser = clap::value_parser!(sampling::LengthSampling))]
+    sample_rate_by_length: Option<sampling::LengthSampling>,
+
+    /// Add the approximate coverage depth at the reference midpoint of each read, as the mean depth of the reads passing the filters in its --depth-window, which reads the input twice and is not possible from stdin
+    #[arg(long, value_parser)]
+    annotate_depth: bool,
+
+    /// The length of the reference windows of --annotate-depth
+    #[arg(
+        long,
+        value_parser,
+        default_value_t = 1000,
+        requires = "annotate_depth"
+    )]
+    depth_window: u64,
+
+    /// Add the number of reference- and query-consuming CIGAR bases, to validate CIGAR parsing
+    #[arg(long, value_parser)]
+    cigar_consumption: bool,
+
+    /// Add whether each alignment is primary, secondary or supplementary
+    #[arg(long, value_parser)]
+    alignment_type: bool,
+
+    /// Add an id shared by the alignments of each read, as a hash of the read name which is the same in every run, and the alignment type, to link primary and supplementary alignments
+    #[arg(long, value_parser)]
+    link_supplementary: bool,
+
+    /// Add the distance from the start of each paired read to the start of its mate on the same contig
+    #[arg(long, value_parser)]
+    mate_distance: bool,
+
+    /// Only keep the primary alignments of properly paired reads, adding their fragment length as the absolute template length, and the number, mean and median of the fragments, counting every pair once, to the --summary
+    #[arg(long, value_parser)]
+    fragment_lengths: bool,
+
+    /// Add the number of mismatches, insertions and deletions per 1000 aligned bases
+    #[arg(long, value_parser)]
+    error_rates: bool,
+
+    /// Add the barcode (BX tag) of linked reads, which is dictionary-encoded in parquet output but a plain string column in arrow output, as arrow files only allow the same dictionary in every batch
+    #[arg(long, value_parser)]
+    linked_read: bool,
+
+    /// Add the longest run of matching bases, which is an upper bound without =/X CIGAR operations
+    #[arg(long, value_parser)]
+    longest_match_run: bool,
+
+    /// Add the lowest percentage of matches in a window sliding along the alignment, which requires an MD tag or =/X CIGAR operations
+    #[arg(long, value_parser)]
+    min_window_identity: bool,
+
+    /// Number of alignment columns (aligned bases, and a single column per insertion or deletion as in the gap-compressed identity) in the windows of --min-window-identity
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 100, requires = "min_window_identity")]
+    window_size: u64,
+
+    /// Add the percentage of matches in this central fraction of the alignment columns, leaving out the noisier alignment ends, which requires an MD tag or =/X CIGAR operations
+    #[arg(long, value_parser = metrics::parse_fraction)]
+    core_fraction: Option<f64>,
+
+    /// Add the NM tag used to calculate the identity, missing if the identity is from the de tag or the extended CIGAR
+    #[arg(long, value_parser)]
+    edit_distance: bool,
+
+    /// Add whether each read is spliced, its number of introns (N CIGAR operations) and their total and maximum length, for RNA alignments
+    #[arg(long, value_parser)]
+    splicing: bool,
+
+    /// Only write these columns, in this order, e.g. lengths,identities
+    #[arg(long, value_parser, value_delimiter = ',')]
+    columns: Vec<String>,
+
+    /// Write these columns first, in this order, followed by the other columns
+    #[arg(long, value_parser, value_delimiter = ',', conflicts_with = "columns")]
+    column_order: Vec<String>,
+
+    /// Only write the columns listed in this file, one per line in order, as name or as name:type to check the type without --polars-optimized, e.g. mapQ:UInt8, ignoring blank lines and comments starting with #
+    #[arg(long, value_parser, conflicts_with_all = ["columns", "column_order", "intervals"])]
+    columns_from_file: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Merge arrow files with identical schemas into one arrow file
+    Merge {
+        /// Arrow files to merge
+        #[arg(value_parser, required = true)]
+        inputs: Vec<String>,
+
+        /// Output file name
+        #[arg(short, long, value_parser, default_value_t = String::from("merged.arrow"))]
+        output: String,
+    },
+}
+
+impl Cli {
+    /// The input file, which is required unless a subcommand is used
+    fn input(&self) -> &str {
+        self.input.as_deref().expect("No input file provided")
+    }
+
+    /// The files listed in --input-fofn, or the input file
+    fn inputs(&self) -> Vec<String> {
+        match &self.input_fofn {
+            Some(fofn) => read_list(fofn),
+            None => vec![self.input().to_string()],
+        }
+    }
+
+    /// The --columns, or the columns listed in the --columns-from-file with their optional type
+    fn column_selection(&self) -> Vec<(String, Option<String>)> {
+        match &self.columns_from_file {
+            Some(path) => read_list(path)
+                .into_iter()
+                .map(|line| match line.split_once(':') {
+                    Some((name, data_type)) => {
+                        (name.trim().to_string(), Some(data_type.trim().to_string()))
+                    }
+                    None => (line, None),
+                })
+                .collect(),
+            None => self
+                .columns
+                .iter()
+                .map(|name| (name.clone(), None))
+                .collect(),
+        }
+    }
+
+    /// The output files with their format: the --format of a single --output
+    /// or the format inferred from the extension of every comma-separated output
+    fn outputs(&self) -> Vec<(&str, output::OutputFormat)> {
+        if !self.output.contains(',') {
+            return vec![(self.output.as_str(), self.format)];
+        }
+        self.output
+            .split(',')
+            .map(|path| {
+                let format = output::OutputFormat::from_extension(path).unwrap_or_else(|| {
+                    panic!("Unknown format of output {path}, expected an .arrow, .arrows, .feather, .parquet, .tsv or .jsonl extension")
+                });
+                (path, format)
+            })
+            .collect()
+    }
+}
+
+/// Reads a list with an entry per line, such as a file of files, ignoring blank lines and comments starting with #
+fn read_list(path: &str) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap_or_else(|_| panic!("Failure reading {path}"))
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum IpcVersion {
+    V4,
+    V5,
+}
+
+impl From<IpcVersion> for MetadataVersion {
+    fn from(version: IpcVersion) -> Self {
+        match version {
+            IpcVersion::V4 => MetadataVersion::V4,
+            IpcVersion::V5 => MetadataVersion::V5,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Strand {
+    Forward,
+    Reverse,
+    Both,
+}
+
+impl Strand {
+    fn keep(&self, read: &bam::Record) -> bool {
+        match self {
+            Strand::Forward => !read.is_reverse(),
+            Strand::Reverse => read.is_reverse(),
+            Strand::Both => true,
+        }
+    }
+}
+
+/// The options used for all arrow files written, using the default 64 byte alignment
+fn write_options(args: &Cli) -> IpcWriteOptions {
+    IpcWriteOptions::try_new(64, false, args.ipc_version.into())
+        .expect("Failure setting arrow write options")
 }
 
 fn main() {
-    env_logger::init();
-    let args = Cli::parse();
-    is_file(&args.input).unwrap_or_else(|_| panic!("Input file {} is invalid", args.input));
+    let mut args = Cli::parse();
+    logger(args.log_file.as_deref()).init();
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    args.threads = clamp_threads(args.threads, cores);
+    if let Some(Command::Merge { inputs, output }) = &args.command {
+        for input in inputs {
+            check_output_path(input, &[output]).unwrap_or_else(|e| panic!("{e}"));
+        }
+        merge::merge(inputs, output, write_options(&args));
+        return;
+    }
+    if args.json_schema_out {
+        println!(
+            "{}",
+            columns::schema_json(&output_schema(&columns::selected_columns(&args), &args))
+        );
+        return;
+    }
+    let inputs = args.inputs();
+    let outputs = output_paths(&args);
+    let outputs: Vec<&str> = outputs.iter().map(String::as_str).collect();
+    for input in &inputs {
+        is_file(input).unwrap_or_else(|_| panic!("Input file {input} is invalid"));
+        check_output_path(input, &outputs).unwrap_or_else(|e| panic!("{e}"));
+    }
     info!("Collected arguments");
-    extract(&args.input, args.output, args.threads)
+    if let Some(reference) = &args.reference {
+        reference::check_reference(reference).unwrap_or_else(|e| panic!("{e}"));
+    }
+    if args.no_ref_download {
+        reference::disable_ref_download();
+    }
+    if args.validate {
+        let problems: Vec<String> = inputs
+            .iter()
+            .flat_map(|input| validate::validate(input, &args))
+            .collect();
+        if problems.is_empty() {
+            for input in &inputs {
+                println!("{input} is valid");
+            }
+            return;
+        }
+        for problem in problems {
+            error!("{problem}");
+        }
+        std::process::exit(1);
+    }
+    if args.count_only {
+        let count: usize = inputs.iter().map(|input| count_reads(input, &args)).sum();
+        println!("{count}");
+        return;
+    }
+    if let Some(reads) = args.tag_summary {
+        tag_summary::print_tag_summary(&tag_summary::tag_summary(&inputs, reads, &args));
+        return;
+    }
+    if let Some(checksum) = extract(&inputs, &args) {
+        println!("{checksum}");
+    }
+}
+
+/// The logger, configured with RUST_LOG, writing to stderr or to the log file
+fn logger(log_file: Option<&str>) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(log_file) = log_file {
+        let file = std::fs::File::create(log_file)
+            .unwrap_or_else(|_| panic!("Failure creating log file {log_file}"));
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder
+}
+
+/// Limits the decompression threads to twice the number of cores, to avoid oversubscription
+fn clamp_threads(threads: usize, cores: usize) -> usize {
+    let max_threads = cores * 2;
+    if threads > max_threads {
+        warn!("Requested {threads} threads, but using {max_threads} for {cores} cores");
+        max_threads
+    } else {
+        threads
+    }
 }
 
 pub fn is_file(pathname: &str) -> Result<(), String> {
@@ -53,139 +690,2993 @@ pub fn is_file(pathname: &str) -> Result<(), String> {
     }
 }
 
-pub fn extract(bam_path: &String, output_path: String, threads: usize) {
-    let mut bam = if bam_path == "-" {
-        bam::Reader::from_stdin().expect("\n\nError reading alignments from stdin.\nDid you include the file header with -h?\n\n\n\n")
-    } else {
-        bam::Reader::from_path(bam_path)
-            .expect("Error opening BAM/CRAM file.\nIs the input file correct?\n\n\n\n")
-    };
-    bam.set_threads(threads).expect(&format!(
-        "Failure setting {} decompression threads",
-        threads
-    ));
-    unzip_n!(4);
-    let (lengths, aligned_lengths, mapqs, identities): (Vec<u64>, Vec<u64>, Vec<u8>, Vec<f64>) =
-        bam.rc_records()
-            .map(|r| r.expect("Failure parsing Bam file"))
-            .filter(|read| read.flags() & (htslib::BAM_FUNMAP | htslib::BAM_FSECONDARY) as u16 == 0)
-            .map(|read| {
-                (
-                    read.seq_len() as u64,
-                    (read.reference_end() - read.reference_start()) as u64,
-                    read.mapq(),
-                    gap_compressed_identity(read) * 100.0,
-                )
+/// All files written during extraction
+fn output_paths(args: &Cli) -> Vec<String> {
+    let mut paths: Vec<String> = args.output.split(',').map(String::from).collect();
+    if let Some(edges) = &args.split_by_mapq {
+        paths = paths
+            .iter()
+            .flat_map(|path| {
+                output::mapq_buckets(edges)
+                    .into_iter()
+                    .map(|bucket| output::mapq_bucket_path(path, bucket))
             })
-            .unzip_n_vec();
-    save_as_arrow(output_path, lengths, aligned_lengths, identities, mapqs);
+            .collect();
+    }
+    paths.extend(
+        [
+            &args.summary,
+            &args.length_stats,
+            &args.gc_bias,
+            &args.grid_summary,
+            &args.error_detail,
+            &args.position_bins_output,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned(),
+    );
+    paths
 }
 
-pub fn save_as_arrow(
-    filename: String,
-    lengths: Vec<u64>,
-    aligned_lengths: Vec<u64>,
-    identities: Vec<f64>,
-    mapqs: Vec<u8>,
-) {
-    let identities_array = Arc::new(Float64Array::from(identities)) as _;
-    let lengths_array = Arc::new(UInt64Array::from(lengths)) as _;
-    let aligned_lengths_array = Arc::new(UInt64Array::from(aligned_lengths)) as _;
-    let mapqs_array = Arc::new(UInt8Array::from(mapqs)) as _;
-    let batch = RecordBatch::try_from_iter([
-        ("identities", identities_array),
-        ("lengths", lengths_array),
-        ("aligned_lengths", aligned_lengths_array),
-        ("mapQ", mapqs_array),
-    ])
-    .unwrap();
+/// Makes sure the input file is not overwritten, by comparing the canonicalized paths
+/// outputs that do not exist yet can not be the input file
+fn check_output_path(input: &str, outputs: &[&str]) -> Result<(), String> {
+    let Ok(input_path) = std::fs::canonicalize(input) else {
+        return Ok(());
+    };
+    for output in outputs {
+        if std::fs::canonicalize(output).is_ok_and(|output_path| output_path == input_path) {
+            return Err(format!(
+                "Output file {output} is the same as input file {input}, refusing to overwrite it"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The schema of the columns, with the compact types of --polars-optimized
+fn output_schema(columns: &[columns::Column], args: &Cli) -> arrow::datatypes::Schema {
+    let schema = columns::schema(columns);
+    if args.polars_optimized {
+        columns::compact_schema(&schema)
+    } else {
+        schema
+    }
+}
 
-    let schema = Schema::new(vec![
-        Field::new("identities", DataType::Float64, false),
-        Field::new("lengths", DataType::UInt64, false),
-        Field::new("aligned_lengths", DataType::UInt64, false),
-        Field::new("mapQ", DataType::UInt8, false),
-    ]);
-    let buffer = File::create(filename).expect("create arrow file error");
-
-    let mut writer = FileWriter::try_new(buffer, &schema).expect("create arrow file writer error");
-
-    writer.write(&batch).expect("write arrow batch error");
-    writer.finish().expect("finish write arrow error");
-}
-
-/// Calculates the gap-compressed identity
-/// based on https://lh3.github.io/2018/11/25/on-the-definition-of-sequence-identity
-/// recent minimap2 version have that as the de tag
-/// if that is not present it is calculated from CIGAR and NM
-fn gap_compressed_identity(record: std::rc::Rc<rust_htslib::bam::Record>) -> f64 {
-    match get_de_tag(&record) {
-        Some(v) => v as f64,
-        None => {
-            let mut matches = 0;
-            let mut gap_size = 0;
-            let mut gap_count = 0;
-            for entry in record.cigar().iter() {
-                match entry {
-                    Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
-                        matches += *len;
+/// Writes the metrics of the reads of all inputs, returns the checksum with --emit-checksum
+fn extract(bam_paths: &[String], args: &Cli) -> Option<String> {
+    let outputs = args.outputs();
+    if args.parquet_partition_by.is_some()
+        && (outputs.len() > 1 || outputs[0].1 != output::OutputFormat::Parquet)
+    {
+        panic!("--parquet-partition-by requires a single output with --format parquet");
+    }
+    if args.checkpoint.is_some() {
+        if outputs.len() > 1 {
+            panic!("--checkpoint requires a single output");
+        }
+        if !matches!(
+            outputs[0].1,
+            output::OutputFormat::Tsv | output::OutputFormat::Jsonl
+        ) {
+            panic!("--checkpoint requires --format tsv or jsonl, as arrow and parquet files can not be appended to");
+        }
+        if bam_paths[0] == "-" || bam_paths[0].ends_with(".cram") {
+            panic!("--checkpoint requires a bam file, as stdin and cram files can not be resumed at an offset");
+        }
+    }
+    if args.flush_each_batch
+        && outputs
+            .iter()
+            .any(|(_, format)| *format == output::OutputFormat::Parquet)
+    {
+        panic!("--flush-each-batch requires arrow, feather, tsv or jsonl output, as parquet files are written per row group");
+    }
+    if outputs
+        .iter()
+        .any(|(path, format)| *path == "-" && *format != output::OutputFormat::ArrowStream)
+    {
+        panic!("Only --format arrow-stream can be written to stdout with -o -");
+    }
+    if args.output == "-" && (args.emit_checksum || args.split_by_mapq.is_some()) {
+        panic!("--emit-checksum and --split-by-mapq can not be used with -o -, which writes the stream to stdout");
+    }
+    let resume = args.resume.then(|| {
+        let checkpoint = checkpoint::Checkpoint::load(args.checkpoint.as_ref().unwrap());
+        if checkpoint.input != bam_paths[0] {
+            panic!(
+                "The checkpoint is of {}, not of {}",
+                checkpoint.input, bam_paths[0]
+            );
+        }
+        info!(
+            "Resuming {} after {} reads written to {}",
+            checkpoint.input, checkpoint.reads, args.output
+        );
+        checkpoint
+    });
+    let columns = columns::selected_columns(args);
+    let schema = output_schema(&columns, args);
+    let mut writers: Vec<output::OutputWriter> = outputs
+        .iter()
+        .map(|(path, format)| match &args.split_by_mapq {
+            Some(edges) => output::OutputWriter::new_mapq_split(
+                output::mapq_buckets(edges)
+                    .into_iter()
+                    .map(|bucket| {
+                        let path = output::mapq_bucket_path(path, bucket);
+                        (bucket, new_writer(&path, *format, &schema, args, None))
+                    })
+                    .collect(),
+            ),
+            None => new_writer(path, *format, &schema, args, resume.as_ref()),
+        })
+        .collect();
+    let mut summary = summary::Summary::default();
+    let mut gc_bias = gc_bias::GcBias::default();
+    let mut length_stats = summary::LengthStats::default();
+    let mut grid = grid::Grid::new(
+        args.grid_length_bins as usize,
+        args.grid_identity_bins as usize,
+    );
+    let mut error_detail = args
+        .error_detail
+        .as_ref()
+        .map(|path| error_detail::ErrorDetailWriter::new(path, write_options(args)));
+    let mut position_bins = args
+        .position_bins
+        .zip(args.position_bins_output.as_ref())
+        .map(|(bins, path)| {
+            position_bins::PositionBinWriter::new(path, bins as usize, write_options(args))
+        });
+    let annotation = args
+        .annotation_bed
+        .as_ref()
+        .map(|bed| bed::IntervalCounter::new(bed::read_bed(bed)));
+    let mut checksum = args.emit_checksum.then(checksum::Checksum::default);
+    // the threads building the arrays of every batch are started once
+    let pool = args
+        .prefer_parallel_batches
+        .then(|| pool::ThreadPool::new(args.threads));
+    if args.shuffle {
+        warn!("All reads are kept in memory to shuffle them");
+    }
+    if args.include_cigar {
+        warn!("The CIGAR strings substantially increase the output size");
+    }
+    let mut reads = vec![];
+    let mut batch_bytes = 0;
+    let mut written = resume.as_ref().map_or(0, |checkpoint| checkpoint.reads);
+    // the categories of the ref_name with --polars-optimized, from the header of the first input
+    let mut contigs: Vec<String> = vec![];
+    let mut without_identity = 0;
+    let profile = args.profile.then(profile::Profile::default);
+    let profile = profile.as_ref();
+    // the reads are added under a lock, so that the inputs can be processed in parallel
+    let add_read = Mutex::new(
+        |read: &bam::Record,
+         header: &HeaderView,
+         metrics: metrics::ReadMetrics,
+         bam_path: &String,
+         offset: Option<i64>| {
+            if args.polars_optimized && contigs.is_empty() {
+                contigs = (0..header.target_count())
+                    .map(|tid| String::from_utf8_lossy(header.tid2name(tid)).to_string())
+                    .collect();
+            }
+            if args.on_missing_identity == metrics::MissingIdentity::Drop
+                && metrics.identity.is_none()
+            {
+                without_identity += 1;
+                return;
+            }
+            summary.add(&metrics);
+            // the fragment of a pair is counted for the mate with the positive template length
+            if let Some(fragment_length) =
+                metrics.fragment_length.filter(|_| read.insert_size() > 0)
+            {
+                summary.add_fragment(fragment_length);
+            }
+            if let Some(error_detail) = &mut error_detail {
+                error_detail.add(read);
+            }
+            if let Some(position_bins) = &mut position_bins {
+                position_bins.add(read);
+            }
+            if args.length_stats.is_some() {
+                if args.unique_reads_for_stats {
+                    let primary = !(read.is_secondary() || read.is_supplementary());
+                    length_stats.add_unique(read.qname(), metrics.length, primary);
+                } else {
+                    length_stats.add(metrics.length);
+                }
+            }
+            if let (Some(_), Some(identity)) = (
+                &args.grid_summary,
+                metrics.identity.filter(|identity| !identity.is_nan()),
+            ) {
+                grid.add(metrics.length, identity);
+            }
+            if let Some(gc_content) = metrics.gc_content {
+                gc_bias.add(gc_content, metrics.aligned_length.unwrap_or(0));
+            }
+            batch_bytes += columns::estimated_size(&columns, &metrics);
+            reads.push(metrics);
+            if !args.shuffle && args.batch_size.is_full(reads.len(), batch_bytes) {
+                written += reads.len() as u64;
+                let batch = profile::Profile::time(profile, profile::Stage::Batches, || {
+                    columns::record_batch(&columns, std::mem::take(&mut reads), pool.as_ref())
+                });
+                profile::Profile::time(profile, profile::Stage::Write, || {
+                    write_batch(
+                        &mut writers,
+                        &mut checksum,
+                        args.polars_optimized.then_some(&contigs),
+                        batch,
+                        args.flush_each_batch,
+                    )
+                });
+                if let (Some(checkpoint_path), Some(offset)) = (&args.checkpoint, offset) {
+                    writers[0].flush();
+                    checkpoint::Checkpoint {
+                        input: bam_path.clone(),
+                        offset,
+                        output_bytes: std::fs::metadata(&args.output)
+                            .expect("read output size error")
+                            .len(),
+                        reads: written,
+                    }
+                    .save(checkpoint_path);
+                }
+                batch_bytes = 0;
+            }
+        },
+    );
+    // the next input to process, shared by the --file-parallelism threads
+    let next_input = AtomicUsize::new(0);
+    let process_inputs = || {
+        while let Some(bam_path) = bam_paths.get(next_input.fetch_add(1, Ordering::Relaxed)) {
+            let secondary_counts = args
+                .secondary_count
+                .then(|| count_secondary_alignments(bam_path, args));
+            let depth = args.annotate_depth.then(|| coverage_depth(bam_path, args));
+            let start = resume.as_ref().map(|checkpoint| checkpoint.offset);
+            // the reading of a read is timed from the end of adding the previous read
+            let mut decoded = Instant::now();
+            for_each_read_from(bam_path, args, start, |read, header, offset| {
+                if let Some(profile) = profile {
+                    profile.add(profile::Stage::Decode, decoded);
+                }
+                let mut metrics = profile::Profile::time(profile, profile::Stage::Metrics, || {
+                    if args.intervals {
+                        metrics::ReadMetrics::interval(read)
+                    } else {
+                        metrics::ReadMetrics::from_record(read, args, header)
                     }
-                    Cigar::Del(len) | Cigar::Ins(len) => {
-                        gap_size += *len;
-                        gap_count += 1;
+                });
+                if args.input_fofn.is_some() {
+                    metrics.source_file = Some(bam_path.clone());
+                }
+                metrics.sample.clone_from(&args.sample_label);
+                // unmapped reads have no contig, so their overlapping features are missing
+                if let Some(annotation) = annotation
+                    .as_ref()
+                    .filter(|_| read.tid() >= 0 && !read.is_unmapped())
+                {
+                    let contig = std::str::from_utf8(header.tid2name(read.tid() as u32))
+                        .expect("Invalid UTF-8 in contig name");
+                    metrics.overlapping_features = Some(annotation.count_overlaps(
+                        contig,
+                        read.reference_start() as u64,
+                        read.reference_end() as u64,
+                    ));
+                }
+                if args.annotate_filters {
+                    metrics.filters = Filter::verdicts(read, args, header);
+                }
+                if let Some(depth) = &depth {
+                    metrics.depth = depth.at_midpoint(read, header);
+                }
+                if let Some(secondary_counts) = &secondary_counts {
+                    if !(read.is_secondary() || read.is_supplementary()) {
+                        metrics.secondary_count =
+                            Some(secondary_counts.get(read.qname()).copied().unwrap_or(0));
                     }
-                    _ => (),
                 }
+                let mut add_read = add_read
+                    .lock()
+                    .expect("Failure adding a read after the extraction of another input failed");
+                add_read(read, header, metrics, bam_path, offset);
+                if profile.is_some() {
+                    decoded = Instant::now();
+                }
+            });
+        }
+    };
+    if args.file_parallelism > 1 {
+        std::thread::scope(|scope| {
+            for _ in 0..args.file_parallelism.min(bam_paths.len()) {
+                scope.spawn(process_inputs);
             }
-            1.0 - ((get_nm_tag(&record) - gap_size + gap_count) as f64
-                / (matches + gap_count) as f64)
+        });
+    } else {
+        process_inputs();
+    }
+    if args.shuffle {
+        reads.shuffle(&mut StdRng::seed_from_u64(args.seed));
+    }
+    write_batches(
+        &columns,
+        reads,
+        args.batch_size,
+        pool.as_ref(),
+        profile,
+        |batch| {
+            profile::Profile::time(profile, profile::Stage::Write, || {
+                write_batch(
+                    &mut writers,
+                    &mut checksum,
+                    args.polars_optimized.then_some(&contigs),
+                    batch,
+                    args.flush_each_batch,
+                )
+            })
+        },
+    );
+    profile::Profile::time(profile, profile::Stage::Write, || {
+        for writer in writers {
+            writer.finish();
         }
+    });
+    if let Some(error_detail) = error_detail {
+        error_detail.finish();
+    }
+    if let Some(position_bins) = position_bins {
+        position_bins.finish();
+    }
+    if let Some(gc_bias_path) = &args.gc_bias {
+        gc_bias.save(gc_bias_path, write_options(args));
+    }
+    if args.on_missing_identity == metrics::MissingIdentity::Drop {
+        info!("Dropped {without_identity} reads of which the identity can not be calculated");
+    }
+    if summary.clamped_identities() > 0 {
+        let (min, max) = args.identity_range;
+        warn!(
+            "The identity of {} reads was clamped to the range {min}-{max}",
+            summary.clamped_identities()
+        );
+    }
+    if args.count_bases {
+        let (bases, aligned_bases) = summary.bases();
+        info!("Wrote reads with {bases} bases, of which {aligned_bases} aligned");
     }
+    if let Some(summary_path) = &args.summary {
+        summary.save(summary_path, args.count_bases);
+    }
+    if let Some(length_stats_path) = &args.length_stats {
+        length_stats.save(length_stats_path);
+    }
+    if let Some(grid_path) = &args.grid_summary {
+        grid.save(grid_path, write_options(args));
+    }
+    if let Some(profile) = profile {
+        eprint!("{}", profile.report());
+    }
+    checksum.map(|checksum| checksum.finish())
 }
 
-fn get_nm_tag(record: &bam::Record) -> u32 {
-    match record.aux(b"NM") {
-        Ok(value) => match value {
-            Aux::U8(v) => u32::from(v),
-            Aux::U16(v) => u32::from(v),
-            Aux::U32(v) => v,
-            Aux::I32(v) => u32::try_from(v).expect("Identified a negative NM tag"),
-            _ => panic!("Unexpected type of Aux for NM tag: {:?}", value),
+/// The writer of an output file, which appends to the output of the checkpoint to resume
+fn new_writer(
+    path: &str,
+    format: output::OutputFormat,
+    schema: &arrow::datatypes::Schema,
+    args: &Cli,
+    resume: Option<&checkpoint::Checkpoint>,
+) -> output::OutputWriter {
+    match format {
+        output::OutputFormat::Arrow => output::OutputWriter::new_arrow(
+            path,
+            schema,
+            write_options(args),
+            args.write_buffer_size as usize,
+        ),
+        output::OutputFormat::ArrowStream => output::OutputWriter::new_arrow_stream(
+            path,
+            schema,
+            write_options(args),
+            args.write_buffer_size as usize,
+        ),
+        output::OutputFormat::Feather => output::OutputWriter::new_arrow(
+            path,
+            schema,
+            write_options(args)
+                .try_with_compression(Some(CompressionType::LZ4_FRAME))
+                .expect("Failure setting LZ4 compression, which requires --ipc-version V5"),
+            args.write_buffer_size as usize,
+        ),
+        output::OutputFormat::Parquet => match args.parquet_partition_by {
+            Some(partition_by) => output::OutputWriter::new_partitioned_parquet(
+                path,
+                partition_by,
+                args.row_group_size,
+            ),
+            None => output::OutputWriter::new_parquet(path, schema, args.row_group_size),
         },
-        Err(_e) => panic!("Unexpected result while trying to access the NM tag"),
+        output::OutputFormat::Tsv => output::OutputWriter::new_tsv(
+            path,
+            schema,
+            args.na_string.as_deref(),
+            resume.map(|checkpoint| checkpoint.output_bytes),
+        ),
+        output::OutputFormat::Jsonl => output::OutputWriter::new_jsonl(
+            path,
+            schema,
+            args.na_string.as_deref(),
+            resume.map(|checkpoint| checkpoint.output_bytes),
+        ),
     }
 }
 
-/// Get the de:f tag from minimap2, which is the gap compressed sequence divergence
-/// Which is converted into identity with (1.0 - de)
-/// This tag can be absent if the aligner version is not quite recent
-fn get_de_tag(record: &bam::Record) -> Option<f32> {
-    match record.aux(b"de") {
-        Ok(value) => match value {
-            Aux::Float(v) => Some(1.0 - v),
-            _ => panic!("Unexpected type of Aux for de tag: {:?}", value),
-        },
-        Err(_e) => None,
+/// Writes the batch to every output, converted to the compact types with the ref_name categories if given
+/// and flushes the outputs if `flush`
+fn write_batch(
+    writers: &mut [output::OutputWriter],
+    checksum: &mut Option<checksum::Checksum>,
+    compact: Option<&Vec<String>>,
+    batch: RecordBatch,
+    flush: bool,
+) {
+    let batch = match compact {
+        Some(contigs) => columns::compact_batch(&batch, contigs),
+        None => batch,
+    };
+    if let Some(checksum) = checksum {
+        checksum.add(&batch);
+    }
+    for writer in writers {
+        writer.write(&batch);
+        if flush {
+            writer.flush();
+        }
     }
 }
 
-#[cfg(test)]
-#[ctor::ctor]
-fn init() {
-    env_logger::init();
+/// Passes the reads to write in record batches of at most --batch-size, built by the threads of the pool
+fn write_batches(
+    columns: &[columns::Column],
+    reads: Vec<metrics::ReadMetrics>,
+    batch_size: output::BatchSize,
+    pool: Option<&pool::ThreadPool>,
+    profile: Option<&profile::Profile>,
+    mut write: impl FnMut(RecordBatch),
+) {
+    let mut record_batch = |reads| {
+        let batch = profile::Profile::time(profile, profile::Stage::Batches, || {
+            columns::record_batch(columns, reads, pool)
+        });
+        write(batch)
+    };
+    let mut batch = vec![];
+    let mut batch_bytes = 0;
+    for read in reads {
+        batch_bytes += columns::estimated_size(columns, &read);
+        batch.push(read);
+        if batch_size.is_full(batch.len(), batch_bytes) {
+            record_batch(std::mem::take(&mut batch));
+            batch_bytes = 0;
+        }
+    }
+    if !batch.is_empty() {
+        record_batch(batch);
+    }
 }
 
-#[test]
-fn verify_app() {
-    use clap::CommandFactory;
-    Cli::command().debug_assert()
+/// Counts the reads passing the filters, without calculating their metrics
+/// unless reads without identity are dropped, as their identity is needed
+fn count_reads(bam_path: &str, args: &Cli) -> usize {
+    let mut count = 0;
+    let drop_missing_identity = args.on_missing_identity == metrics::MissingIdentity::Drop;
+    for_each_read(bam_path, args, |read, header| {
+        if !drop_missing_identity
+            || metrics::ReadMetrics::from_record(read, args, header)
+                .identity
+                .is_some()
+        {
+            count += 1
+        }
+    });
+    count
 }
 
-#[test]
-fn test_extract() {
-    extract(
-        &"test-data/small-test-phased.bam".to_string(),
-        "test.arrow".to_string(),
-        4,
-    )
+/// Counts the secondary alignments per read name in the complete file, regardless of the filters
+/// as the secondary alignments of a read can be anywhere in the file, this is a separate pass over the input
+fn count_secondary_alignments(bam_path: &str, args: &Cli) -> HashMap<Vec<u8>, u64> {
+    if bam_path == "-" {
+        panic!("--secondary-count reads the input twice, which is not possible from stdin");
+    }
+    let mut bam = open_reader(bam_path, args);
+    let mut counts = HashMap::new();
+    for read in bam.rc_records() {
+        let read = read.expect("Failure parsing Bam file");
+        if read.is_secondary() {
+            *counts.entry(read.qname().to_vec()).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+/// The coverage depth per --depth-window of the reads passing the filters, in a separate pass over the input
+fn coverage_depth(bam_path: &str, args: &Cli) -> depth::Depth {
+    if bam_path == "-" {
+        panic!("--annotate-depth reads the input twice, which is not possible from stdin");
+    }
+    let mut depth = depth::Depth::new(args.depth_window);
+    for_each_read(bam_path, args, |read, _| depth.add(read));
+    depth
+}
+
+/// Calls f on every read passing the filters
+/// from the complete file or from the requested region(s) of an indexed file
+fn for_each_read(bam_path: &str, args: &Cli, mut f: impl FnMut(&bam::Record, &HeaderView)) {
+    for_each_read_from(bam_path, args, None, |read, header, _| f(read, header));
+}
+
+/// Calls f on every read passing the filters, with the virtual file offset after the read
+/// which is only known when reading the complete file, starting at the offset `start` if given
+fn for_each_read_from(
+    bam_path: &str,
+    args: &Cli,
+    start: Option<i64>,
+    mut f: impl FnMut(&bam::Record, &HeaderView, Option<i64>),
+) {
+    if args.ref_retries > 0 && bam_path.ends_with(".cram") {
+        reference::probe_reference(bam_path, args);
+    }
+    // the reads with a short reference span or small aligned fraction are counted, so these are filtered here
+    let mut short_spans = 0;
+    let mut unaligned = 0;
+    let mut overruns = 0;
+    let mut f = |read: &bam::Record, header: &HeaderView, offset| {
+        if !args.annotate_filters {
+            if !Filter::ContigOverrun.passes(read, args, header) {
+                overruns += 1;
+                return;
+            }
+            if !Filter::MinRefSpan.passes(read, args, header) {
+                short_spans += 1;
+                return;
+            }
+            if !Filter::MinAlignedFraction.passes(read, args, header) {
+                unaligned += 1;
+                return;
+            }
+        }
+        f(read, header, offset)
+    };
+    if args.region.is_some() || args.regions_bed.is_some() || args.per_contig_window.is_some() {
+        for_each_read_in_regions(bam_path, args, |read, header| f(read, header, None));
+    } else {
+        for_each_read_in_file(bam_path, args, start, &mut f);
+    }
+    if let Some(min) = args.min_ref_span {
+        info!("Dropped {short_spans} reads with a reference span below {min} from {bam_path}");
+    }
+    if let Some(min) = args.min_aligned_fraction {
+        info!("Dropped {unaligned} reads with an aligned fraction below {min} from {bam_path}");
+    }
+    if overruns > 0 {
+        warn!("Dropped {overruns} reads mapped beyond the end of their contig from {bam_path}");
+    }
+    warn_if_truncated(bam_path);
+}
+
+/// Opens the file or stdin, with the --reference of cram files and the --threads to decompress
+fn open_reader(bam_path: &str, args: &Cli) -> bam::Reader {
+    let mut bam = if bam_path == "-" {
+        bam::Reader::from_stdin().expect("\n\nError reading alignments from stdin.\nDid you include the file header with -h?\n\n\n\n")
+    } else {
+        bam::Reader::from_path(bam_path)
+            .expect("Error opening BAM/CRAM file.\nIs the input file correct?\n\n\n\n")
+    };
+    if let Some(reference) = &args.reference {
+        bam.set_reference(reference)
+            .unwrap_or_else(|e| panic!("Failure setting reference {reference}: {e}"));
+    }
+    bam.set_threads(args.threads)
+        .unwrap_or_else(|_| panic!("Failure setting {} decompression threads", args.threads));
+    bam
+}
+
+/// Calls f on every read of the file passing the filters, from the virtual file offset `start` if given
+fn for_each_read_in_file(
+    bam_path: &str,
+    args: &Cli,
+    start: Option<i64>,
+    mut f: impl FnMut(&bam::Record, &HeaderView, Option<i64>),
+) {
+    let mut bam = open_reader(bam_path, args);
+    let header = bam.header().clone();
+    if let Some(offset) = start {
+        bam.seek(offset)
+            .unwrap_or_else(|e| panic!("Failure seeking to offset {offset} of {bam_path}: {e}"));
+    }
+    // reading into one record, instead of iterating, keeps the reader available for its offset
+    let mut read = bam::Record::new();
+    while let Some(result) = bam.read(&mut read) {
+        result.expect("Failure parsing Bam file");
+        if passes_filters(&read, args, &header) {
+            f(&read, &header, Some(bam.tell()))
+        }
+    }
+}
+
+/// The inputs which are checked for truncation, as some inputs are read in multiple passes
+static TRUNCATION_CHECKED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Warns once per input if it is likely truncated
+fn warn_if_truncated(bam_path: &str) {
+    let mut checked = TRUNCATION_CHECKED
+        .lock()
+        .expect("Failure checking the truncation of an input");
+    if checked.iter().any(|path| path == bam_path) {
+        return;
+    }
+    checked.push(bam_path.to_string());
+    if let Some(problem) = validate::truncation_problem(bam_path) {
+        warn!("{problem}");
+    }
+}
+
+/// Fetches the reads overlapping the --region, the intervals in --regions-bed
+/// or the --per-contig-window of every contig
+/// reads overlapping multiple intervals are only processed once
+fn for_each_read_in_regions(
+    bam_path: &str,
+    args: &Cli,
+    mut f: impl FnMut(&bam::Record, &HeaderView),
+) {
+    let mut bam = bam::IndexedReader::from_path(bam_path)
+        .expect("Error opening BAM/CRAM file.\nIs the input file correct and indexed?\n\n\n\n");
+    if let Some(reference) = &args.reference {
+        bam.set_reference(reference)
+            .unwrap_or_else(|e| panic!("Failure setting reference {reference}: {e}"));
+    }
+    bam.set_threads(args.threads)
+        .unwrap_or_else(|_| panic!("Failure setting {} decompression threads", args.threads));
+    let header = bam.header().clone();
+    if let Some(region) = &args.region {
+        if region == "-" {
+            for_each_read_in_region_lines(&mut bam, &header, args, std::io::stdin().lock(), f);
+            return;
+        }
+        bam.fetch(region.as_str())
+            .unwrap_or_else(|_| panic!("Failure fetching region {region}"));
+        for read in bam
+            .rc_records()
+            .map(|r| r.expect("Failure parsing Bam file"))
+            .filter(|read| passes_filters(read, args, &header))
+        {
+            f(&read, &header)
+        }
+        return;
+    }
+    let intervals = match args.per_contig_window {
+        Some(window) => contig_windows(&header, window),
+        None => bed::read_bed(args.regions_bed.as_ref().unwrap()),
+    };
+    let mut seen = HashSet::new();
+    for interval in intervals {
+        bam.fetch((
+            interval.contig.as_str(),
+            interval.start as i64,
+            interval.end as i64,
+        ))
+        .unwrap_or_else(|_| {
+            panic!(
+                "Failure fetching region {}:{}-{}",
+                interval.contig, interval.start, interval.end
+            )
+        });
+        for read in bam
+            .rc_records()
+            .map(|r| r.expect("Failure parsing Bam file"))
+            .filter(|read| passes_filters(read, args, &header))
+            .filter(|read| seen.insert((read.qname().to_vec(), read.pos(), read.flags())))
+        {
+            f(&read, &header)
+        }
+    }
+}
+
+/// Calls f on the reads overlapping the region on each line, as the lines are read
+/// so that another process can stream regions, reads overlapping several regions are only used once
+fn for_each_read_in_region_lines(
+    bam: &mut bam::IndexedReader,
+    header: &HeaderView,
+    args: &Cli,
+    lines: impl BufRead,
+    mut f: impl FnMut(&bam::Record, &HeaderView),
+) {
+    let mut seen = HashSet::new();
+    for line in lines.lines() {
+        let line = line.expect("Failure reading regions");
+        let region = line.trim();
+        if region.is_empty() {
+            continue;
+        }
+        bam.fetch(region)
+            .unwrap_or_else(|_| panic!("Failure fetching region {region}"));
+        for read in bam
+            .rc_records()
+            .map(|r| r.expect("Failure parsing Bam file"))
+            .filter(|read| passes_filters(read, args, header))
+            .filter(|read| seen.insert((read.qname().to_vec(), read.pos(), read.flags())))
+        {
+            f(&read, header)
+        }
+    }
+}
+
+/// The first window bases of every contig in the header
+fn contig_windows(header: &HeaderView, window: u64) -> Vec<bed::Interval> {
+    (0..header.target_count())
+        .map(|tid| bed::Interval {
+            contig: String::from_utf8_lossy(header.tid2name(tid)).to_string(),
+            start: 0,
+            end: window.min(header.target_len(tid).unwrap_or(window)),
+        })
+        .collect()
+}
+
+/// Unmapped reads and secondary alignments are excluded, unless these flags are in --include-flags
+const EXCLUDED_FLAGS: u16 = (htslib::BAM_FUNMAP | htslib::BAM_FSECONDARY) as u16;
+
+/// The filters of the reads, which only add a column per filter with --annotate-filters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Flags,
+    Contigs,
+    Strand,
+    ProperPair,
+    Blacklist,
+    NameRegex,
+    SampleRate,
+    MinRefSpan,
+    MinAlignedFraction,
+    ContigOverrun,
+}
+
+impl Filter {
+    const ALL: [Filter; 10] = [
+        Filter::Flags,
+        Filter::Contigs,
+        Filter::Strand,
+        Filter::ProperPair,
+        Filter::Blacklist,
+        Filter::NameRegex,
+        Filter::SampleRate,
+        Filter::MinRefSpan,
+        Filter::MinAlignedFraction,
+        Filter::ContigOverrun,
+    ];
+
+    /// The name of the column with whether the read passes this filter
+    pub fn column_name(&self) -> &'static str {
+        match self {
+            Filter::Flags => "pass_flags",
+            Filter::Contigs => "pass_contigs",
+            Filter::Strand => "pass_strand",
+            Filter::ProperPair => "pass_proper_pair",
+            Filter::Blacklist => "pass_blacklist",
+            Filter::NameRegex => "pass_name_regex",
+            Filter::SampleRate => "pass_sample_rate",
+            Filter::MinRefSpan => "pass_min_ref_span",
+            Filter::MinAlignedFraction => "pass_min_aligned_fraction",
+            Filter::ContigOverrun => "pass_contig_overrun",
+        }
+    }
+
+    /// The filters set by the arguments, of which the flag filter is always set
+    pub fn active(args: &Cli) -> Vec<Filter> {
+        Filter::ALL
+            .into_iter()
+            .filter(|filter| match filter {
+                Filter::Flags => true,
+                Filter::Contigs => !args.contigs.is_empty() || !args.exclude_contigs.is_empty(),
+                Filter::Strand => args.strand != Strand::Both,
+                Filter::ProperPair => args.fragment_lengths,
+                Filter::Blacklist => args.blacklist_bed.is_some(),
+                Filter::NameRegex => args.name_regex.is_some(),
+                Filter::SampleRate => args.sample_rate_by_length.is_some(),
+                Filter::MinRefSpan => args.min_ref_span.is_some(),
+                Filter::MinAlignedFraction => args.min_aligned_fraction.is_some(),
+                Filter::ContigOverrun => args.on_contig_overrun == metrics::ContigOverrun::Drop,
+            })
+            .collect()
+    }
+
+    /// Whether the read passes this filter, which every read does if the filter is not set
+    fn passes(&self, read: &bam::Record, args: &Cli, header: &HeaderView) -> bool {
+        match self {
+            Filter::Flags => read.flags() & EXCLUDED_FLAGS & !args.include_flags == 0,
+            Filter::Contigs => passes_contig_filters(read, args, header),
+            Filter::Strand => args.strand.keep(read),
+            Filter::ProperPair => !args.fragment_lengths || metrics::is_proper_pair(read),
+            Filter::Blacklist => match &args.blacklist_bed {
+                Some(blacklist) => {
+                    read.tid() < 0 || {
+                        let contig = String::from_utf8_lossy(header.tid2name(read.tid() as u32));
+                        blacklist.count_overlaps(
+                            &contig,
+                            read.reference_start() as u64,
+                            read.reference_end() as u64,
+                        ) == 0
+                    }
+                }
+                None => true,
+            },
+            Filter::NameRegex => match &args.name_regex {
+                Some(regex) => regex.is_match(read.qname()),
+                None => true,
+            },
+            Filter::SampleRate => match &args.sample_rate_by_length {
+                Some(sampling) => sampling.keep(read.qname(), read.seq_len() as u64),
+                None => true,
+            },
+            Filter::MinRefSpan => match args.min_ref_span {
+                Some(min) => (read.reference_end() - read.reference_start()) as u64 >= min,
+                None => true,
+            },
+            Filter::MinAlignedFraction => match args.min_aligned_fraction {
+                Some(min) => {
+                    !metrics::aligned_fraction(read).is_some_and(|fraction| fraction < min)
+                }
+                None => true,
+            },
+            Filter::ContigOverrun => {
+                args.on_contig_overrun != metrics::ContigOverrun::Drop
+                    || !metrics::overruns_contig(read, header)
+            }
+        }
+    }
+
+    /// Whether the read passes each of the filters set by the arguments, for --annotate-filters
+    pub fn verdicts(read: &bam::Record, args: &Cli, header: &HeaderView) -> Vec<(Filter, bool)> {
+        Filter::active(args)
+            .into_iter()
+            .map(|filter| (filter, filter.passes(read, args, header)))
+            .collect()
+    }
+}
+
+/// Whether the read passes the flag filter, the optional --name-regex, --contigs, --exclude-contigs,
+/// --fragment-lengths and --blacklist-bed and is sampled by the optional --sample-rate-by-length
+/// which every read does with --annotate-filters, as the filters are only annotated
+fn passes_filters(read: &bam::Record, args: &Cli, header: &HeaderView) -> bool {
+    args.annotate_filters
+        || [
+            Filter::Flags,
+            Filter::Contigs,
+            Filter::Strand,
+            Filter::ProperPair,
+            Filter::Blacklist,
+            Filter::NameRegex,
+            Filter::SampleRate,
+        ]
+        .iter()
+        .all(|filter| filter.passes(read, args, header))
+}
+
+/// Reads without a contig are only kept without --contigs
+fn passes_contig_filters(read: &bam::Record, args: &Cli, header: &HeaderView) -> bool {
+    if args.contigs.is_empty() && args.exclude_contigs.is_empty() {
+        return true;
+    }
+    let listed = |contigs: &[String]| {
+        read.tid() >= 0
+            && contigs
+                .iter()
+                .any(|contig| contig.as_bytes() == header.tid2name(read.tid() as u32))
+    };
+    (args.contigs.is_empty() || listed(&args.contigs)) && !listed(&args.exclude_contigs)
+}
+
+/// The warnings logged by the tests, to check that a warning was given
+#[cfg(test)]
+static TEST_WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Logs as env_logger, and keeps the warnings in TEST_WARNINGS
+#[cfg(test)]
+struct TestLogger(env_logger::Logger);
+
+#[cfg(test)]
+impl log::Log for TestLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.level() <= log::Level::Warn {
+            TEST_WARNINGS
+                .lock()
+                .unwrap()
+                .push(record.args().to_string());
+        }
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Whether a warning containing the message was logged, by any test
+#[cfg(test)]
+fn warned(message: &str) -> bool {
+    TEST_WARNINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|warning| warning.contains(message))
+}
+
+#[cfg(test)]
+#[ctor::ctor]
+fn init() {
+    let logger = env_logger::Builder::from_default_env().build();
+    log::set_max_level(logger.filter().max(log::LevelFilter::Warn));
+    log::set_boxed_logger(Box::new(TestLogger(logger))).expect("Failure setting the test logger");
+}
+
+#[test]
+fn verify_app() {
+    use clap::CommandFactory;
+    Cli::command().debug_assert()
+}
+
+#[cfg(test)]
+fn read_arrow(filename: &str) -> Vec<RecordBatch> {
+    let file = File::open(filename).expect("open arrow file error");
+    arrow::ipc::reader::FileReader::try_new(file, None)
+        .expect("create arrow file reader error")
+        .map(|batch| batch.expect("read arrow batch error"))
+        .collect()
+}
+
+#[cfg(test)]
+fn sum_column(batches: &[RecordBatch], column: &str) -> u64 {
+    batches
+        .iter()
+        .map(|batch| {
+            arrow::compute::sum(
+                batch
+                    .column_by_name(column)
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<arrow::array::UInt64Array>()
+                    .unwrap(),
+            )
+            .unwrap_or(0)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+fn num_rows(batches: &[RecordBatch]) -> usize {
+    batches.iter().map(|batch| batch.num_rows()).sum()
+}
+
+#[test]
+fn test_extract() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test.arrow",
+    ]);
+    extract(&args.inputs(), &args);
+}
+
+#[test]
+fn test_annotate_depth() {
+    use arrow::array::{Array, Float64Array};
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-annotate-depth.arrow",
+        "--annotate-depth",
+        "--depth-window",
+        "500",
+    ]);
+    extract(&args.inputs(), &args);
+    // the actual coverage at the midpoint of each read, as the aligned blocks started but not ended
+    let (mut starts, mut ends, mut midpoints) = (vec![], vec![], vec![]);
+    for_each_read(args.input(), &args, |read, _| {
+        for [start, end] in read.aligned_blocks() {
+            starts.push((read.tid(), start));
+            ends.push((read.tid(), end));
+        }
+        midpoints.push((
+            read.tid(),
+            (read.reference_start() + read.reference_end()) / 2,
+        ));
+    });
+    starts.sort_unstable();
+    ends.sort_unstable();
+    let coverage: Vec<usize> = midpoints
+        .iter()
+        .map(|&midpoint| {
+            starts.partition_point(|&start| start <= midpoint)
+                - ends.partition_point(|&end| end <= midpoint)
+        })
+        .collect();
+    let batches = read_arrow("test-annotate-depth.arrow");
+    let depths: Vec<f64> = batches
+        .iter()
+        .flat_map(|batch| {
+            let depth = batch
+                .column_by_name("depth")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Float64Array>()
+                .unwrap();
+            assert_eq!(depth.null_count(), 0);
+            depth.values().to_vec()
+        })
+        .collect();
+    assert_eq!(depths.len(), coverage.len());
+    // the reads at a higher actual coverage have a higher approximate depth
+    let mut order: Vec<usize> = (0..coverage.len()).collect();
+    order.sort_by_key(|&i| coverage[i]);
+    let (low, high) = order.split_at(order.len() / 2);
+    let mean = |reads: &[usize]| reads.iter().map(|&i| depths[i]).sum::<f64>() / reads.len() as f64;
+    assert!(coverage[*high.last().unwrap()] > coverage[low[0]]);
+    assert!(mean(high) > mean(low));
+    assert!(depths.iter().all(|&depth| depth > 0.0));
+}
+
+#[test]
+fn test_gc_bias() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-gc-bias-metrics.arrow",
+        "--gc-bias",
+        "test-gc-bias.arrow",
+    ]);
+    extract(&args.inputs(), &args);
+    let metrics = read_arrow("test-gc-bias-metrics.arrow");
+    let gc_bias = read_arrow("test-gc-bias.arrow");
+    assert_eq!(gc_bias[0].num_rows(), 101);
+    assert_eq!(
+        sum_column(&gc_bias, "aligned_bases"),
+        sum_column(&metrics, "aligned_lengths")
+    );
+    assert_eq!(sum_column(&gc_bias, "reads"), num_rows(&metrics) as u64);
+}
+
+#[test]
+fn test_name_regex() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-name-regex.arrow",
+        "--name-regex",
+        "^3f",
+    ]);
+    extract(&args.inputs(), &args);
+    let expected = bam::Reader::from_path(args.input())
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap())
+        .filter(|read| read.flags() & (htslib::BAM_FUNMAP | htslib::BAM_FSECONDARY) as u16 == 0)
+        .filter(|read| read.qname().starts_with(b"3f"))
+        .count();
+    assert!(expected > 0);
+    assert_eq!(num_rows(&read_arrow("test-name-regex.arrow")), expected);
+}
+
+#[test]
+fn test_cram_bgzip_reference() {
+    use arrow::array::{Array, Float64Array};
+    use rand::Rng;
+    use std::io::Write;
+    // the first reads of the test file are moved to a short contig, of which a random reference is bgzipped
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let mut reads: Vec<bam::Record> = reader
+        .records()
+        .map(|read| read.unwrap())
+        .filter(|read| !(read.is_unmapped() || read.is_secondary()))
+        .take(20)
+        .collect();
+    let start = reads.iter().map(|read| read.pos()).min().unwrap();
+    let length = reads.iter().map(|read| read.reference_end()).max().unwrap() - start + 100;
+    let mut header = bam::Header::new();
+    header.push_record(
+        bam::header::HeaderRecord::new(b"SQ")
+            .push_tag(b"SN", "chrT")
+            .push_tag(b"LN", length),
+    );
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut fasta = rust_htslib::bgzf::Writer::from_path("test-cram-reference.fa.gz").unwrap();
+    writeln!(fasta, ">chrT").unwrap();
+    let sequence: Vec<u8> = (0..length).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect();
+    for line in sequence.chunks(60) {
+        fasta.write_all(line).unwrap();
+        fasta.write_all(b"\n").unwrap();
+    }
+    drop(fasta);
+    let path = std::ffi::CString::new("test-cram-reference.fa.gz").unwrap();
+    assert_eq!(unsafe { htslib::fai_build(path.as_ptr()) }, 0);
+    let mut bam_writer =
+        bam::Writer::from_path("test-cram-reference.bam", &header, bam::Format::Bam).unwrap();
+    let mut cram_writer =
+        bam::Writer::from_path("test-cram-reference.cram", &header, bam::Format::Cram).unwrap();
+    cram_writer
+        .set_reference("test-cram-reference.fa.gz")
+        .unwrap();
+    for read in &mut reads {
+        read.set_tid(0);
+        read.set_pos(read.pos() - start);
+        read.set_mtid(-1);
+        read.set_mpos(-1);
+        bam_writer.write(read).unwrap();
+        cram_writer.write(read).unwrap();
+    }
+    drop(bam_writer);
+    drop(cram_writer);
+    let identities = |input: &str| -> Vec<Option<f64>> {
+        let output = format!("{input}.arrow");
+        let args = Cli::parse_from([
+            "make_arrow",
+            input,
+            "-o",
+            &output,
+            "--reference",
+            "test-cram-reference.fa.gz",
+        ]);
+        extract(&args.inputs(), &args);
+        read_arrow(&output)
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column_by_name("identities")
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Float64Array>()
+                    .unwrap()
+                    .iter()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+    let from_bam = identities("test-cram-reference.bam");
+    assert_eq!(from_bam.len(), 20);
+    assert!(from_bam.iter().all(|identity| identity.is_some()));
+    assert_eq!(identities("test-cram-reference.cram"), from_bam);
+    // without its index the bgzipped reference is rejected before decoding
+    std::fs::copy("test-cram-reference.fa.gz", "test-cram-unindexed.fa.gz").unwrap();
+    let _ = std::fs::remove_file("test-cram-unindexed.fa.gz.fai");
+    let error = reference::check_reference("test-cram-unindexed.fa.gz").unwrap_err();
+    assert!(error.contains("index test-cram-unindexed.fa.gz.fai"));
+}
+
+#[test]
+fn test_ref_retries_conflicts_with_no_ref_download() {
+    assert!(Cli::try_parse_from([
+        "make_arrow",
+        "input.cram",
+        "--ref-retries",
+        "3",
+        "--no-ref-download",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_count_only() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-count-only.arrow",
+        "--name-regex",
+        "^[0-7]",
+        "--count-only",
+    ]);
+    let count = count_reads(args.input(), &args);
+    extract(&args.inputs(), &args);
+    assert_eq!(count, num_rows(&read_arrow("test-count-only.arrow")));
+    // the reads without identity are not counted when they are dropped
+    use rust_htslib::bam::Read;
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let mut writer =
+        bam::Writer::from_path("test-count-only.bam", &header, bam::Format::Bam).unwrap();
+    for (i, read) in reader.records().take(20).enumerate() {
+        let mut read = read.unwrap();
+        if i % 2 == 0 {
+            read.remove_aux(b"NM").unwrap();
+        }
+        writer.write(&read).unwrap();
+    }
+    drop(writer);
+    let count_args = |policy: &str| {
+        Cli::parse_from([
+            "make_arrow",
+            "test-count-only.bam",
+            "-o",
+            "test-count-only-drop.arrow",
+            "--count-only",
+            "--on-missing-identity",
+            policy,
+        ])
+    };
+    let args = count_args("drop");
+    let count = count_reads(args.input(), &args);
+    assert!(count < count_reads(args.input(), &count_args("null")));
+    extract(&args.inputs(), &args);
+    assert_eq!(count, num_rows(&read_arrow("test-count-only-drop.arrow")));
+}
+
+#[test]
+fn test_parquet_row_group_size() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-row-groups.parquet",
+        "--format",
+        "parquet",
+        "--row-group-size",
+        "1000",
+    ]);
+    extract(&args.inputs(), &args);
+    let reader = SerializedFileReader::new(File::open("test-row-groups.parquet").unwrap()).unwrap();
+    let metadata = reader.metadata();
+    let rows = metadata.file_metadata().num_rows() as usize;
+    assert!(rows > 1000);
+    assert!(metadata.num_row_groups() > 1);
+    assert_eq!(metadata.num_row_groups(), rows.div_ceil(1000));
+    assert!(metadata
+        .row_groups()
+        .iter()
+        .all(|row_group| row_group.num_rows() <= 1000));
+}
+
+#[test]
+fn test_parquet_partition_by_contig() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    let _ = std::fs::remove_dir_all("test-partitioned.parquet");
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-partitioned.parquet",
+        "--format",
+        "parquet",
+        "--parquet-partition-by",
+        "contig",
+    ]);
+    extract(&args.inputs(), &args);
+    let partitions: Vec<String> = std::fs::read_dir("test-partitioned.parquet")
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(partitions, ["contig=chr7"]);
+    let reader = SerializedFileReader::new(
+        File::open("test-partitioned.parquet/contig=chr7/part.parquet").unwrap(),
+    )
+    .unwrap();
+    let metadata = reader.metadata().file_metadata();
+    assert_eq!(
+        metadata.num_rows() as usize,
+        count_reads(args.input(), &args)
+    );
+    assert!(metadata
+        .schema_descr()
+        .columns()
+        .iter()
+        .all(|column| column.name() != "contig"));
+}
+
+#[test]
+fn test_batches_and_summary() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-batches.arrow",
+        "--batch-size",
+        "1000",
+        "--summary",
+        "test-batches-summary.json",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-batches.arrow");
+    let rows = num_rows(&batches);
+    assert_eq!(batches.len(), rows.div_ceil(1000));
+    let summary: serde_json::Value =
+        serde_json::from_reader(File::open("test-batches-summary.json").unwrap()).unwrap();
+    assert_eq!(summary["reads"], rows);
+    let mean_length = sum_column(&batches, "lengths") as f64 / rows as f64;
+    assert!((summary["lengths"]["mean"].as_f64().unwrap() - mean_length).abs() < 1e-6);
+}
+
+#[test]
+fn test_count_bases() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-count-bases.arrow",
+        "--summary",
+        "test-count-bases-summary.json",
+        "--count-bases",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-count-bases.arrow");
+    let summary: serde_json::Value =
+        serde_json::from_reader(File::open("test-count-bases-summary.json").unwrap()).unwrap();
+    assert_eq!(summary["total_bases"], sum_column(&batches, "lengths"));
+    assert_eq!(
+        summary["total_aligned_bases"],
+        sum_column(&batches, "aligned_lengths")
+    );
+}
+
+#[test]
+fn test_tsv() {
+    let tsv_args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-tsv.tsv",
+        "--format",
+        "tsv",
+        "--quality",
+    ]);
+    extract(&tsv_args.inputs(), &tsv_args);
+    let arrow_args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-tsv.arrow",
+        "--quality",
+    ]);
+    extract(&arrow_args.inputs(), &arrow_args);
+    let tsv = std::fs::read_to_string("test-tsv.tsv").unwrap();
+    let mut lines = tsv.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "identities\tlengths\taligned_lengths\tmapQ\tquals"
+    );
+    let first_row: Vec<&str> = lines.next().unwrap().split('\t').collect();
+    let batches = read_arrow("test-tsv.arrow");
+    let row = arrow::util::display::ArrayFormatter::try_new(
+        batches[0].column_by_name("lengths").unwrap(),
+        &Default::default(),
+    )
+    .unwrap()
+    .value(0)
+    .to_string();
+    assert_eq!(first_row[1], row);
+    assert_eq!(tsv.lines().count() - 1, num_rows(&batches));
+}
+
+#[test]
+fn test_na_string() {
+    let write = |format: &str, output: &str| {
+        let args = Cli::parse_from([
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            output,
+            "--format",
+            format,
+            "--nanopore-tags",
+            "--na-string",
+            "NA",
+        ]);
+        extract(&args.inputs(), &args);
+        std::fs::read_to_string(output).unwrap()
+    };
+    // the test file has no ch and st tags
+    let tsv = write("tsv", "test-na-string.tsv");
+    let mut lines = tsv.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "identities\tlengths\taligned_lengths\tmapQ\tchannel\tstart_time"
+    );
+    assert!(lines.next().unwrap().ends_with("\tNA\tNA"));
+    let jsonl = write("jsonl", "test-na-string.jsonl");
+    let first: serde_json::Value = serde_json::from_str(jsonl.lines().next().unwrap()).unwrap();
+    assert_eq!(first["channel"], "NA");
+    assert!(first["lengths"].as_u64().unwrap() > 0);
+    assert_eq!(jsonl.lines().count(), tsv.lines().count() - 1);
+}
+
+#[test]
+fn test_jsonl_nulls() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-jsonl-nulls.jsonl",
+        "--format",
+        "jsonl",
+        "--nanopore-tags",
+    ]);
+    extract(&args.inputs(), &args);
+    let jsonl = std::fs::read_to_string("test-jsonl-nulls.jsonl").unwrap();
+    let first = jsonl.lines().next().unwrap();
+    assert!(first.starts_with("{\"identities\":"));
+    assert!(first.ends_with(",\"channel\":null,\"start_time\":null}"));
+}
+
+#[test]
+fn test_batch_size_bytes() {
+    use arrow::array::{Array, StringArray};
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-batch-bytes.arrow",
+        "--include-names",
+        "--batch-size",
+        "20KB",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-batch-bytes.arrow");
+    assert!(batches.len() > 1);
+    // identities, lengths, aligned_lengths and mapQ take 25 bytes, names their length and offset
+    let batch_bytes = |batch: &RecordBatch| {
+        let names = batch
+            .column_by_name("names")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        25 * batch.num_rows() + 4 * names.len() + names.value_data().len()
+    };
+    let max_row_bytes = 25 + 4 + 36;
+    for batch in &batches[..batches.len() - 1] {
+        assert!(batch_bytes(batch) >= 20_000);
+        assert!(batch_bytes(batch) < 20_000 + max_row_bytes);
+    }
+}
+
+#[test]
+fn test_length_stats() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-length-stats.arrow",
+        "--length-stats",
+        "test-length-stats.json",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-length-stats.arrow");
+    let stats: serde_json::Value =
+        serde_json::from_reader(File::open("test-length-stats.json").unwrap()).unwrap();
+    assert_eq!(stats["reads"], num_rows(&batches));
+    assert_eq!(stats["total_bases"], sum_column(&batches, "lengths"));
+    assert!(stats["N50"].as_u64().unwrap() >= stats["median"].as_f64().unwrap() as u64);
+}
+
+#[test]
+fn test_clamp_threads() {
+    assert_eq!(clamp_threads(4, 8), 4);
+    assert_eq!(clamp_threads(16, 8), 16);
+    assert!(!warned("Requested 64 threads"));
+    assert_eq!(clamp_threads(64, 8), 16);
+    assert!(warned("Requested 64 threads, but using 16 for 8 cores"));
+}
+
+#[test]
+fn test_cigar_consumption() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--cigar-consumption",
+    ]);
+    let mut reads = 0;
+    for_each_read(args.input(), &args, |read, header| {
+        let metrics = metrics::ReadMetrics::from_record(read, &args, header);
+        let cigar = read.cigar();
+        let soft_clips = (cigar.leading_softclips() + cigar.trailing_softclips()) as u64;
+        assert_eq!(metrics.query_consumed.unwrap() + soft_clips, metrics.length);
+        assert_eq!(metrics.ref_consumed, metrics.aligned_length);
+        reads += 1;
+    });
+    assert!(reads > 0);
+}
+
+#[test]
+fn test_feather() {
+    let feather_args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-feather.arrow",
+        "--format",
+        "feather",
+    ]);
+    extract(&feather_args.inputs(), &feather_args);
+    let arrow_args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-feather-uncompressed.arrow",
+    ]);
+    extract(&arrow_args.inputs(), &arrow_args);
+    let feather = read_arrow("test-feather.arrow");
+    let uncompressed = read_arrow("test-feather-uncompressed.arrow");
+    assert_eq!(feather, uncompressed);
+    assert!(
+        std::fs::metadata("test-feather.arrow").unwrap().len()
+            < std::fs::metadata("test-feather-uncompressed.arrow")
+                .unwrap()
+                .len()
+    );
+}
+
+#[test]
+fn test_write_buffer_size() {
+    let write = |output: &str, buffer_size: &str| {
+        let args = Cli::parse_from([
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            output,
+            "--batch-size",
+            "1000",
+            "--write-buffer-size",
+            buffer_size,
+        ]);
+        extract(&args.inputs(), &args);
+        std::fs::read(output).unwrap()
+    };
+    let small = write("test-write-buffer-small.arrow", "1");
+    let large = write("test-write-buffer-large.arrow", "4194304");
+    assert_eq!(small, large);
+    let args = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    assert_eq!(
+        num_rows(&read_arrow("test-write-buffer-large.arrow")),
+        count_reads(args.input(), &args)
+    );
+}
+
+#[test]
+fn test_output_same_as_input() {
+    let input = "test-data/small-test-phased.bam";
+    assert!(check_output_path(input, &["test-output-path.arrow"]).is_ok());
+    assert!(check_output_path(
+        input,
+        &[
+            "test-output-path.arrow",
+            "./test-data/../test-data/small-test-phased.bam"
+        ]
+    )
+    .is_err());
+    let args = Cli::parse_from([
+        "make_arrow",
+        input,
+        "-o",
+        "test-output-path.arrow",
+        "--summary",
+        "test-data/small-test-phased.bam",
+    ]);
+    let outputs = output_paths(&args);
+    let outputs: Vec<&str> = outputs.iter().map(String::as_str).collect();
+    assert!(check_output_path(args.input(), &outputs).is_err());
+}
+
+#[test]
+fn test_alignment_type() {
+    use arrow::array::{DictionaryArray, StringArray};
+    use arrow::datatypes::Int8Type;
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-alignment-type.arrow",
+        "--include-flags",
+        "256",
+        "--alignment-type",
+    ]);
+    extract(&args.inputs(), &args);
+    let mut counts = std::collections::HashMap::new();
+    for batch in read_arrow("test-alignment-type.arrow") {
+        let types = batch
+            .column_by_name("alignment_type")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<DictionaryArray<Int8Type>>()
+            .unwrap()
+            .downcast_dict::<StringArray>()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.unwrap().to_string())
+            .collect::<Vec<String>>();
+        for alignment_type in types {
+            *counts.entry(alignment_type).or_insert(0) += 1;
+        }
+    }
+    let mut expected = std::collections::HashMap::new();
+    for read in bam::Reader::from_path(args.input()).unwrap().records() {
+        let read = read.unwrap();
+        let alignment_type = match read.flags() {
+            flags if flags & htslib::BAM_FUNMAP as u16 != 0 => continue,
+            flags if flags & htslib::BAM_FSECONDARY as u16 != 0 => "secondary",
+            flags if flags & htslib::BAM_FSUPPLEMENTARY as u16 != 0 => "supplementary",
+            _ => "primary",
+        };
+        *expected.entry(alignment_type.to_string()).or_insert(0) += 1;
+    }
+    assert_eq!(counts.len(), 3);
+    assert_eq!(counts, expected);
+}
+
+#[test]
+fn test_link_supplementary() {
+    use arrow::array::{ArrayAccessor, DictionaryArray, StringArray, UInt64Array};
+    use arrow::datatypes::Int8Type;
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-link-supplementary.arrow",
+        "--link-supplementary",
+        "--include-names",
+    ]);
+    extract(&args.inputs(), &args);
+    // the group ids and alignment types per read name
+    let mut reads: HashMap<String, (HashSet<u64>, Vec<String>)> = HashMap::new();
+    for batch in read_arrow("test-link-supplementary.arrow") {
+        let names = batch
+            .column_by_name("names")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let ids = batch
+            .column_by_name("read_group_id")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        let types = batch
+            .column_by_name("alignment_type")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<DictionaryArray<Int8Type>>()
+            .unwrap()
+            .downcast_dict::<StringArray>()
+            .unwrap();
+        for i in 0..batch.num_rows() {
+            let read = reads.entry(names.value(i).to_string()).or_default();
+            read.0.insert(ids.value(i));
+            read.1.push(types.value(i).to_string());
+        }
+    }
+    let split_reads: Vec<&(HashSet<u64>, Vec<String>)> = reads
+        .values()
+        .filter(|(_, types)| {
+            types.contains(&"primary".to_string()) && types.contains(&"supplementary".to_string())
+        })
+        .collect();
+    assert!(!split_reads.is_empty());
+    assert!(split_reads.iter().all(|(ids, _)| ids.len() == 1));
+    let ids: HashSet<u64> = reads.values().flat_map(|(ids, _)| ids.clone()).collect();
+    assert_eq!(ids.len(), reads.len());
+}
+
+#[test]
+fn test_on_missing_identity_drop() {
+    use rust_htslib::bam::Read;
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let mut writer =
+        bam::Writer::from_path("test-missing-identity.bam", &header, bam::Format::Bam).unwrap();
+    // the NM tag is removed from every other read
+    for (i, read) in reader.records().take(20).enumerate() {
+        let mut read = read.unwrap();
+        if i % 2 == 0 {
+            read.remove_aux(b"NM").unwrap();
+        }
+        writer.write(&read).unwrap();
+    }
+    drop(writer);
+    let extract_rows = |policy| {
+        let args = Cli::parse_from([
+            "make_arrow",
+            "test-missing-identity.bam",
+            "-o",
+            &format!("test-missing-identity-{policy}.arrow"),
+            "--on-missing-identity",
+            policy,
+        ]);
+        extract(&args.inputs(), &args);
+        read_arrow(&format!("test-missing-identity-{policy}.arrow"))
+    };
+    let null = extract_rows("null");
+    let dropped = extract_rows("drop");
+    let nulls = null[0].column_by_name("identities").unwrap().null_count();
+    assert!(nulls > 0);
+    assert_eq!(num_rows(&dropped), num_rows(&null) - nulls);
+    assert_eq!(
+        dropped[0]
+            .column_by_name("identities")
+            .unwrap()
+            .null_count(),
+        0
+    );
+}
+
+#[test]
+fn test_fragment_lengths() {
+    use arrow::array::UInt64Array;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    let reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let mut writer =
+        bam::Writer::from_path("test-fragment-lengths.bam", &header, bam::Format::Bam).unwrap();
+    let paired = (htslib::BAM_FPAIRED | htslib::BAM_FPROPER_PAIR) as u16;
+    let first = htslib::BAM_FREAD1 as u16;
+    let second = htslib::BAM_FREAD2 as u16;
+    let reverse = htslib::BAM_FREVERSE as u16;
+    let mate_reverse = htslib::BAM_FMREVERSE as u16;
+    let secondary = htslib::BAM_FSECONDARY as u16;
+    for (name, flags, pos, insert_size) in [
+        ("pair1", paired | first | mate_reverse, 1000, 300i64),
+        ("pair1", paired | second | reverse, 1200, -300),
+        ("pair2", paired | first | mate_reverse, 2000, 500),
+        ("pair2", paired | second | reverse, 2400, -500),
+        // not properly paired
+        ("pair3", htslib::BAM_FPAIRED as u16 | first, 3000, 9000),
+        (
+            "pair2",
+            paired | first | secondary | mate_reverse,
+            5000,
+            700,
+        ),
+    ] {
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(100)]);
+        record.set(name.as_bytes(), Some(&cigar), &[b'A'; 100], &[30; 100]);
+        record.set_tid(0);
+        record.set_mtid(0);
+        record.set_pos(pos);
+        record.set_mpos(pos + insert_size.abs() - 100);
+        record.set_insert_size(insert_size);
+        record.set_flags(flags);
+        record.push_aux(b"NM", bam::record::Aux::U8(0)).unwrap();
+        writer.write(&record).unwrap();
+    }
+    drop(writer);
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-fragment-lengths.bam",
+        "-o",
+        "test-fragment-lengths.arrow",
+        "--include-flags",
+        "256",
+        "--fragment-lengths",
+        "--summary",
+        "test-fragment-lengths.json",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-fragment-lengths.arrow");
+    let fragment_lengths: Vec<u64> = batches[0]
+        .column_by_name("fragment_length")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .unwrap()
+        .iter()
+        .map(|length| length.unwrap())
+        .collect();
+    // every mate has the fragment length of its pair
+    assert_eq!(fragment_lengths, [300, 300, 500, 500]);
+    let summary: serde_json::Value =
+        serde_json::from_reader(File::open("test-fragment-lengths.json").unwrap()).unwrap();
+    // while the summary has every fragment once
+    assert_eq!(summary["fragment_lengths"]["fragments"], 2);
+    assert_eq!(summary["fragment_lengths"]["mean"], 400.0);
+    assert_eq!(summary["fragment_lengths"]["median"], 400.0);
+}
+
+#[test]
+fn test_contig_overrun_drop() {
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let contig_length = reader.header().target_len(0).unwrap() as i64;
+    let mut writer =
+        bam::Writer::from_path("test-contig-overrun.bam", &header, bam::Format::Bam).unwrap();
+    for (i, read) in reader.records().take(10).enumerate() {
+        let mut read = read.unwrap();
+        if i == 0 {
+            // the first read is moved to the end of the contig, beyond which it ends
+            read.set_tid(0);
+            read.set_pos(contig_length - 10);
+        }
+        writer.write(&read).unwrap();
+    }
+    drop(writer);
+    let extract_rows = |policy| {
+        let args = Cli::parse_from([
+            "make_arrow",
+            "test-contig-overrun.bam",
+            "-o",
+            &format!("test-contig-overrun-{policy}.arrow"),
+            "--on-contig-overrun",
+            policy,
+        ]);
+        extract(&args.inputs(), &args);
+        read_arrow(&format!("test-contig-overrun-{policy}.arrow"))
+    };
+    let null = extract_rows("null");
+    assert_eq!(
+        null[0]
+            .column_by_name("aligned_lengths")
+            .unwrap()
+            .null_count(),
+        1
+    );
+    assert_eq!(num_rows(&extract_rows("drop")), num_rows(&null) - 1);
+}
+
+#[test]
+fn test_validate() {
+    std::fs::copy(
+        "test-data/small-test-phased.bam",
+        "test-validate-unindexed.bam",
+    )
+    .unwrap();
+    let problems = |input: &str| {
+        let args = Cli::parse_from([
+            "make_arrow",
+            input,
+            "--validate",
+            "--region",
+            "chr7:152700001-152760000",
+        ]);
+        validate::validate(args.input(), &args)
+    };
+    assert_eq!(
+        problems("test-data/small-test-phased.bam"),
+        Vec::<String>::new()
+    );
+    let unindexed = problems("test-validate-unindexed.bam");
+    assert_eq!(unindexed.len(), 1);
+    assert!(unindexed[0].contains("index"));
+}
+
+#[test]
+fn test_truncated_bam() {
+    let bam = std::fs::read("test-data/small-test-phased.bam").unwrap();
+    std::fs::write("test-truncated.bam", &bam[..bam.len() - 28]).unwrap();
+    assert_eq!(
+        validate::truncation_problem("test-data/small-test-phased.bam"),
+        None
+    );
+    let problem = validate::truncation_problem("test-truncated.bam").unwrap();
+    assert!(problem.contains("truncated"));
+    let args = Cli::parse_from(["make_arrow", "test-truncated.bam", "--validate"]);
+    assert_eq!(validate::validate(args.input(), &args), vec![problem]);
+    // all blocks before the missing EOF block are still read
+    let complete = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    assert_eq!(
+        count_reads(args.input(), &args),
+        count_reads(complete.input(), &complete)
+    );
+    // the input is only checked in the first pass
+    count_reads(args.input(), &args);
+    let checked = TRUNCATION_CHECKED.lock().unwrap();
+    assert_eq!(
+        checked
+            .iter()
+            .filter(|path| *path == "test-truncated.bam")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_sam_not_truncated() {
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let mut writer =
+        bam::Writer::from_path("test-not-truncated.sam", &header, bam::Format::Sam).unwrap();
+    for read in reader.records().take(10) {
+        writer.write(&read.unwrap()).unwrap();
+    }
+    drop(writer);
+    assert_eq!(validate::truncation_problem("test-not-truncated.sam"), None);
+    let args = Cli::parse_from(["make_arrow", "test-not-truncated.sam", "--validate"]);
+    assert!(validate::validate(args.input(), &args).is_empty());
+}
+
+#[test]
+fn test_grid_summary() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-grid-metrics.arrow",
+        "--grid-summary",
+        "test-grid.arrow",
+        "--grid-length-bins",
+        "20",
+        "--grid-identity-bins",
+        "10",
+    ]);
+    extract(&args.inputs(), &args);
+    let grid = read_arrow("test-grid.arrow");
+    assert!(num_rows(&grid) <= 20 * 10);
+    assert_eq!(
+        sum_column(&grid, "count"),
+        num_rows(&read_arrow("test-grid-metrics.arrow")) as u64
+    );
+}
+
+#[test]
+fn test_exclude_contigs() {
+    let count = |extra: &[&str]| {
+        let mut arguments = vec!["make_arrow", "test-data/small-test-phased.bam"];
+        arguments.extend(extra);
+        let args = Cli::parse_from(arguments);
+        count_reads(args.input(), &args)
+    };
+    let all = count(&[]);
+    assert!(all > 0);
+    // all reads in the test file are on chr7
+    assert_eq!(count(&["--exclude-contigs", "chr7"]), 0);
+    assert_eq!(count(&["--exclude-contigs", "chr1,chrM"]), all);
+    assert_eq!(count(&["--contigs", "chr7"]), all);
+    assert_eq!(count(&["--contigs", "chr1"]), 0);
+    // a file with reads on chr1 and chr7, of which one contig is excluded
+    use rust_htslib::bam::Read;
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let chr1 = reader.header().tid(b"chr1").unwrap() as i32;
+    let mut writer =
+        bam::Writer::from_path("test-exclude-contigs.bam", &header, bam::Format::Bam).unwrap();
+    for (i, read) in reader.records().take(40).enumerate() {
+        let mut read = read.unwrap();
+        if i % 2 == 0 {
+            read.set_tid(chr1);
+        }
+        writer.write(&read).unwrap();
+    }
+    drop(writer);
+    let contigs = |exclude: &str| {
+        let args = Cli::parse_from([
+            "make_arrow",
+            "test-exclude-contigs.bam",
+            "--exclude-contigs",
+            exclude,
+        ]);
+        let mut contigs = vec![];
+        for_each_read(args.input(), &args, |read, header| {
+            contigs.push(String::from_utf8_lossy(header.tid2name(read.tid() as u32)).to_string())
+        });
+        contigs
+    };
+    let chr1_reads = contigs("chr7");
+    assert!(!chr1_reads.is_empty());
+    assert!(chr1_reads.iter().all(|contig| contig == "chr1"));
+    let chr7_reads = contigs("chr1");
+    assert!(!chr7_reads.is_empty());
+    assert!(chr7_reads.iter().all(|contig| contig == "chr7"));
+    assert_eq!(chr1_reads.len() + chr7_reads.len(), contigs("chrM").len());
+    assert!(Cli::try_parse_from([
+        "make_arrow",
+        "input.bam",
+        "--contigs",
+        "chr1",
+        "--exclude-contigs",
+        "chrM",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_shuffle() {
+    let names = |extra: &[&str], output: &str| {
+        let mut arguments = vec![
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            output,
+            "--include-names",
+            "--batch-size",
+            "1000",
+        ];
+        arguments.extend(extra);
+        let args = Cli::parse_from(arguments);
+        extract(&args.inputs(), &args);
+        let batches = read_arrow(output);
+        assert_eq!(batches.len(), num_rows(&batches).div_ceil(1000));
+        batches
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column_by_name("names")
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<arrow::array::StringArray>()
+                    .unwrap()
+                    .iter()
+                    .map(|name| name.unwrap().to_string())
+                    .collect::<Vec<String>>()
+            })
+            .collect::<Vec<String>>()
+    };
+    let ordered = names(&[], "test-shuffle-ordered.arrow");
+    let first = names(&["--shuffle", "--seed", "1"], "test-shuffle-1.arrow");
+    let second = names(&["--shuffle", "--seed", "1"], "test-shuffle-2.arrow");
+    let other = names(&["--shuffle", "--seed", "2"], "test-shuffle-3.arrow");
+    assert_eq!(first, second);
+    assert_ne!(first, other);
+    assert_ne!(first, ordered);
+    let (mut sorted, mut sorted_ordered) = (first.clone(), ordered.clone());
+    sorted.sort();
+    sorted_ordered.sort();
+    assert_eq!(sorted, sorted_ordered);
+}
+
+#[test]
+fn test_per_contig_window() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--per-contig-window",
+        "152750000",
+    ]);
+    let mut reads = 0;
+    for_each_read(args.input(), &args, |read, _| {
+        assert!(read.pos() < 152750000);
+        reads += 1;
+    });
+    let all = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    let all = count_reads(all.input(), &all);
+    assert!(reads > 0 && reads < all);
+    let region = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--region",
+        "chr7:1-152750000",
+    ]);
+    assert_eq!(reads, count_reads(region.input(), &region));
+}
+
+#[test]
+fn test_unique_reads_for_stats() {
+    let stats = |extra: &[&str], output: &str| -> serde_json::Value {
+        let mut arguments = vec![
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            "test-unique-reads.arrow",
+            "--length-stats",
+            output,
+        ];
+        arguments.extend(extra);
+        let args = Cli::parse_from(arguments);
+        extract(&args.inputs(), &args);
+        serde_json::from_reader(File::open(output).unwrap()).unwrap()
+    };
+    let all = stats(&[], "test-unique-reads-all.json");
+    let unique = stats(&["--unique-reads-for-stats"], "test-unique-reads.json");
+    let args = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    let mut names = HashSet::new();
+    let mut supplementary = 0;
+    for_each_read(args.input(), &args, |read, _| {
+        names.insert(read.qname().to_vec());
+        supplementary += read.is_supplementary() as usize;
+    });
+    assert!(supplementary > 0);
+    assert_eq!(unique["reads"], names.len());
+    assert!(unique["reads"].as_u64() < all["reads"].as_u64());
+}
+
+#[test]
+fn test_emit_checksum() {
+    let checksum = |output: &str, format: &str| {
+        let args = Cli::parse_from([
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            output,
+            "--format",
+            format,
+            "--emit-checksum",
+            "--include-names",
+            "--batch-size",
+            "1000",
+        ]);
+        extract(&args.inputs(), &args).unwrap()
+    };
+    let arrow = checksum("test-checksum.arrow", "arrow");
+    assert_eq!(arrow, checksum("test-checksum.tsv", "tsv"));
+    assert_eq!(arrow, checksum("test-checksum.arrow", "arrow"));
+}
+
+#[test]
+fn test_strand() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--strand",
+        "reverse",
+    ]);
+    let mut reverse = 0;
+    for_each_read(args.input(), &args, |read, _| {
+        assert!(read.is_reverse());
+        reverse += 1;
+    });
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--strand",
+        "forward",
+    ]);
+    let forward = count_reads(args.input(), &args);
+    assert!(reverse > 0 && forward > 0);
+    let args = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    assert_eq!(forward + reverse, count_reads(args.input(), &args));
+}
+
+#[test]
+fn test_annotate_filters() {
+    use arrow::array::{Array, BooleanArray};
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-annotate-filters.arrow",
+        "--annotate-filters",
+        "--strand",
+        "forward",
+        "--min-ref-span",
+        "5000",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-annotate-filters.arrow");
+    let column = |name: &str| -> Vec<bool> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                let column = batch
+                    .column_by_name(name)
+                    .unwrap_or_else(|| panic!("Column {name} is missing"))
+                    .as_any()
+                    .downcast_ref::<BooleanArray>()
+                    .unwrap();
+                assert_eq!(column.null_count(), 0);
+                column
+                    .iter()
+                    .map(|value| value.unwrap())
+                    .collect::<Vec<bool>>()
+            })
+            .collect()
+    };
+    let mut expected = vec![];
+    let mut bam = bam::Reader::from_path(args.input()).unwrap();
+    for read in bam.records() {
+        let read = read.unwrap();
+        let flags = !(read.is_unmapped() || read.is_secondary());
+        let strand = !read.is_reverse();
+        let span = read.reference_end() - read.reference_start() >= 5000;
+        expected.push([flags, strand, span, flags && strand && span]);
+    }
+    // every read is written, with the verdict of each filter
+    assert_eq!(num_rows(&batches), expected.len());
+    for (i, name) in ["pass_flags", "pass_strand", "pass_min_ref_span", "pass"]
+        .iter()
+        .enumerate()
+    {
+        let verdicts: Vec<bool> = expected.iter().map(|verdicts| verdicts[i]).collect();
+        assert_eq!(column(name), verdicts, "{name}");
+    }
+    assert!(batches[0].column_by_name("pass_contigs").is_none());
+    // the reads that pass are the reads kept without --annotate-filters
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--strand",
+        "forward",
+        "--min-ref-span",
+        "5000",
+    ]);
+    let passed = column("pass").iter().filter(|passes| **passes).count();
+    assert!(passed > 0 && passed < expected.len());
+    assert_eq!(passed, count_reads(args.input(), &args));
+}
+
+#[test]
+fn test_min_ref_span() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--min-ref-span",
+        "5000",
+    ]);
+    let mut kept = 0;
+    for_each_read(args.input(), &args, |read, _| {
+        assert!(read.reference_end() - read.reference_start() >= 5000);
+        kept += 1;
+    });
+    let all = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    let mut short = 0;
+    for_each_read(all.input(), &all, |read, _| {
+        short += (read.reference_end() - read.reference_start() < 5000) as usize;
+    });
+    assert!(kept > 0 && short > 0);
+    assert_eq!(kept + short, count_reads(all.input(), &all));
+}
+
+#[test]
+fn test_min_aligned_fraction() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--min-aligned-fraction",
+        "0.95",
+    ]);
+    let mut kept = 0;
+    for_each_read(args.input(), &args, |read, _| {
+        assert!(metrics::aligned_fraction(read).unwrap() >= 0.95);
+        kept += 1;
+    });
+    let all = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    let mut below = 0;
+    for_each_read(all.input(), &all, |read, _| {
+        below += (metrics::aligned_fraction(read).unwrap() < 0.95) as usize;
+    });
+    assert!(kept > 0 && below > 0);
+    assert_eq!(kept + below, count_reads(all.input(), &all));
+}
+
+#[test]
+fn test_invalid_name_regex() {
+    assert!(Cli::try_parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--name-regex",
+        "^3f(",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_ipc_version() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-ipc-v4.arrow",
+        "--ipc-version",
+        "V4",
+    ]);
+    extract(&args.inputs(), &args);
+    // the footer is followed by its length as int32 and the magic string
+    let bytes = std::fs::read("test-ipc-v4.arrow").unwrap();
+    let footer_end = bytes.len() - 10;
+    let footer_len = i32::from_le_bytes(bytes[footer_end..footer_end + 4].try_into().unwrap());
+    let footer =
+        arrow::ipc::root_as_footer(&bytes[footer_end - footer_len as usize..footer_end]).unwrap();
+    assert_eq!(footer.version(), MetadataVersion::V4);
+    assert!(num_rows(&read_arrow("test-ipc-v4.arrow")) > 0);
+}
+
+#[test]
+fn test_edge_distance() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-edge-distance.arrow",
+        "--edge-distance",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-edge-distance.arrow");
+    // all reads in the test file are on chr7, of 159345973 bases
+    assert_eq!(
+        sum_column(&batches, "dist_to_contig_start")
+            + sum_column(&batches, "aligned_lengths")
+            + sum_column(&batches, "dist_to_contig_end"),
+        159345973 * num_rows(&batches) as u64
+    );
+}
+
+#[test]
+fn test_regions_bed() {
+    std::fs::write(
+        "test-regions.bed",
+        "chr7\t152700000\t152760000\nchr7\t152740000\t152800000\n",
+    )
+    .unwrap();
+    let count = |extra: &[&str], output: &str| {
+        let mut arguments = vec![
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            output,
+        ];
+        arguments.extend(extra);
+        let args = Cli::parse_from(arguments);
+        extract(&args.inputs(), &args);
+        num_rows(&read_arrow(output))
+    };
+    let union = count(
+        &["--regions-bed", "test-regions.bed"],
+        "test-regions-bed.arrow",
+    );
+    let first = count(
+        &["--region", "chr7:152700001-152760000"],
+        "test-region-1.arrow",
+    );
+    let second = count(
+        &["--region", "chr7:152740001-152800000"],
+        "test-region-2.arrow",
+    );
+    let spanning = count(
+        &["--region", "chr7:152700001-152800000"],
+        "test-region-3.arrow",
+    );
+    assert!(first > 0 && second > 0);
+    // reads overlapping both intervals are not counted twice
+    assert!(union < first + second);
+    assert_eq!(union, spanning);
+}
+
+#[test]
+fn test_merge() {
+    let extract_region = |region: &str, output: &str| {
+        let args = Cli::parse_from([
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            output,
+            "--region",
+            region,
+        ]);
+        extract(&args.inputs(), &args);
+    };
+    extract_region("chr7:152700001-152760000", "test-merge-1.arrow");
+    extract_region("chr7:152740001-152800000", "test-merge-2.arrow");
+    let args = Cli::parse_from([
+        "make_arrow",
+        "merge",
+        "test-merge-1.arrow",
+        "test-merge-2.arrow",
+        "-o",
+        "test-merged.arrow",
+    ]);
+    let Some(Command::Merge { inputs, output }) = &args.command else {
+        panic!("Expected the merge subcommand");
+    };
+    merge::merge(inputs, output, write_options(&args));
+    let first = read_arrow("test-merge-1.arrow");
+    let second = read_arrow("test-merge-2.arrow");
+    let merged = read_arrow("test-merged.arrow");
+    assert!(num_rows(&first) > 0 && num_rows(&second) > 0);
+    assert_eq!(num_rows(&merged), num_rows(&first) + num_rows(&second));
+    assert_eq!(merged[0].schema(), first[0].schema());
+}
+
+#[test]
+#[should_panic(expected = "is different from")]
+fn test_merge_different_schemas() {
+    for (output, extra) in [
+        ("test-merge-schema-1.arrow", None),
+        ("test-merge-schema-2.arrow", Some("--quality")),
+    ] {
+        let mut arguments = vec![
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            output,
+            "--region",
+            "chr7:152700001-152710000",
+        ];
+        arguments.extend(extra);
+        let args = Cli::parse_from(arguments);
+        extract(&args.inputs(), &args);
+    }
+    merge::merge(
+        &[
+            "test-merge-schema-1.arrow".to_string(),
+            "test-merge-schema-2.arrow".to_string(),
+        ],
+        "test-merge-schema.arrow",
+        IpcWriteOptions::default(),
+    );
+}
+
+#[test]
+fn test_secondary_count() {
+    use arrow::array::{Array, StringArray, UInt64Array};
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-secondary-count.arrow",
+        "--secondary-count",
+        "--include-names",
+    ]);
+    extract(&args.inputs(), &args);
+    let mut expected: HashMap<String, u64> = HashMap::new();
+    for read in bam::Reader::from_path(args.input()).unwrap().records() {
+        let read = read.unwrap();
+        if read.is_secondary() {
+            *expected
+                .entry(String::from_utf8(read.qname().to_vec()).unwrap())
+                .or_insert(0) += 1;
+        }
+    }
+    let mut multimapping = 0;
+    for batch in read_arrow("test-secondary-count.arrow") {
+        let names = batch
+            .column_by_name("names")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .clone();
+        let counts = batch
+            .column_by_name("secondary_count")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap()
+            .clone();
+        for i in 0..batch.num_rows() {
+            // supplementary alignments have no count
+            if counts.is_valid(i) {
+                let count = expected.get(names.value(i)).copied().unwrap_or(0);
+                assert_eq!(counts.value(i), count);
+                multimapping += (count > 0) as usize;
+            }
+        }
+    }
+    assert!(multimapping > 0);
+}
+
+#[test]
+fn test_positions() {
+    use arrow::array::{Array, UInt64Array};
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-positions.arrow",
+        "--positions",
+    ]);
+    extract(&args.inputs(), &args);
+    for batch in read_arrow("test-positions.arrow") {
+        let column = |name| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .unwrap()
+                .clone()
+        };
+        let (starts, ends) = (column("ref_start"), column("ref_end"));
+        let aligned_lengths = column("aligned_lengths");
+        assert_eq!(starts.null_count(), 0);
+        for i in 0..batch.num_rows() {
+            assert_eq!(ends.value(i) - starts.value(i), aligned_lengths.value(i));
+        }
+    }
+}
+
+#[test]
+fn test_intervals() {
+    use arrow::array::{Array, DictionaryArray, StringArray, UInt64Array};
+    use arrow::datatypes::Int8Type;
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-intervals.arrow",
+        "--intervals",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-intervals.arrow");
+    let names: Vec<String> = batches[0]
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| field.name().clone())
+        .collect();
+    assert_eq!(names, ["read_name", "ref_start", "ref_end", "strand"]);
+    let batch = &batches[0];
+    let read_names = batch
+        .column_by_name("read_name")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    let column = |name| {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap()
+            .clone()
+    };
+    let (starts, ends) = (column("ref_start"), column("ref_end"));
+    let strands = batch
+        .column_by_name("strand")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<DictionaryArray<Int8Type>>()
+        .unwrap();
+    let strand_values = strands
+        .values()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    let mut i = 0;
+    for_each_read(args.input(), &args, |read, _| {
+        if i < 100 {
+            assert_eq!(read_names.value(i), String::from_utf8_lossy(read.qname()));
+            assert_eq!(starts.value(i), read.reference_start() as u64);
+            assert_eq!(ends.value(i), read.reference_end() as u64);
+            let strand = strand_values.value(strands.keys().value(i) as usize);
+            assert_eq!(strand, if read.is_reverse() { "-" } else { "+" });
+        }
+        i += 1;
+    });
+    assert_eq!(num_rows(&batches), i);
+}
+
+#[test]
+fn test_include_cigar() {
+    use arrow::array::{Array, StringArray};
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-include-cigar.arrow",
+        "--include-cigar",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-include-cigar.arrow");
+    let cigars = batches[0]
+        .column_by_name("cigar")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    assert_eq!(cigars.null_count(), 0);
+    let mut i = 0;
+    for_each_read(args.input(), &args, |read, _| {
+        if i < 100 {
+            assert_eq!(cigars.value(i), read.cigar().to_string());
+        }
+        i += 1;
+    });
+}
+
+#[test]
+fn test_region_lines() {
+    let args = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    let mut positions = vec![];
+    for_each_read(args.input(), &args, |read, _| positions.push(read.pos()));
+    let (first, last) = (positions[0], positions[positions.len() - 1]);
+    // 1-based regions of a single base at the start of the first and last read
+    let lines = format!("chr7:{0}-{0}\n\nchr7:{1}-{1}\n", first + 1, last + 1);
+    let mut bam = bam::IndexedReader::from_path(args.input()).unwrap();
+    let header = bam.header().clone();
+    let mut fetched = vec![];
+    for_each_read_in_region_lines(
+        &mut bam,
+        &header,
+        &args,
+        std::io::Cursor::new(lines),
+        |read, _| fetched.push(read.reference_start()),
+    );
+    assert!(fetched.contains(&first));
+    assert!(fetched.contains(&last));
+    let split = fetched.iter().position(|start| *start > first).unwrap();
+    assert!(fetched[..split].iter().all(|start| *start <= first));
+    assert!(fetched[split..].iter().all(|start| *start <= last));
+}
+
+#[test]
+fn test_input_fofn() {
+    use arrow::array::StringArray;
+    std::fs::write(
+        "test-input.fofn",
+        "# two copies of the test data\ntest-data/small-test-phased.bam\n\n  test-input-copy.bam\n",
+    )
+    .unwrap();
+    std::fs::copy("test-data/small-test-phased.bam", "test-input-copy.bam").unwrap();
+    let args = Cli::parse_from([
+        "make_arrow",
+        "--input-fofn",
+        "test-input.fofn",
+        "-o",
+        "test-input-fofn.arrow",
+    ]);
+    assert_eq!(
+        args.inputs(),
+        ["test-data/small-test-phased.bam", "test-input-copy.bam"]
+    );
+    extract(&args.inputs(), &args);
+    let single = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    let reads = count_reads(single.input(), &single);
+    let mut sources: HashMap<String, usize> = HashMap::new();
+    for batch in read_arrow("test-input-fofn.arrow") {
+        let source_files = batch
+            .column_by_name("source_file")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .clone();
+        for source_file in source_files.iter() {
+            *sources.entry(source_file.unwrap().to_string()).or_insert(0) += 1;
+        }
+    }
+    assert_eq!(sources["test-data/small-test-phased.bam"], reads);
+    assert_eq!(sources["test-input-copy.bam"], reads);
+}
+
+#[test]
+fn test_file_parallelism() {
+    use arrow::array::StringArray;
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let mut records = reader.records();
+    // three inputs with 1000, 2000 and 3000 different reads
+    let mut fofn = String::new();
+    for (i, reads) in [1000, 2000, 3000].into_iter().enumerate() {
+        let path = format!("test-file-parallelism-{i}.bam");
+        let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+        for read in records.by_ref().take(reads) {
+            writer.write(&read.unwrap()).unwrap();
+        }
+        fofn.push_str(&format!("{path}\n"));
+    }
+    std::fs::write("test-file-parallelism.fofn", fofn).unwrap();
+    let args = Cli::parse_from([
+        "make_arrow",
+        "--input-fofn",
+        "test-file-parallelism.fofn",
+        "-o",
+        "test-file-parallelism.arrow",
+        "--file-parallelism",
+        "2",
+    ]);
+    extract(&args.inputs(), &args);
+    let mut sources: HashMap<String, usize> = HashMap::new();
+    for batch in read_arrow("test-file-parallelism.arrow") {
+        let source_files = batch
+            .column_by_name("source_file")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .clone();
+        for source_file in source_files.iter() {
+            *sources.entry(source_file.unwrap().to_string()).or_insert(0) += 1;
+        }
+    }
+    for input in args.inputs() {
+        assert_eq!(sources[&input], count_reads(&input, &args));
+    }
+    assert_eq!(sources.len(), 3);
+}
+
+/// Writes the first reads of the test file, of which the first is unmapped without contig and CIGAR
+#[cfg(test)]
+fn write_with_unmapped_read(path: &str) {
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+    for (i, read) in reader.records().take(10).enumerate() {
+        let mut read = read.unwrap();
+        if i == 0 {
+            let (qname, seq, qual) = (
+                read.qname().to_vec(),
+                read.seq().as_bytes(),
+                read.qual().to_vec(),
+            );
+            read.set(&qname, None, &seq, &qual);
+            read.set_unmapped();
+            read.set_tid(-1);
+            read.set_pos(-1);
+        }
+        writer.write(&read).unwrap();
+    }
+}
+
+#[test]
+fn test_annotation_bed_unmapped() {
+    use arrow::array::{Array, UInt64Array};
+    write_with_unmapped_read("test-annotation-unmapped.bam");
+    std::fs::write("test-annotation-unmapped.bed", "chr7\t0\t200000000\n").unwrap();
+    for filter in ["--include-flags=4", "--annotate-filters"] {
+        let args = Cli::parse_from([
+            "make_arrow",
+            "test-annotation-unmapped.bam",
+            "-o",
+            "test-annotation-unmapped.arrow",
+            "--annotation-bed",
+            "test-annotation-unmapped.bed",
+            filter,
+        ]);
+        extract(&args.inputs(), &args);
+        let batches = read_arrow("test-annotation-unmapped.arrow");
+        assert_eq!(num_rows(&batches), count_reads(args.input(), &args));
+        let overlaps = batches[0]
+            .column_by_name("overlapping_features")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap()
+            .clone();
+        assert!(overlaps.is_null(0));
+        assert_eq!(overlaps.null_count(), 1);
+    }
+}
+
+#[test]
+fn test_annotation_bed() {
+    use arrow::array::{Array, UInt64Array};
+    std::fs::write(
+        "test-annotation.bed",
+        "chr7\t0\t152750000\nchr7\t152750000\t200000000\nchr1\t0\t200000000\n",
+    )
+    .unwrap();
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-annotation.arrow",
+        "--annotation-bed",
+        "test-annotation.bed",
+        "--edge-distance",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-annotation.arrow");
+    let mut spanning = 0;
+    for batch in &batches {
+        let column = |name| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .unwrap()
+                .clone()
+        };
+        let (overlaps, starts) = (
+            column("overlapping_features"),
+            column("dist_to_contig_start"),
+        );
+        let aligned_lengths = column("aligned_lengths");
+        assert_eq!(overlaps.null_count(), 0);
+        for i in 0..batch.num_rows() {
+            let (start, end) = (starts.value(i), starts.value(i) + aligned_lengths.value(i));
+            let expected = if start < 152750000 && end > 152750000 {
+                2
+            } else {
+                1
+            };
+            spanning += (expected == 2) as usize;
+            assert_eq!(overlaps.value(i), expected);
+        }
+    }
+    assert!(spanning > 0);
+}
+
+#[test]
+fn test_checkpoint_resume() {
+    let run = |resume: bool| {
+        let mut arguments = vec![
+            "make_arrow",
+            "test-data/small-test-phased.bam",
+            "-o",
+            "test-resume.tsv",
+            "--format",
+            "tsv",
+            "--batch-size",
+            "1000",
+            "--checkpoint",
+            "test-resume.json",
+        ];
+        if resume {
+            arguments.push("--resume");
+        }
+        let args = Cli::parse_from(arguments);
+        extract(&args.inputs(), &args);
+    };
+    run(false);
+    let complete = std::fs::read_to_string("test-resume.tsv").unwrap();
+    // the last checkpoint is of the last complete batch, before the remaining reads
+    let saved = checkpoint::Checkpoint::load("test-resume.json");
+    let rows = complete.lines().count() as u64 - 1;
+    assert_eq!(saved.reads, rows / 1000 * 1000);
+    assert!(saved.reads < rows);
+    // an interruption while writing the next batch leaves a partial batch after the checkpoint
+    let mut interrupted = complete.as_bytes()[..saved.output_bytes as usize].to_vec();
+    interrupted.extend_from_slice(b"99.1\t1234\tpartial");
+    std::fs::write("test-resume.tsv", interrupted).unwrap();
+    run(true);
+    assert_eq!(
+        std::fs::read_to_string("test-resume.tsv").unwrap(),
+        complete
+    );
+}
+
+#[test]
+fn test_polars_optimized() {
+    use arrow::datatypes::DataType;
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-polars-optimized.arrow",
+        "--polars-optimized",
+        "--ref-name-and-strand",
+        "--quality",
+        "--batch-size",
+        "1000",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-polars-optimized.arrow");
+    assert!(batches.len() > 1);
+    let schema = batches[0].schema();
+    let data_type = |name: &str| schema.field_with_name(name).unwrap().data_type().clone();
+    assert_eq!(data_type("identities"), DataType::Float32);
+    assert_eq!(data_type("quals"), DataType::Float32);
+    assert_eq!(data_type("lengths"), DataType::UInt32);
+    assert_eq!(data_type("mapQ"), DataType::UInt8);
+    assert_eq!(
+        data_type("ref_name"),
+        DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+    );
+    assert_eq!(
+        data_type("strand"),
+        DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8))
+    );
+    let ref_name = arrow::util::display::array_value_to_string(
+        batches[0].column_by_name("ref_name").unwrap(),
+        0,
+    )
+    .unwrap();
+    assert_eq!(ref_name, "chr7");
+}
+
+#[test]
+fn test_blacklist_bed() {
+    let (start, end) = (152_750_000, 152_750_100);
+    std::fs::write("test-blacklist.bed", format!("chr7\t{start}\t{end}\n")).unwrap();
+    let all = Cli::parse_from(["make_arrow", "test-data/small-test-phased.bam"]);
+    let mut overlapping = 0;
+    for_each_read(all.input(), &all, |read, _| {
+        if (read.reference_start() as u64) < end && read.reference_end() as u64 > start {
+            overlapping += 1;
+        }
+    });
+    assert!(overlapping > 0);
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--blacklist-bed",
+        "test-blacklist.bed",
+    ]);
+    for_each_read(args.input(), &args, |read, _| {
+        assert!(read.reference_end() as u64 <= start || read.reference_start() as u64 >= end);
+    });
+    assert_eq!(
+        count_reads(args.input(), &args),
+        count_reads(all.input(), &all) - overlapping
+    );
+}
+
+#[test]
+fn test_sample_label() {
+    use arrow::array::StringArray;
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-sample-label.arrow",
+        "--sample-label",
+        "HG002",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-sample-label.arrow");
+    for batch in &batches {
+        let samples = batch
+            .column_by_name("sample")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .clone();
+        assert!(samples.iter().all(|sample| sample == Some("HG002")));
+    }
+    assert_eq!(num_rows(&batches), count_reads(args.input(), &args));
+}
+
+#[test]
+fn test_multiple_outputs() {
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-multiple-outputs.arrow,test-multiple-outputs.tsv",
+        "--batch-size",
+        "1000",
+    ]);
+    extract(&args.inputs(), &args);
+    let batches = read_arrow("test-multiple-outputs.arrow");
+    let tsv = std::fs::read_to_string("test-multiple-outputs.tsv").unwrap();
+    let rows: Vec<&str> = tsv.lines().skip(1).collect();
+    assert_eq!(rows.len(), num_rows(&batches));
+    let lengths = batches.iter().flat_map(|batch| {
+        let formatter = arrow::util::display::ArrayFormatter::try_new(
+            batch.column_by_name("lengths").unwrap(),
+            &Default::default(),
+        )
+        .unwrap();
+        (0..batch.num_rows())
+            .map(|i| formatter.value(i).to_string())
+            .collect::<Vec<_>>()
+    });
+    for (row, length) in rows.iter().zip(lengths) {
+        assert_eq!(row.split('\t').nth(1).unwrap(), length);
+    }
+}
+
+#[test]
+fn test_split_by_mapq() {
+    use arrow::array::{Array, UInt8Array};
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "-o",
+        "test-split-by-mapq.arrow",
+        "--split-by-mapq",
+        "1,31,60",
+    ]);
+    extract(&args.inputs(), &args);
+    let buckets = [(0, 0), (1, 30), (31, 59), (60, 255)];
+    let mut total = 0;
+    for (min, max) in buckets {
+        let batches = read_arrow(&format!("test-split-by-mapq.mapq{min}-{max}.arrow"));
+        for batch in &batches {
+            let mapqs = batch
+                .column_by_name("mapQ")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<UInt8Array>()
+                .unwrap();
+            assert!(mapqs
+                .iter()
+                .all(|mapq| (min..=max).contains(&mapq.unwrap())));
+        }
+        total += num_rows(&batches);
+    }
+    assert_eq!(total, count_reads(args.input(), &args));
 }
@@ -0,0 +1,42 @@
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::{FileWriter, IpcWriteOptions};
+use log::info;
+use std::fs::File;
+
+/// Concatenates the record batches of arrow files with identical schemas into one arrow file
+pub fn merge(inputs: &[String], output: &str, write_options: IpcWriteOptions) {
+    let mut writer: Option<FileWriter<File>> = None;
+    let mut schema = None;
+    for input in inputs {
+        let file = File::open(input).unwrap_or_else(|_| panic!("Failure opening {input}"));
+        let reader = FileReader::try_new(file, None)
+            .unwrap_or_else(|_| panic!("Failure reading {input}, is it an arrow file?"));
+        match &schema {
+            None => {
+                let file = File::create(output).expect("create merged arrow file error");
+                writer = Some(
+                    FileWriter::try_new_with_options(file, &reader.schema(), write_options.clone())
+                        .expect("create merged arrow file writer error"),
+                );
+                schema = Some(reader.schema());
+            }
+            Some(schema) if *schema != reader.schema() => {
+                panic!(
+                    "The schema of {input} is different from that of {}",
+                    inputs[0]
+                )
+            }
+            Some(_) => (),
+        }
+        let writer = writer.as_mut().unwrap();
+        for batch in reader {
+            writer
+                .write(&batch.expect("read arrow batch error"))
+                .expect("write merged arrow batch error");
+        }
+        info!("Merged {input}");
+    }
+    if let Some(mut writer) = writer {
+        writer.finish().expect("finish write merged arrow error");
+    }
+}
@@ -0,0 +1,1867 @@
+use clap::ValueEnum;
+use log::warn;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::{Aux, Cigar, CigarStringView};
+use rust_htslib::bam::{self, HeaderView};
+use std::hash::{Hash, Hasher};
+use std::sync::{Once, OnceLock};
+use twox_hash::XxHash64;
+
+use crate::error_detail::{parse_md, MdBase};
+use crate::modifications::{self, ModType};
+use crate::Cli;
+
+/// The orientation in which the clips at both ends of a read are reported
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClipOrientation {
+    /// Left and right clips along the reference, as in the CIGAR
+    Reference,
+    /// 5' and 3' clips of the read, swapped for reverse strand reads
+    Read,
+}
+
+/// Where the matches and mismatches of the identity are taken from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentitySource {
+    /// The de tag, or otherwise the CIGAR and NM tag
+    Tags,
+    /// Only the =/X CIGAR operations, failing on reads with M operations
+    CigarEqx,
+}
+
+/// What is written for reads of which the identity can not be calculated
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingIdentity {
+    /// Write the read with a missing identity
+    Null,
+    /// Write the read with NaN as identity
+    Nan,
+    /// Do not write the read
+    Drop,
+}
+
+/// What is done with reads mapped beyond the end of their contig, which are corrupt
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContigOverrun {
+    /// Write the read without its aligned length, positions and distances to the contig ends
+    Null,
+    /// Do not write the read
+    Drop,
+}
+
+/// How the base qualities of a read are combined into a single quality
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QualityMethod {
+    /// The mean of the Phred scores
+    MeanPhred,
+    /// The Phred score of the mean error probability
+    MeanError,
+}
+
+/// Whether an alignment is the primary, a secondary or a supplementary alignment of its read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlignmentType {
+    Primary,
+    Secondary,
+    Supplementary,
+}
+
+impl AlignmentType {
+    pub const NAMES: [&'static str; 3] = ["primary", "secondary", "supplementary"];
+
+    fn from_record(record: &bam::Record) -> Self {
+        if record.is_secondary() {
+            AlignmentType::Secondary
+        } else if record.is_supplementary() {
+            AlignmentType::Supplementary
+        } else {
+            AlignmentType::Primary
+        }
+    }
+}
+
+/// The metrics extracted from a single read
+/// optional metrics are None unless the corresponding argument was set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadMetrics {
+    pub identity: Option<f64>,
+    pub length: u64,
+    pub aligned_length: Option<u64>,
+    pub mapq: u8,
+    pub gc_content: Option<f64>,
+    /// The GC content excluding soft-clipped bases
+    pub aligned_gc_content: Option<f64>,
+    pub dist_to_contig_start: Option<u64>,
+    pub dist_to_contig_end: Option<u64>,
+    pub quality: Option<f64>,
+    pub aligned_quality: Option<f64>,
+    /// The minimum and maximum base quality
+    pub quality_range: Option<(u8, u8)>,
+    pub mod_fractions: Vec<(ModType, f64)>,
+    pub mean_mod_probability: Option<f64>,
+    pub channel: Option<u32>,
+    pub start_time: Option<String>,
+    pub name: Option<String>,
+    /// None for reads without CIGAR
+    pub cigar: Option<String>,
+    pub num_passes: Option<i32>,
+    pub predicted_accuracy: Option<f64>,
+    /// The left and right, or 5' and 3', soft- and hard-clipped bases
+    pub clips: Option<(u64, u64)>,
+    /// The fraction of the read which is clipped at both ends
+    pub clipped_fraction: Option<f64>,
+    pub overlapping_features: Option<u64>,
+    /// The approximate coverage depth at the reference midpoint, None for unmapped reads
+    pub depth: Option<f64>,
+    pub ref_consumed: Option<u64>,
+    pub query_consumed: Option<u64>,
+    pub alignment_type: Option<AlignmentType>,
+    /// The hash of the read name, shared by the alignments of a read
+    pub read_group_id: Option<u64>,
+    pub mate_distance: Option<i64>,
+    /// The absolute template length of properly paired reads
+    pub fragment_length: Option<u64>,
+    pub mismatch_rate: Option<f64>,
+    pub insertion_rate: Option<f64>,
+    pub deletion_rate: Option<f64>,
+    pub barcode: Option<String>,
+    pub longest_match_run: Option<u64>,
+    /// The lowest percentage of matching alignment columns in a sliding window
+    pub min_window_identity: Option<f64>,
+    /// The percentage of matching alignment columns in the central --core-fraction of the alignment
+    pub core_identity: Option<f64>,
+    /// The NM tag used in the identity, None if the identity is from the de tag
+    pub edit_distance: Option<u64>,
+    pub is_spliced: Option<bool>,
+    /// Whether only one end has a soft clip of at least --clip-min bases
+    pub one_sided_clip: Option<bool>,
+    pub num_introns: Option<u64>,
+    /// The number of secondary alignments, only for primary alignments
+    pub secondary_count: Option<u64>,
+    /// The 0-based half-open reference interval of the alignment
+    pub ref_start: Option<u64>,
+    pub ref_end: Option<u64>,
+    /// The 0-based half-open interval of the aligned bases in the read as sequenced
+    pub query_start: Option<u64>,
+    pub query_end: Option<u64>,
+    pub source_file: Option<String>,
+    /// The --sample-label, the same for all reads
+    pub sample: Option<String>,
+    /// The contig of the alignment, None for unmapped reads
+    pub contig: Option<String>,
+    /// Whether the alignment is on the reverse strand, None for unmapped reads
+    pub reverse: Option<bool>,
+    pub total_intron_length: Option<u64>,
+    pub max_intron_length: Option<u64>,
+    /// The number of A, C, G, T and other bases in the read sequence
+    pub base_counts: Option<[u64; 5]>,
+    pub flags: Option<u16>,
+    /// Whether the identity was outside the --identity-range
+    pub identity_clamped: bool,
+    /// Whether the read passes each filter, with --annotate-filters
+    pub filters: Vec<(crate::Filter, bool)>,
+}
+
+impl ReadMetrics {
+    /// Only the name, reference span and strand of the read, for --intervals
+    pub fn interval(record: &bam::Record) -> Self {
+        let mut metrics = ReadMetrics {
+            name: Some(read_name(record.qname())),
+            ..Default::default()
+        };
+        if !record.is_unmapped() {
+            metrics.ref_start = Some(record.reference_start() as u64);
+            metrics.ref_end = Some(record.reference_end() as u64);
+            metrics.reverse = Some(record.is_reverse());
+        }
+        metrics
+    }
+
+    pub fn from_record(record: &bam::Record, args: &Cli, header: &HeaderView) -> Self {
+        let mut metrics = ReadMetrics {
+            length: record.seq_len() as u64,
+            mapq: record.mapq(),
+            ..Default::default()
+        };
+        // without CIGAR the identity would be 0/0 and the aligned length is unknown
+        if record.cigar_len() == 0 {
+            // unmapped reads never have a CIGAR
+            if !record.is_unmapped() {
+                let name = String::from_utf8_lossy(record.qname());
+                MISSING_CIGAR_WARNING.call_once(|| {
+                    warn!("Mapped reads without CIGAR, such as {name}, have no identity and aligned length")
+                });
+            }
+        } else {
+            match gap_compressed_identity(
+                record,
+                args.identity_source,
+                args.prefer_computed_identity,
+                args.clip_penalty,
+                args.identity_ignore_homopolymer,
+            ) {
+                Some(identity) => {
+                    let identity = identity * 100.0;
+                    let (min, max) = args.identity_range;
+                    metrics.identity_clamped = !(min..=max).contains(&identity);
+                    metrics.identity = Some(identity.clamp(min, max));
+                }
+                // without aligned bases the identity would be 0/0
+                None if args.identity_source == IdentitySource::CigarEqx
+                    || get_nm_tag(record).is_some() => {}
+                None => {
+                    let name = String::from_utf8_lossy(record.qname());
+                    if args.fail_on_missing_tag {
+                        panic!("Read {name} has no NM tag, which is required to calculate its identity");
+                    }
+                    MISSING_NM_WARNING.call_once(|| {
+                        warn!("Reads without NM tag, such as {name}, have no identity")
+                    });
+                }
+            }
+            metrics.aligned_length =
+                Some((record.reference_end() - record.reference_start()) as u64);
+            if args.edit_distance {
+                let uses_de = !(args.prefer_computed_identity
+                    || args.clip_penalty
+                    || args.identity_ignore_homopolymer)
+                    && get_de_tag(record).is_some();
+                if !uses_de && args.identity_source == IdentitySource::Tags {
+                    metrics.edit_distance =
+                        get_int_tag(record, b"NM").and_then(|v| u64::try_from(v).ok());
+                }
+            }
+        }
+        if metrics.identity.is_none() && args.on_missing_identity == MissingIdentity::Nan {
+            metrics.identity = Some(f64::NAN);
+        }
+        if args.positions && !record.is_unmapped() {
+            metrics.ref_start = Some(record.reference_start() as u64);
+            metrics.ref_end = Some(record.reference_end() as u64);
+        }
+        // a sequence stored as * has no bases, so the metrics of the sequence are missing
+        let has_sequence = record.seq_len() > 0;
+        if (args.gc_bias.is_some() || args.gc_aligned) && has_sequence {
+            let seq = record.seq().as_bytes();
+            metrics.gc_content = Some(gc_content(&seq));
+            if args.gc_aligned {
+                metrics.aligned_gc_content = Some(gc_content(aligned_part(record, &seq)));
+            }
+        }
+        if (args.parquet_partition_by.is_some() || args.ref_name_and_strand) && record.tid() >= 0 {
+            metrics.contig =
+                Some(String::from_utf8_lossy(header.tid2name(record.tid() as u32)).to_string());
+        }
+        if args.ref_name_and_strand && !record.is_unmapped() {
+            metrics.reverse = Some(record.is_reverse());
+        }
+        if args.include_flags_column {
+            metrics.flags = Some(record.flags());
+        }
+        if args.base_composition && has_sequence {
+            metrics.base_counts = Some(base_counts(record));
+        }
+        if args.edge_distance
+            && !record.is_unmapped()
+            && record.tid() >= 0
+            && !overruns_contig(record, header)
+        {
+            let contig_length = header
+                .target_len(record.tid() as u32)
+                .expect("Failure getting contig length from header");
+            let (start, end) = edge_distances(
+                record.reference_start() as u64,
+                record.reference_end() as u64,
+                contig_length,
+            );
+            metrics.dist_to_contig_start = Some(start);
+            metrics.dist_to_contig_end = Some(end);
+        }
+        if args.quality || args.quality_aligned || args.quality_range {
+            if let Some(qual) = base_qualities(record) {
+                if args.quality_range {
+                    metrics.quality_range = quality_range(qual);
+                }
+                if args.quality {
+                    metrics.quality = mean_quality(qual, args.quality_method);
+                }
+                if args.quality_aligned {
+                    metrics.aligned_quality =
+                        mean_quality(aligned_part(record, qual), args.quality_method);
+                }
+            }
+        }
+        if !args.mod_types.is_empty() || args.mod_mean {
+            if let Some((mm, ml)) = modifications::get_mm_ml_tags(record) {
+                metrics.mod_fractions = modifications::mod_fractions(&mm, &ml, &args.mod_types);
+                if args.mod_mean {
+                    metrics.mean_mod_probability =
+                        modifications::mean_probability(&mm, &ml, &args.mod_types);
+                }
+            }
+        }
+        if args.nanopore_tags {
+            metrics.channel = get_int_tag(record, b"ch").and_then(|v| u32::try_from(v).ok());
+            metrics.start_time = get_string_tag(record, b"st");
+        }
+        if args.hifi_tags {
+            metrics.num_passes = get_int_tag(record, b"np").and_then(|v| i32::try_from(v).ok());
+            metrics.predicted_accuracy = get_float_tag(record, b"rq");
+        }
+        if args.clips {
+            let clips = clip_lengths(&record.cigar());
+            metrics.clipped_fraction = clipped_fraction(record, clips);
+            metrics.clips = Some(oriented_clips(
+                clips,
+                record.is_reverse(),
+                args.clip_orientation,
+            ));
+        }
+        if args.cigar_consumption {
+            let (ref_consumed, query_consumed) = cigar_consumption(&record.cigar());
+            metrics.ref_consumed = Some(ref_consumed);
+            metrics.query_consumed = Some(query_consumed);
+        }
+        if args.alignment_type || args.link_supplementary {
+            metrics.alignment_type = Some(AlignmentType::from_record(record));
+        }
+        if args.link_supplementary {
+            metrics.read_group_id = Some(read_group_id(record.qname()));
+        }
+        if args.fragment_lengths {
+            metrics.fragment_length = Some(record.insert_size().unsigned_abs());
+        }
+        if args.mate_distance {
+            metrics.mate_distance = mate_distance(record);
+        }
+        if args.error_rates {
+            if let Some((mismatches, insertions, deletions)) = error_rates(record) {
+                metrics.mismatch_rate = Some(mismatches);
+                metrics.insertion_rate = Some(insertions);
+                metrics.deletion_rate = Some(deletions);
+            }
+        }
+        if args.linked_read {
+            metrics.barcode = get_string_tag(record, b"BX");
+        }
+        if args.longest_match_run {
+            metrics.longest_match_run = Some(longest_match_run(&record.cigar()));
+        }
+        if args.splicing {
+            let introns = intron_lengths(&record.cigar());
+            metrics.is_spliced = Some(!introns.is_empty());
+            metrics.num_introns = Some(introns.len() as u64);
+            metrics.total_intron_length = Some(introns.iter().sum());
+            metrics.max_intron_length = Some(introns.iter().max().copied().unwrap_or(0));
+        }
+        if args.query_coords {
+            if let Some((start, end)) = query_coords(record) {
+                metrics.query_start = Some(start);
+                metrics.query_end = Some(end);
+            }
+        }
+        if args.one_sided_clip {
+            metrics.one_sided_clip = Some(one_sided_clip(&record.cigar(), args.clip_min));
+        }
+        if args.min_window_identity {
+            metrics.min_window_identity = min_window_identity(record, args.window_size as usize);
+        }
+        if let Some(fraction) = args.core_fraction {
+            metrics.core_identity = core_identity(record, fraction);
+        }
+        if args.include_names {
+            metrics.name = Some(read_name(record.qname()));
+        }
+        // the reference coordinates of a read beyond the end of its contig can not be trusted
+        if overruns_contig(record, header) {
+            CONTIG_OVERRUN_WARNING.call_once(|| {
+                warn!(
+                    "Reads mapped beyond the end of their contig, such as {}, have no aligned length and positions",
+                    String::from_utf8_lossy(record.qname())
+                )
+            });
+            metrics.aligned_length = None;
+            metrics.ref_start = None;
+            metrics.ref_end = None;
+        }
+        if args.include_cigar && record.cigar_len() > 0 {
+            metrics.cigar = Some(record.cigar().to_string());
+        }
+        metrics
+    }
+}
+
+/// Parses the identity range as min:max, e.g. 0:100
+pub fn parse_identity_range(s: &str) -> Result<(f64, f64), String> {
+    let (min, max) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Expected min:max, got {s}"))?;
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("Invalid identity {value}"))
+    };
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(format!(
+            "Minimum identity {min} is larger than maximum {max}"
+        ));
+    }
+    Ok((min, max))
+}
+
+/// Parses a fraction between 0 and 1
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("Expected a fraction between 0 and 1, got {s}")),
+    }
+}
+
+/// The fraction of the read in the alignment, which is 0 for unaligned reads
+/// the read length includes the soft- and hard-clipped bases
+/// None without sequence
+pub fn aligned_fraction(record: &bam::Record) -> Option<f64> {
+    if record.seq_len() == 0 {
+        return None;
+    }
+    let cigar = record.cigar();
+    let (left, right) = clip_lengths(&cigar);
+    let soft = (cigar.leading_softclips() + cigar.trailing_softclips()) as u64;
+    let aligned = if record.is_unmapped() {
+        0
+    } else {
+        (record.seq_len() as u64).saturating_sub(soft)
+    };
+    let length = (record.seq_len() as u64 + left + right).saturating_sub(soft);
+    Some(aligned as f64 / length as f64)
+}
+
+/// Warns only for the first read without the NM tag required for its identity
+static MISSING_NM_WARNING: Once = Once::new();
+
+/// Warns only for the first mapped read without CIGAR
+static MISSING_CIGAR_WARNING: Once = Once::new();
+
+/// Warns only for the first read mapped beyond the end of its contig
+static CONTIG_OVERRUN_WARNING: Once = Once::new();
+
+/// Warns only for the first read name which is not valid UTF-8
+static INVALID_NAME_WARNING: Once = Once::new();
+
+/// The fixed-seed hash of the read name, to link the primary, supplementary and secondary alignments of a read
+/// which is the same in every run, so that the ids of separate outputs can be joined
+fn read_group_id(qname: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    qname.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The read name, with invalid UTF-8 replaced by the replacement character
+fn read_name(qname: &[u8]) -> String {
+    match std::str::from_utf8(qname) {
+        Ok(name) => name.to_string(),
+        Err(_) => {
+            let name = String::from_utf8_lossy(qname).to_string();
+            INVALID_NAME_WARNING.call_once(|| {
+                warn!("Read name {name} is not valid UTF-8, invalid bytes are replaced by \u{FFFD}")
+            });
+            name
+        }
+    }
+}
+
+/// Calculates the gap-compressed identity
+/// based on https://lh3.github.io/2018/11/25/on-the-definition-of-sequence-identity
+/// recent minimap2 version have that as the de tag
+/// if that is not present, or prefer_computed is set, it is calculated from CIGAR and NM
+/// with clip_penalty soft-clipped bases are counted as mismatches, which requires the CIGAR
+/// with ignore_homopolymer indels in homopolymers are not counted as errors, nor as aligned columns
+/// with the cigar-eqx source the mismatches are the X CIGAR operations instead, ignoring the tags
+/// None if the identity has to be calculated, but the NM tag is missing or there are no aligned bases
+fn gap_compressed_identity(
+    record: &bam::Record,
+    source: IdentitySource,
+    prefer_computed: bool,
+    clip_penalty: bool,
+    ignore_homopolymer: bool,
+) -> Option<f64> {
+    let de = if source == IdentitySource::CigarEqx
+        || prefer_computed
+        || clip_penalty
+        || ignore_homopolymer
+    {
+        None
+    } else {
+        get_de_tag(record)
+    };
+    match de {
+        Some(v) => Some(v as f64),
+        None => {
+            let mut matches = 0;
+            let mut gap_size = 0;
+            let mut gap_count = 0;
+            let mut clipped = 0;
+            let mut diffs = 0;
+            for entry in record.cigar().iter() {
+                match entry {
+                    Cigar::Match(_) if source == IdentitySource::CigarEqx => {
+                        panic!(
+                            "Read {} has M CIGAR operations, while --identity-source cigar-eqx requires =/X operations",
+                            String::from_utf8_lossy(record.qname())
+                        );
+                    }
+                    Cigar::Diff(len) => {
+                        matches += *len;
+                        diffs += *len;
+                    }
+                    Cigar::Match(len) | Cigar::Equal(len) => {
+                        matches += *len;
+                    }
+                    Cigar::Del(len) | Cigar::Ins(len) => {
+                        gap_size += *len;
+                        gap_count += 1;
+                    }
+                    Cigar::SoftClip(len) if clip_penalty => {
+                        clipped += *len;
+                    }
+                    _ => (),
+                }
+            }
+            let mismatches = match source {
+                IdentitySource::CigarEqx => diffs,
+                // the bases of all gaps are in the NM tag, but only the gaps which are errors are counted
+                // an NM tag smaller than the gap bases, as written by some aligners, has no mismatches
+                IdentitySource::Tags => get_nm_tag(record)?.saturating_sub(gap_size),
+            };
+            if ignore_homopolymer {
+                gap_count -= homopolymer_indels(record);
+            }
+            let columns = matches + gap_count + clipped;
+            if columns == 0 {
+                return None;
+            }
+            Some(1.0 - ((mismatches + gap_count + clipped) as f64 / columns as f64))
+        }
+    }
+}
+
+/// Whether every alignment column (aligned base, insertion or deletion) is a match
+/// each insertion and deletion is a single column as in the gap-compressed identity
+/// mismatches are known from =/X CIGAR operations, or from the MD tag for M operations
+/// None if the CIGAR has M operations and there is no MD tag
+fn alignment_columns(record: &bam::Record) -> Option<Vec<bool>> {
+    let mut md = match record.aux(b"MD") {
+        Ok(Aux::String(md)) => Some(parse_md(md).into_iter()),
+        _ => None,
+    };
+    let mut columns = vec![];
+    for entry in record.cigar().iter() {
+        match entry {
+            Cigar::Match(len) => {
+                let md = md.as_mut()?;
+                for _ in 0..*len {
+                    columns.push(md.next() == Some(MdBase::Match));
+                }
+            }
+            Cigar::Equal(len) => columns.extend(std::iter::repeat_n(true, *len as usize)),
+            Cigar::Diff(len) => columns.extend(std::iter::repeat_n(false, *len as usize)),
+            Cigar::Ins(_) => columns.push(false),
+            // a deletion of 0 bases is not a gap
+            Cigar::Del(len) if *len > 0 => {
+                if let Some(md) = md.as_mut() {
+                    md.nth(*len as usize - 1);
+                }
+                columns.push(false)
+            }
+            _ => (),
+        }
+    }
+    Some(columns)
+}
+
+/// The lowest percentage of matches in a window of alignment columns sliding along the alignment
+/// alignments shorter than the window are a single window
+fn min_window_identity(record: &bam::Record, window: usize) -> Option<f64> {
+    let columns = alignment_columns(record)?;
+    if columns.is_empty() {
+        return None;
+    }
+    let window = window.min(columns.len());
+    let mut matches = columns[..window].iter().filter(|m| **m).count();
+    let mut min_matches = matches;
+    for i in window..columns.len() {
+        matches = matches + columns[i] as usize - columns[i - window] as usize;
+        min_matches = min_matches.min(matches);
+    }
+    Some(min_matches as f64 / window as f64 * 100.0)
+}
+
+/// The percentage of matches in the central fraction of the alignment columns
+/// trimming an equal number of columns at both ends, None if no columns are left
+fn core_identity(record: &bam::Record, fraction: f64) -> Option<f64> {
+    let columns = alignment_columns(record)?;
+    let trimmed = (columns.len() as f64 * (1.0 - fraction) / 2.0) as usize;
+    let core = &columns[trimmed..columns.len() - trimmed];
+    if core.is_empty() {
+        return None;
+    }
+    Some(core.iter().filter(|m| **m).count() as f64 / core.len() as f64 * 100.0)
+}
+
+/// The number of insertions and deletions of a single repeated base
+/// adjacent to the same base in the read, e.g. an A inserted or deleted next to AAAA
+/// deleted bases are only known from the MD tag, without it only insertions are counted
+fn homopolymer_indels(record: &bam::Record) -> u32 {
+    let seq = record.seq().as_bytes();
+    if seq.is_empty() {
+        return 0;
+    }
+    let mut deleted = match record.aux(b"MD") {
+        Ok(Aux::String(md)) => parse_md(md)
+            .into_iter()
+            .filter_map(|base| match base {
+                MdBase::Deleted(base) => Some(base.to_ascii_uppercase()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+    .into_iter();
+    // whether the indel bases are one repeated base, which is also before or after them in the read
+    let is_homopolymer = |bases: &[u8], before: usize, after: usize| {
+        bases.iter().all(|base| *base == bases[0])
+            && (before > 0 && seq[before - 1] == bases[0] || seq.get(after) == Some(&bases[0]))
+    };
+    let mut query_pos = 0;
+    let mut count = 0;
+    for entry in record.cigar().iter() {
+        match entry {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) | Cigar::SoftClip(len) => {
+                query_pos += *len as usize
+            }
+            Cigar::Ins(len) => {
+                let end = query_pos + *len as usize;
+                count += is_homopolymer(&seq[query_pos..end], query_pos, end) as u32;
+                query_pos = end;
+            }
+            Cigar::Del(len) => {
+                let bases: Vec<u8> = deleted.by_ref().take(*len as usize).collect();
+                if bases.len() == *len as usize {
+                    count += is_homopolymer(&bases, query_pos, query_pos) as u32;
+                }
+            }
+            _ => (),
+        }
+    }
+    count
+}
+
+/// Calculates the fraction of G and C bases in the (part of the) read sequence
+fn gc_content(seq: &[u8]) -> f64 {
+    let gc = seq
+        .iter()
+        .filter(|base| matches!(base, b'G' | b'C'))
+        .count();
+    gc as f64 / seq.len() as f64
+}
+
+/// Counts the A, C, G, T and other (N or ambiguous) bases of the read sequence
+fn base_counts(record: &bam::Record) -> [u64; 5] {
+    let seq = record.seq();
+    let mut counts = [0; 5];
+    for i in 0..seq.len() {
+        let index = match seq[i] {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => 4,
+        };
+        counts[index] += 1;
+    }
+    counts
+}
+
+/// Distance of the alignment to the start and end of the contig
+/// an alignment extending over the end of the contig (e.g. on circular contigs) has distance 0
+fn edge_distances(reference_start: u64, reference_end: u64, contig_length: u64) -> (u64, u64) {
+    (
+        reference_start.min(contig_length),
+        contig_length.saturating_sub(reference_end),
+    )
+}
+
+/// The error probability of every Phred score
+fn error_probabilities() -> &'static [f64; 256] {
+    static PROBABILITIES: OnceLock<[f64; 256]> = OnceLock::new();
+    PROBABILITIES.get_or_init(|| std::array::from_fn(|q| 10f64.powf(-(q as f64) / 10.0)))
+}
+
+/// Calculates the mean quality score, as the mean Phred score or the Phred score of the mean error probability
+/// returns None if the quality is absent, which htslib stores as 0xff
+fn mean_quality(qual: &[u8], method: QualityMethod) -> Option<f64> {
+    if qual.is_empty() || qual[0] == 255 {
+        return None;
+    }
+    match method {
+        QualityMethod::MeanPhred => {
+            Some(qual.iter().map(|q| *q as u64).sum::<u64>() as f64 / qual.len() as f64)
+        }
+        QualityMethod::MeanError => {
+            let probabilities = error_probabilities();
+            let error =
+                qual.iter().map(|q| probabilities[*q as usize]).sum::<f64>() / qual.len() as f64;
+            Some(-10.0 * error.log10())
+        }
+    }
+}
+
+/// The minimum and maximum base quality, in one pass over the qualities
+/// returns None if the quality is absent, like mean_quality
+fn quality_range(qual: &[u8]) -> Option<(u8, u8)> {
+    if qual.is_empty() || qual[0] == 255 {
+        return None;
+    }
+    Some(qual.iter().fold((u8::MAX, u8::MIN), |(min, max), q| {
+        (min.min(*q), max.max(*q))
+    }))
+}
+
+/// The base qualities of the read, None if these are inconsistent with the sequence
+fn base_qualities(record: &bam::Record) -> Option<&[u8]> {
+    checked_qualities(record.qname(), record.seq().len(), record.qual())
+}
+
+fn checked_qualities<'a>(qname: &[u8], seq_len: usize, qual: &'a [u8]) -> Option<&'a [u8]> {
+    if qual.len() == seq_len {
+        Some(qual)
+    } else {
+        warn!(
+            "Read {} has {} bases but {} quality values, ignoring its quality",
+            String::from_utf8_lossy(qname),
+            seq_len,
+            qual.len()
+        );
+        None
+    }
+}
+
+/// The bases or base qualities of the aligned part of the read, excluding soft-clipped bases
+fn aligned_part<'a>(record: &bam::Record, values: &'a [u8]) -> &'a [u8] {
+    let cigar = record.cigar();
+    let start = (cigar.leading_softclips() as usize).min(values.len());
+    let end = values
+        .len()
+        .saturating_sub(cigar.trailing_softclips() as usize)
+        .max(start);
+    &values[start..end]
+}
+
+/// The number of soft- and hard-clipped bases at the start and end of the CIGAR
+fn clip_lengths(cigar: &CigarStringView) -> (u64, u64) {
+    let clipped = |entries: &mut dyn Iterator<Item = &Cigar>| -> u64 {
+        entries
+            .map_while(|entry| match entry {
+                Cigar::SoftClip(len) | Cigar::HardClip(len) => Some(*len as u64),
+                _ => None,
+            })
+            .sum()
+    };
+    (clipped(&mut cigar.iter()), clipped(&mut cigar.iter().rev()))
+}
+
+/// The 0-based start and exclusive end of the aligned bases in the read, including hard-clipped bases
+/// counted from the 5' end of the read, so from the end of the CIGAR for reverse strand reads
+/// None for unmapped reads
+fn query_coords(record: &bam::Record) -> Option<(u64, u64)> {
+    if record.is_unmapped() || record.cigar_len() == 0 {
+        return None;
+    }
+    let cigar = record.cigar();
+    let (left, right) = clip_lengths(&cigar);
+    let (_, aligned) = cigar_consumption(&cigar);
+    if record.is_reverse() {
+        Some((right, right + aligned))
+    } else {
+        Some((left, left + aligned))
+    }
+}
+
+/// Whether exactly one end is soft-clipped by at least min_length bases, which can indicate a breakpoint
+fn one_sided_clip(cigar: &CigarStringView, min_length: u64) -> bool {
+    let left = cigar.leading_softclips() as u64 >= min_length;
+    let right = cigar.trailing_softclips() as u64 >= min_length;
+    left != right
+}
+
+/// The soft- and hard-clipped bases at both ends as a fraction of the read length
+/// which includes the hard-clipped bases, as these are not in the sequence
+/// None without sequence
+fn clipped_fraction(record: &bam::Record, (left, right): (u64, u64)) -> Option<f64> {
+    if record.seq_len() == 0 {
+        return None;
+    }
+    let cigar = record.cigar();
+    let soft = (cigar.leading_softclips() + cigar.trailing_softclips()) as u64;
+    let length = record.seq_len() as u64 + left + right - soft;
+    Some((left + right) as f64 / length as f64)
+}
+
+/// Whether the alignment ends beyond the length of its contig in the header
+pub fn overruns_contig(record: &bam::Record, header: &HeaderView) -> bool {
+    !record.is_unmapped()
+        && record.tid() >= 0
+        && header
+            .target_len(record.tid() as u32)
+            .is_some_and(|length| record.reference_end() as u64 > length)
+}
+
+/// Whether the read is the primary alignment of a properly paired read, which has a fragment length
+pub fn is_proper_pair(record: &bam::Record) -> bool {
+    record.is_proper_pair()
+        && !(record.is_unmapped()
+            || record.is_mate_unmapped()
+            || record.is_secondary()
+            || record.is_supplementary())
+}
+
+/// The distance from the start of the read to the start of its mate
+/// None for unpaired reads, unmapped mates and mates on another contig
+fn mate_distance(record: &bam::Record) -> Option<i64> {
+    (record.is_paired() && !record.is_mate_unmapped() && record.mtid() == record.tid())
+        .then(|| record.mpos() - record.pos())
+}
+
+/// The number of mismatched bases, insertions and deletions per 1000 aligned reference bases
+/// mismatches are the NM tag minus the inserted and deleted bases, indels are counted as events
+/// None without aligned bases
+fn error_rates(record: &bam::Record) -> Option<(f64, f64, f64)> {
+    let aligned_length = record.reference_end() - record.reference_start();
+    if record.cigar_len() == 0 || aligned_length <= 0 {
+        return None;
+    }
+    let mut gap_size = 0;
+    let mut insertions = 0;
+    let mut deletions = 0;
+    for entry in record.cigar().iter() {
+        match entry {
+            Cigar::Ins(len) => {
+                gap_size += *len;
+                insertions += 1;
+            }
+            Cigar::Del(len) => {
+                gap_size += *len;
+                deletions += 1;
+            }
+            _ => (),
+        }
+    }
+    let mismatches = get_nm_tag(record)?.saturating_sub(gap_size);
+    let per_kb = |count: u32| count as f64 * 1000.0 / aligned_length as f64;
+    Some((per_kb(mismatches), per_kb(insertions), per_kb(deletions)))
+}
+
+/// The longest run of matching bases, which are consecutive = operations in the CIGAR
+/// M operations can also contain mismatches, so for CIGARs without = this is an upper bound
+fn longest_match_run(cigar: &CigarStringView) -> u64 {
+    let mut longest = 0;
+    let mut run = 0;
+    for entry in cigar.iter() {
+        match entry {
+            Cigar::Equal(len) | Cigar::Match(len) => run += *len as u64,
+            _ => run = 0,
+        }
+        longest = longest.max(run);
+    }
+    longest
+}
+
+/// The number of reference- and query-consuming bases in the CIGAR, excluding soft clips
+fn cigar_consumption(cigar: &CigarStringView) -> (u64, u64) {
+    cigar
+        .iter()
+        .fold((0, 0), |(reference, query), entry| match entry {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                (reference + *len as u64, query + *len as u64)
+            }
+            Cigar::Del(len) | Cigar::RefSkip(len) => (reference + *len as u64, query),
+            Cigar::Ins(len) => (reference, query + *len as u64),
+            _ => (reference, query),
+        })
+}
+
+/// The lengths of the introns, the reference skips (N) of spliced alignments
+fn intron_lengths(cigar: &CigarStringView) -> Vec<u64> {
+    cigar
+        .iter()
+        .filter_map(|entry| match entry {
+            Cigar::RefSkip(len) => Some(*len as u64),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Reverse strand reads are aligned reverse complemented, so their 5' end is on the right
+fn oriented_clips(clips: (u64, u64), reverse: bool, orientation: ClipOrientation) -> (u64, u64) {
+    match orientation {
+        ClipOrientation::Read if reverse => (clips.1, clips.0),
+        _ => clips,
+    }
+}
+
+fn get_nm_tag(record: &bam::Record) -> Option<u32> {
+    match record.aux(b"NM") {
+        Ok(value) => match value {
+            Aux::U8(v) => Some(u32::from(v)),
+            Aux::U16(v) => Some(u32::from(v)),
+            Aux::U32(v) => Some(v),
+            Aux::I32(v) => Some(u32::try_from(v).expect("Identified a negative NM tag")),
+            _ => panic!("Unexpected type of Aux for NM tag: {:?}", value),
+        },
+        Err(_e) => None,
+    }
+}
+
+/// Get an integer tag of any integer Aux type, None if absent or of another type
+fn get_int_tag(record: &bam::Record, tag: &[u8]) -> Option<i64> {
+    match record.aux(tag) {
+        Ok(Aux::I8(v)) => Some(v as i64),
+        Ok(Aux::U8(v)) => Some(v as i64),
+        Ok(Aux::I16(v)) => Some(v as i64),
+        Ok(Aux::U16(v)) => Some(v as i64),
+        Ok(Aux::I32(v)) => Some(v as i64),
+        Ok(Aux::U32(v)) => Some(v as i64),
+        _ => None,
+    }
+}
+
+fn get_float_tag(record: &bam::Record, tag: &[u8]) -> Option<f64> {
+    match record.aux(tag) {
+        Ok(Aux::Float(v)) => Some(v as f64),
+        Ok(Aux::Double(v)) => Some(v),
+        _ => None,
+    }
+}
+
+fn get_string_tag(record: &bam::Record, tag: &[u8]) -> Option<String> {
+    match record.aux(tag) {
+        Ok(Aux::String(v)) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Get the de:f tag from minimap2, which is the gap compressed sequence divergence
+/// Which is converted into identity with (1.0 - de)
+/// This tag can be absent if the aligner version is not quite recent
+fn get_de_tag(record: &bam::Record) -> Option<f32> {
+    match record.aux(b"de") {
+        Ok(value) => match value {
+            Aux::Float(v) => Some(1.0 - v),
+            _ => panic!("Unexpected type of Aux for de tag: {:?}", value),
+        },
+        Err(_e) => None,
+    }
+}
+
+#[test]
+fn test_edge_distances() {
+    // a read close to the end of a 1000bp contig
+    assert_eq!(edge_distances(100, 990, 1000), (100, 10));
+    // a read starting at the first base of the contig
+    assert_eq!(edge_distances(0, 500, 1000), (0, 500));
+    // a read extending over the end of the contig
+    assert_eq!(edge_distances(900, 1050, 1000), (900, 0));
+}
+
+#[cfg(test)]
+fn clipped_record() -> bam::Record {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"clipped",
+        Some(&CigarString(vec![
+            Cigar::SoftClip(2),
+            Cigar::Match(4),
+            Cigar::SoftClip(2),
+        ])),
+        b"ACGTACGT",
+        &[2, 2, 30, 30, 30, 30, 2, 2],
+    );
+    record
+}
+
+#[test]
+fn test_aligned_quality() {
+    let record = clipped_record();
+    assert_eq!(
+        mean_quality(record.qual(), QualityMethod::MeanPhred),
+        Some(16.0)
+    );
+    assert_eq!(
+        mean_quality(
+            aligned_part(&record, record.qual()),
+            QualityMethod::MeanPhred
+        ),
+        Some(30.0)
+    );
+}
+
+#[test]
+fn test_gc_aligned() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    // GC-rich adapters around an AT-rich aligned part
+    record.set(
+        b"adapters",
+        Some(&CigarString(vec![
+            Cigar::SoftClip(4),
+            Cigar::Match(4),
+            Cigar::SoftClip(2),
+        ])),
+        b"GCGCATTACC",
+        &[30; 10],
+    );
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--gc-aligned"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.gc_content, Some(0.6));
+    assert_eq!(metrics.aligned_gc_content, Some(0.0));
+}
+
+#[test]
+fn test_aligned_fraction() {
+    use rust_htslib::bam::record::CigarString;
+    // 4 of the 8 bases are soft-clipped
+    assert_eq!(aligned_fraction(&clipped_record()), Some(0.5));
+    let mut record = bam::Record::new();
+    record.set(
+        b"hard_clipped",
+        Some(&CigarString(vec![Cigar::HardClip(30), Cigar::Match(10)])),
+        &[b'A'; 10],
+        &[30; 10],
+    );
+    assert_eq!(aligned_fraction(&record), Some(0.25));
+    assert!(parse_fraction("1.5").is_err());
+}
+
+#[test]
+fn test_inconsistent_quality_length() {
+    let record = clipped_record();
+    assert_eq!(base_qualities(&record), Some(record.qual()));
+    assert_eq!(checked_qualities(b"truncated", 8, &[30, 30, 30]), None);
+}
+
+#[test]
+fn test_quality_range() {
+    assert_eq!(quality_range(clipped_record().qual()), Some((2, 30)));
+    assert_eq!(quality_range(&[17]), Some((17, 17)));
+    assert_eq!(quality_range(&[255, 255, 255]), None);
+}
+
+#[test]
+fn test_missing_quality() {
+    assert_eq!(
+        mean_quality(&[255, 255, 255], QualityMethod::MeanPhred),
+        None
+    );
+    assert_eq!(
+        mean_quality(&[255, 255, 255], QualityMethod::MeanError),
+        None
+    );
+}
+
+#[test]
+fn test_prefer_computed_identity() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"de_tagged",
+        Some(&CigarString(vec![Cigar::Match(10)])),
+        b"ACGTACGTAC",
+        &[30; 10],
+    );
+    record.push_aux(b"NM", Aux::U8(1)).unwrap();
+    record.push_aux(b"de", Aux::Float(0.05)).unwrap();
+    assert!(
+        (gap_compressed_identity(&record, IdentitySource::Tags, false, false, false).unwrap()
+            - 0.95)
+            .abs()
+            < 1e-6
+    );
+    assert!(
+        (gap_compressed_identity(&record, IdentitySource::Tags, true, false, false).unwrap() - 0.9)
+            .abs()
+            < 1e-9
+    );
+}
+
+#[test]
+fn test_nm_smaller_than_gaps() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"inconsistent_nm",
+        Some(&CigarString(vec![
+            Cigar::Match(50),
+            Cigar::Del(5),
+            Cigar::Match(48),
+        ])),
+        &[b'A'; 98],
+        &[30; 98],
+    );
+    // the NM tag of 2 is smaller than the 5 deleted bases
+    record.push_aux(b"NM", Aux::U8(2)).unwrap();
+    // only the single gap is an error in 98 matches and 1 gap
+    assert!(
+        (gap_compressed_identity(&record, IdentitySource::Tags, true, false, false).unwrap()
+            - (1.0 - 1.0 / 99.0))
+            .abs()
+            < 1e-9
+    );
+}
+
+#[cfg(test)]
+pub fn test_header() -> HeaderView {
+    use rust_htslib::bam::header::{Header, HeaderRecord};
+    let mut header = Header::new();
+    header.push_record(
+        HeaderRecord::new(b"SQ")
+            .push_tag(b"SN", "chr1")
+            .push_tag(b"LN", 1000),
+    );
+    HeaderView::from_header(&header)
+}
+
+#[test]
+fn test_empty_cigar() {
+    use clap::Parser;
+    let mut record = bam::Record::new();
+    record.set(b"no_cigar", None, b"ACGTACGTAC", &[30; 10]);
+    record.set_tid(0);
+    record.set_pos(100);
+    record.push_aux(b"NM", Aux::U8(1)).unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.identity, None);
+    assert_eq!(metrics.aligned_length, None);
+    assert_eq!(metrics.length, 10);
+}
+
+#[test]
+fn test_nanopore_tags() {
+    use clap::Parser;
+    let mut record = clipped_record_with_nm();
+    record.push_aux(b"ch", Aux::U16(1234)).unwrap();
+    record
+        .push_aux(b"st", Aux::String("2023-08-21T10:00:00.000+00:00"))
+        .unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--nanopore-tags"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.channel, Some(1234));
+    assert_eq!(
+        metrics.start_time.as_deref(),
+        Some("2023-08-21T10:00:00.000+00:00")
+    );
+    let untagged = ReadMetrics::from_record(&clipped_record_with_nm(), &args, &test_header());
+    assert_eq!(untagged.channel, None);
+    assert_eq!(untagged.start_time, None);
+}
+
+#[cfg(test)]
+fn clipped_record_with_nm() -> bam::Record {
+    let mut record = clipped_record();
+    record.push_aux(b"NM", Aux::U8(0)).unwrap();
+    record
+}
+
+#[test]
+fn test_hifi_tags() {
+    use clap::Parser;
+    let mut record = clipped_record_with_nm();
+    record.push_aux(b"np", Aux::I32(12)).unwrap();
+    record.push_aux(b"rq", Aux::Float(0.999)).unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--hifi-tags"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.num_passes, Some(12));
+    assert!((metrics.predicted_accuracy.unwrap() - 0.999).abs() < 1e-6);
+    let untagged = ReadMetrics::from_record(&clipped_record_with_nm(), &args, &test_header());
+    assert_eq!(untagged.num_passes, None);
+    assert_eq!(untagged.predicted_accuracy, None);
+}
+
+#[test]
+fn test_clip_orientation() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"reverse",
+        Some(&CigarString(vec![
+            Cigar::HardClip(5),
+            Cigar::SoftClip(3),
+            Cigar::Match(4),
+            Cigar::SoftClip(1),
+        ])),
+        b"ACGTACGT",
+        &[30; 8],
+    );
+    record.push_aux(b"NM", Aux::U8(0)).unwrap();
+    record.set_reverse();
+    let reference = Cli::parse_from(["make_arrow", "input.bam", "--clips"]);
+    let metrics = ReadMetrics::from_record(&record, &reference, &test_header());
+    assert_eq!(metrics.clips, Some((8, 1)));
+    let read = Cli::parse_from([
+        "make_arrow",
+        "input.bam",
+        "--clips",
+        "--clip-orientation",
+        "read",
+    ]);
+    let metrics = ReadMetrics::from_record(&record, &read, &test_header());
+    assert_eq!(metrics.clips, Some((1, 8)));
+    record.unset_reverse();
+    let metrics = ReadMetrics::from_record(&record, &read, &test_header());
+    assert_eq!(metrics.clips, Some((8, 1)));
+}
+
+#[test]
+fn test_clipped_fraction() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--clips"]);
+    // 2 soft-clipped bases at both ends of 8 bases
+    let metrics = ReadMetrics::from_record(&clipped_record(), &args, &test_header());
+    assert_eq!(metrics.clipped_fraction, Some(0.5));
+    // the 5 hard-clipped bases are part of the read length
+    let mut record = bam::Record::new();
+    record.set(
+        b"hard_clipped",
+        Some(&CigarString(vec![
+            Cigar::HardClip(5),
+            Cigar::SoftClip(3),
+            Cigar::Match(4),
+            Cigar::SoftClip(1),
+        ])),
+        b"ACGTACGT",
+        &[30; 8],
+    );
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.clipped_fraction, Some(9.0 / 13.0));
+}
+
+#[test]
+fn test_clip_penalty() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"heavily_clipped",
+        Some(&CigarString(vec![
+            Cigar::SoftClip(30),
+            Cigar::Match(60),
+            Cigar::Ins(2),
+            Cigar::Match(38),
+            Cigar::SoftClip(20),
+            Cigar::HardClip(100),
+        ])),
+        &[b'A'; 150],
+        &[30; 150],
+    );
+    // one mismatch and an insertion of 2 bases
+    record.push_aux(b"NM", Aux::U8(3)).unwrap();
+    record.push_aux(b"de", Aux::Float(0.0202)).unwrap();
+    // 2 differences (mismatch and gap) in 98 matches and 1 gap
+    assert!(
+        (gap_compressed_identity(&record, IdentitySource::Tags, true, false, false).unwrap()
+            - (1.0 - 2.0 / 99.0))
+            .abs()
+            < 1e-9
+    );
+    // the 50 soft-clipped bases are added as mismatches, hard clips are ignored
+    assert!(
+        (gap_compressed_identity(&record, IdentitySource::Tags, false, true, false).unwrap()
+            - (1.0 - 52.0 / 149.0))
+            .abs()
+            < 1e-9
+    );
+}
+
+#[test]
+fn test_identity_ignore_homopolymer() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    // an A inserted after AAAA, a C inserted between A and G and a T deleted before TTT
+    record.set(
+        b"homopolymer",
+        Some(&CigarString(vec![
+            Cigar::Match(6),
+            Cigar::Ins(1),
+            Cigar::Match(4),
+            Cigar::Ins(1),
+            Cigar::Match(4),
+            Cigar::Del(1),
+            Cigar::Match(5),
+        ])),
+        b"CGAAAAAGTCACGTCATTTGC",
+        &[30; 21],
+    );
+    record.push_aux(b"NM", Aux::U8(3)).unwrap();
+    record.push_aux(b"MD", Aux::String("14^T5")).unwrap();
+    // 3 gaps in 19 matches
+    let standard =
+        gap_compressed_identity(&record, IdentitySource::Tags, true, false, false).unwrap();
+    assert!((standard - (1.0 - 3.0 / 22.0)).abs() < 1e-9);
+    // only the C insertion is counted
+    let adjusted =
+        gap_compressed_identity(&record, IdentitySource::Tags, false, false, true).unwrap();
+    assert!((adjusted - (1.0 - 1.0 / 20.0)).abs() < 1e-9);
+    assert!(adjusted > standard);
+}
+
+#[test]
+fn test_mate_distance() {
+    let mut record = clipped_record_with_nm();
+    record.set_tid(0);
+    record.set_pos(1000);
+    assert_eq!(mate_distance(&record), None);
+    record.set_paired();
+    record.set_mtid(0);
+    record.set_mpos(1350);
+    assert_eq!(mate_distance(&record), Some(350));
+    record.set_mpos(600);
+    assert_eq!(mate_distance(&record), Some(-400));
+    record.set_mtid(1);
+    assert_eq!(mate_distance(&record), None);
+    record.set_mtid(0);
+    record.set_mate_unmapped();
+    assert_eq!(mate_distance(&record), None);
+}
+
+#[test]
+fn test_error_rates() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"errors",
+        Some(&CigarString(vec![
+            Cigar::SoftClip(10),
+            Cigar::Match(100),
+            Cigar::Ins(3),
+            Cigar::Match(200),
+            Cigar::Del(5),
+            Cigar::Match(100),
+            Cigar::Del(1),
+            Cigar::Match(95),
+        ])),
+        &[b'A'; 508],
+        &[30; 508],
+    );
+    // 4 mismatches, 3 inserted and 6 deleted bases
+    record.push_aux(b"NM", Aux::U8(13)).unwrap();
+    // 495 matches and 6 deleted bases span 501 reference bases
+    let (mismatches, insertions, deletions) = error_rates(&record).unwrap();
+    assert!((mismatches - 4000.0 / 501.0).abs() < 1e-9);
+    assert!((insertions - 1000.0 / 501.0).abs() < 1e-9);
+    assert!((deletions - 2000.0 / 501.0).abs() < 1e-9);
+    let mut unaligned = bam::Record::new();
+    unaligned.set(b"no_cigar", None, b"ACGT", &[30; 4]);
+    assert_eq!(error_rates(&unaligned), None);
+}
+
+#[test]
+fn test_linked_read_barcode() {
+    use clap::Parser;
+    let mut record = clipped_record_with_nm();
+    record
+        .push_aux(b"BX", Aux::String("ACGTACGTACGTACGT-1"))
+        .unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--linked-read"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.barcode.as_deref(), Some("ACGTACGTACGTACGT-1"));
+    let untagged = ReadMetrics::from_record(&clipped_record_with_nm(), &args, &test_header());
+    assert_eq!(untagged.barcode, None);
+}
+
+#[test]
+fn test_longest_match_run() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    let cigar = CigarString(vec![
+        Cigar::SoftClip(50),
+        Cigar::Equal(10),
+        Cigar::Diff(1),
+        Cigar::Equal(25),
+        Cigar::Equal(5),
+        Cigar::Ins(2),
+        Cigar::Equal(20),
+        Cigar::Del(1),
+        Cigar::Equal(12),
+    ]);
+    record.set(b"eqx", Some(&cigar), &[b'A'; 125], &[30; 125]);
+    assert_eq!(longest_match_run(&record.cigar()), 30);
+    let cigar = CigarString(vec![Cigar::Match(40), Cigar::Del(3), Cigar::Match(60)]);
+    record.set(b"match", Some(&cigar), &[b'A'; 100], &[30; 100]);
+    assert_eq!(longest_match_run(&record.cigar()), 60);
+}
+
+#[test]
+fn test_edit_distance() {
+    use clap::Parser;
+    let mut record = clipped_record();
+    record.push_aux(b"NM", Aux::U16(7)).unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--edit-distance"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.edit_distance, Some(7));
+    // the identity is from the de tag, unless it is calculated from the NM tag anyway
+    record.push_aux(b"de", Aux::Float(0.01)).unwrap();
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.edit_distance, None);
+    let args = Cli::parse_from([
+        "make_arrow",
+        "input.bam",
+        "--edit-distance",
+        "--prefer-computed-identity",
+    ]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.edit_distance, Some(7));
+}
+
+#[test]
+fn test_splicing() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let mut record = clipped_record_with_nm();
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--splicing"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.is_spliced, Some(false));
+    assert_eq!(metrics.num_introns, Some(0));
+    assert_eq!(metrics.max_intron_length, Some(0));
+    let cigar = CigarString(vec![
+        Cigar::Match(50),
+        Cigar::RefSkip(1200),
+        Cigar::Match(30),
+        Cigar::RefSkip(350),
+        Cigar::Match(20),
+    ]);
+    record.set(b"spliced", Some(&cigar), &[b'A'; 100], &[30; 100]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.is_spliced, Some(true));
+    assert_eq!(metrics.num_introns, Some(2));
+    assert_eq!(metrics.total_intron_length, Some(1550));
+    assert_eq!(metrics.max_intron_length, Some(1200));
+}
+
+#[test]
+fn test_invalid_utf8_name() {
+    use clap::Parser;
+    let mut record = clipped_record_with_nm();
+    record.set_qname(b"read\xff1");
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--include-names"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.name.as_deref(), Some("read\u{FFFD}1"));
+    assert_eq!(read_name(b"valid_read"), "valid_read");
+}
+
+#[test]
+fn test_identity_clamp() {
+    use clap::Parser;
+    let mut record = clipped_record_with_nm();
+    // a de tag below 0 leads to an identity above 100%
+    record.push_aux(b"de", Aux::Float(-0.05)).unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.identity, Some(100.0));
+    assert!(metrics.identity_clamped);
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--identity-range", "0:110"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert!(!metrics.identity_clamped);
+    assert!((metrics.identity.unwrap() - 105.0).abs() < 1e-4);
+    assert!(parse_identity_range("100:0").is_err());
+    assert!(parse_identity_range("90").is_err());
+}
+
+#[test]
+fn test_base_counts() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"composition",
+        Some(&CigarString(vec![Cigar::Match(12)])),
+        b"AACGGGTTTTNR",
+        &[30; 12],
+    );
+    let counts = base_counts(&record);
+    assert_eq!(counts, [2, 1, 3, 4, 2]);
+    assert_eq!(counts.iter().sum::<u64>(), record.seq_len() as u64);
+}
+
+#[test]
+fn test_min_window_identity() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"error_cluster",
+        Some(&CigarString(vec![
+            Cigar::Match(120),
+            Cigar::Del(2),
+            Cigar::Match(80),
+        ])),
+        &[b'A'; 200],
+        &[30; 200],
+    );
+    // 4 mismatches in 12 bases, followed by a deletion of 2 bases
+    record.push_aux(b"NM", Aux::U8(6)).unwrap();
+    record
+        .push_aux(b"MD", Aux::String("107C3C3C3C0^GG80"))
+        .unwrap();
+    let args = Cli::parse_from([
+        "make_arrow",
+        "input.bam",
+        "--min-window-identity",
+        "--window-size",
+        "20",
+    ]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    // 4 mismatches and the deletion in the window of 20 columns ending at the deletion
+    assert_eq!(metrics.min_window_identity, Some(75.0));
+    assert!(metrics.min_window_identity.unwrap() < metrics.identity.unwrap());
+    // a window of the whole alignment is the gap-compressed identity
+    assert_eq!(
+        min_window_identity(&record, 1000),
+        Some(196.0 / 201.0 * 100.0)
+    );
+    // without MD tag the mismatches of M operations are unknown
+    let mut untagged = bam::Record::new();
+    untagged.set(
+        b"untagged",
+        Some(&CigarString(vec![Cigar::Match(10)])),
+        &[b'A'; 10],
+        &[30; 10],
+    );
+    assert_eq!(min_window_identity(&untagged, 20), None);
+    // a deletion of 0 bases does not add a column
+    let mut empty_deletion = bam::Record::new();
+    empty_deletion.set(
+        b"empty_deletion",
+        Some(&CigarString(vec![
+            Cigar::Match(10),
+            Cigar::Del(0),
+            Cigar::Match(10),
+        ])),
+        &[b'A'; 20],
+        &[30; 20],
+    );
+    empty_deletion.push_aux(b"MD", Aux::String("9C10")).unwrap();
+    assert_eq!(min_window_identity(&empty_deletion, 20), Some(95.0));
+}
+
+#[test]
+fn test_core_identity() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"noisy_ends",
+        Some(&CigarString(vec![Cigar::Match(200)])),
+        &[b'A'; 200],
+        &[30; 200],
+    );
+    // 4 mismatches at each end of the alignment
+    record.push_aux(b"NM", Aux::U8(8)).unwrap();
+    record
+        .push_aux(b"MD", Aux::String("0C0C0C0C192C0C0C0C0"))
+        .unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--core-fraction", "0.9"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    // the 10 columns trimmed at both ends include all mismatches
+    assert_eq!(metrics.core_identity, Some(100.0));
+    assert!(metrics.core_identity.unwrap() > metrics.identity.unwrap());
+    assert_eq!(core_identity(&record, 1.0), Some(96.0));
+    assert_eq!(core_identity(&record, 0.0), None);
+    let mut untagged = bam::Record::new();
+    untagged.set(
+        b"untagged",
+        Some(&CigarString(vec![Cigar::Match(10)])),
+        &[b'A'; 10],
+        &[30; 10],
+    );
+    assert_eq!(core_identity(&untagged, 0.5), None);
+}
+
+#[test]
+fn test_core_identity_indels() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"indels",
+        Some(&CigarString(vec![
+            Cigar::Match(50),
+            Cigar::Ins(3),
+            Cigar::Match(50),
+            Cigar::Del(4),
+            Cigar::Match(50),
+        ])),
+        &[b'A'; 153],
+        &[30; 153],
+    );
+    // 2 mismatches, 3 inserted and 4 deleted bases
+    record.push_aux(b"NM", Aux::U8(9)).unwrap();
+    record
+        .push_aux(b"MD", Aux::String("10C89^ACGT30G19"))
+        .unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--core-fraction", "1"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    // the whole alignment of 150 aligned bases and 2 gaps has the gap-compressed identity
+    assert!((metrics.core_identity.unwrap() - 148.0 / 152.0 * 100.0).abs() < 1e-9);
+    assert!((metrics.core_identity.unwrap() - metrics.identity.unwrap()).abs() < 1e-9);
+}
+
+#[test]
+fn test_missing_sequence() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    record.set(
+        b"no_sequence",
+        Some(&CigarString(vec![Cigar::Match(10)])),
+        b"",
+        &[],
+    );
+    record.set_tid(0);
+    record.set_pos(100);
+    record.push_aux(b"NM", Aux::U8(1)).unwrap();
+    let args = Cli::parse_from([
+        "make_arrow",
+        "input.bam",
+        "--gc-aligned",
+        "--base-composition",
+        "--quality",
+        "--quality-aligned",
+        "--quality-range",
+        "--identity-ignore-homopolymer",
+    ]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.gc_content, None);
+    assert_eq!(metrics.aligned_gc_content, None);
+    assert_eq!(metrics.base_counts, None);
+    assert_eq!(metrics.quality, None);
+    assert_eq!(metrics.aligned_quality, None);
+    assert_eq!(metrics.quality_range, None);
+    assert_eq!(aligned_fraction(&record), None);
+    // the alignment metrics only depend on the CIGAR and tags
+    assert!((metrics.identity.unwrap() - 90.0).abs() < 1e-9);
+    assert_eq!(metrics.aligned_length, Some(10));
+}
+
+#[test]
+fn test_missing_nm_tag() {
+    use clap::Parser;
+    let args = Cli::parse_from(["make_arrow", "input.bam"]);
+    let metrics = ReadMetrics::from_record(&clipped_record(), &args, &test_header());
+    assert_eq!(metrics.identity, None);
+    assert!(metrics.aligned_length.is_some());
+}
+
+#[test]
+fn test_contig_overrun() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--positions", "--edge-distance"]);
+    let mut record = bam::Record::new();
+    let cigar = CigarString(vec![Cigar::Match(100)]);
+    record.set(b"overrun", Some(&cigar), &[b'A'; 100], &[30; 100]);
+    record.push_aux(b"NM", Aux::U8(0)).unwrap();
+    record.set_tid(0);
+    record.set_pos(950);
+    // the contig of the test header is 1000 bases, so the alignment ends 50 bases beyond it
+    assert!(overruns_contig(&record, &test_header()));
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.aligned_length, None);
+    assert_eq!((metrics.ref_start, metrics.ref_end), (None, None));
+    assert_eq!(metrics.dist_to_contig_end, None);
+    assert_eq!(metrics.identity, Some(100.0));
+    record.set_pos(900);
+    assert!(!overruns_contig(&record, &test_header()));
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(
+        (metrics.ref_start, metrics.ref_end),
+        (Some(900), Some(1000))
+    );
+    assert_eq!(metrics.dist_to_contig_end, Some(0));
+}
+
+#[test]
+fn test_edge_distance_unmapped() {
+    use clap::Parser;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--edge-distance"]);
+    let mut record = bam::Record::new();
+    record.set(b"unmapped", None, &[b'A'; 100], &[30; 100]);
+    record.set_unmapped();
+    record.set_tid(-1);
+    record.set_pos(-1);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(
+        (metrics.dist_to_contig_start, metrics.dist_to_contig_end),
+        (None, None)
+    );
+}
+
+#[test]
+fn test_query_coords() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--query-coords"]);
+    let mut record = bam::Record::new();
+    let cigar = CigarString(vec![
+        Cigar::HardClip(10),
+        Cigar::SoftClip(5),
+        Cigar::Match(50),
+        Cigar::Ins(3),
+        Cigar::Del(2),
+        Cigar::Match(20),
+        Cigar::SoftClip(7),
+    ]);
+    record.set(b"clipped", Some(&cigar), &[b'A'; 85], &[30; 85]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(
+        (metrics.query_start, metrics.query_end),
+        (Some(15), Some(88))
+    );
+    // the 7 soft-clipped bases at the end of the CIGAR are the 5' end of a reverse strand read
+    record.set_reverse();
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(
+        (metrics.query_start, metrics.query_end),
+        (Some(7), Some(80))
+    );
+    record.set_unmapped();
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!((metrics.query_start, metrics.query_end), (None, None));
+}
+
+#[test]
+fn test_one_sided_clip() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let args = Cli::parse_from([
+        "make_arrow",
+        "input.bam",
+        "--one-sided-clip",
+        "--clip-min",
+        "20",
+    ]);
+    let mut record = bam::Record::new();
+    let cigar = CigarString(vec![
+        Cigar::SoftClip(30),
+        Cigar::Match(60),
+        Cigar::SoftClip(5),
+    ]);
+    record.set(b"one_sided", Some(&cigar), &[b'A'; 95], &[30; 95]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.one_sided_clip, Some(true));
+    let cigar = CigarString(vec![
+        Cigar::SoftClip(30),
+        Cigar::Match(60),
+        Cigar::SoftClip(25),
+    ]);
+    record.set(b"balanced", Some(&cigar), &[b'A'; 115], &[30; 115]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.one_sided_clip, Some(false));
+    // hard clips are not counted
+    let cigar = CigarString(vec![Cigar::HardClip(30), Cigar::Match(60)]);
+    record.set(b"hard_clipped", Some(&cigar), &[b'A'; 60], &[30; 60]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.one_sided_clip, Some(false));
+}
+
+#[test]
+fn test_identity_source_cigar_eqx() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    let cigar = CigarString(vec![
+        Cigar::SoftClip(5),
+        Cigar::Equal(40),
+        Cigar::Diff(2),
+        Cigar::Equal(30),
+        Cigar::Ins(3),
+        Cigar::Equal(20),
+        Cigar::Del(4),
+        Cigar::Diff(1),
+        Cigar::Equal(10),
+    ]);
+    record.set(b"eqx", Some(&cigar), &[b'A'; 111], &[30; 111]);
+    // the 3 mismatches and 7 inserted and deleted bases
+    record.push_aux(b"NM", Aux::U8(10)).unwrap();
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--identity-source", "cigar-eqx"]);
+    let eqx = ReadMetrics::from_record(&record, &args, &test_header());
+    let args = Cli::parse_from(["make_arrow", "input.bam"]);
+    let nm = ReadMetrics::from_record(&record, &args, &test_header());
+    assert!((eqx.identity.unwrap() - nm.identity.unwrap()).abs() < 1e-9);
+    assert!((eqx.identity.unwrap() - 100.0 * (1.0 - 5.0 / 105.0)).abs() < 1e-9);
+}
+
+#[test]
+#[should_panic(expected = "requires =/X operations")]
+fn test_identity_source_cigar_eqx_with_m() {
+    use clap::Parser;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--identity-source", "cigar-eqx"]);
+    ReadMetrics::from_record(&clipped_record(), &args, &test_header());
+}
+
+#[test]
+fn test_on_missing_identity() {
+    use clap::Parser;
+    use rust_htslib::bam::record::CigarString;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--on-missing-identity", "null"]);
+    let metrics = ReadMetrics::from_record(&clipped_record(), &args, &test_header());
+    assert_eq!(metrics.identity, None);
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--on-missing-identity", "nan"]);
+    let metrics = ReadMetrics::from_record(&clipped_record(), &args, &test_header());
+    assert!(metrics.identity.unwrap().is_nan());
+    assert!(!metrics.identity_clamped);
+    // a read without aligned bases
+    let mut record = bam::Record::new();
+    let cigar = CigarString(vec![Cigar::SoftClip(8)]);
+    record.set(b"unaligned", Some(&cigar), b"ACGTACGT", &[30; 8]);
+    record.push_aux(b"NM", Aux::U8(0)).unwrap();
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert!(metrics.identity.unwrap().is_nan());
+    // without CIGAR
+    let mut record = bam::Record::new();
+    record.set(b"no_cigar", None, b"ACGTACGT", &[30; 8]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert!(metrics.identity.unwrap().is_nan());
+}
+
+#[test]
+#[should_panic(expected = "has no NM tag")]
+fn test_fail_on_missing_tag() {
+    use clap::Parser;
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--fail-on-missing-tag"]);
+    ReadMetrics::from_record(&clipped_record(), &args, &test_header());
+}
+
+#[test]
+fn test_mean_error_quality() {
+    // error probabilities 0.1, 0.001, 0.001 and 0.0001, with a mean of 0.025525
+    let qual = [10, 30, 30, 40];
+    assert_eq!(mean_quality(&qual, QualityMethod::MeanPhred), Some(27.5));
+    let mean_error = mean_quality(&qual, QualityMethod::MeanError).unwrap();
+    assert!((mean_error - -10.0 * 0.025525f64.log10()).abs() < 1e-9);
+    assert!((mean_error - 15.93).abs() < 0.01);
+}
+
+#[test]
+fn test_flags_column() {
+    use clap::Parser;
+    let mut record = clipped_record_with_nm();
+    record.set_flags(2064);
+    let args = Cli::parse_from(["make_arrow", "input.bam", "--include-flags-column"]);
+    let metrics = ReadMetrics::from_record(&record, &args, &test_header());
+    assert_eq!(metrics.flags, Some(record.flags()));
+    assert_eq!(metrics.flags, Some(2064));
+}
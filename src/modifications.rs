@@ -0,0 +1,221 @@
+use clap::ValueEnum;
+use rust_htslib::bam::{self, record::Aux};
+
+/// Base modification calls with an ML probability of at least 128/256 are considered modified
+const MODIFIED_THRESHOLD: u8 = 128;
+
+/// The base modifications as encoded in the MM tag by their canonical base and code
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ModType {
+    #[value(name = "5mC")]
+    FiveMC,
+    #[value(name = "5hmC")]
+    FiveHmC,
+    #[value(name = "5fC")]
+    FiveFC,
+    #[value(name = "5caC")]
+    FiveCaC,
+    #[value(name = "6mA")]
+    SixMA,
+}
+
+impl ModType {
+    fn base_and_code(&self) -> (char, &'static str) {
+        match self {
+            ModType::FiveMC => ('C', "m"),
+            ModType::FiveHmC => ('C', "h"),
+            ModType::FiveFC => ('C', "f"),
+            ModType::FiveCaC => ('C', "c"),
+            ModType::SixMA => ('A', "a"),
+        }
+    }
+
+    pub fn column_name(&self) -> &'static str {
+        match self {
+            ModType::FiveMC => "frac_5mC",
+            ModType::FiveHmC => "frac_5hmC",
+            ModType::FiveFC => "frac_5fC",
+            ModType::FiveCaC => "frac_5caC",
+            ModType::SixMA => "frac_6mA",
+        }
+    }
+}
+
+/// The base modification calls of one modification type, as ML probabilities
+#[derive(Debug, PartialEq)]
+struct ModCalls {
+    base: char,
+    code: String,
+    probabilities: Vec<u8>,
+}
+
+/// Parses the MM tag (e.g. C+mh?,5,12;A+a,3;) with its ML probabilities
+/// if multiple codes are combined for one base, the ML values are interleaved per position
+/// the bases skipped in the implicit mode (. or no mode) are unmodified calls with probability 0
+/// while the skipped bases of the explicit mode (?) are not called
+fn parse_mod_calls(mm: &str, ml: &[u8]) -> Vec<ModCalls> {
+    let mut ml_offset = 0;
+    let mut calls = vec![];
+    for entry in mm.split(';').filter(|e| !e.is_empty()) {
+        let mut fields = entry.split(',');
+        let header = fields.next().unwrap_or_default();
+        let skips: Vec<usize> = fields.filter_map(|f| f.trim().parse().ok()).collect();
+        let positions = skips.len();
+        let implicit = !header.ends_with('?');
+        let mut chars = header.chars();
+        let base = chars.next().unwrap_or('N');
+        // skip the strand
+        chars.next();
+        let codes: String = chars.filter(|c| !matches!(c, '.' | '?')).collect();
+        // numeric ChEBI codes are a single code, otherwise every character is one
+        let codes: Vec<String> = if codes.chars().all(|c| c.is_ascii_digit()) {
+            vec![codes]
+        } else {
+            codes.chars().map(|c| c.to_string()).collect()
+        };
+        let n_codes = codes.len();
+        for (i, code) in codes.into_iter().enumerate() {
+            let mut probabilities: Vec<u8> = (0..positions)
+                .filter_map(|p| ml.get(ml_offset + p * n_codes + i).copied())
+                .collect();
+            if implicit {
+                probabilities.extend(std::iter::repeat_n(0, skips.iter().sum()));
+            }
+            calls.push(ModCalls {
+                base,
+                code,
+                probabilities,
+            });
+        }
+        ml_offset += positions * n_codes;
+    }
+    calls
+}
+
+/// The fraction of calls considered modified for each of the requested modification types
+/// modification types absent from the MM tag are not returned
+pub fn mod_fractions(mm: &str, ml: &[u8], mod_types: &[ModType]) -> Vec<(ModType, f64)> {
+    let calls = parse_mod_calls(mm, ml);
+    mod_types
+        .iter()
+        .filter_map(|mod_type| {
+            let (base, code) = mod_type.base_and_code();
+            let probabilities: Vec<u8> = calls
+                .iter()
+                .filter(|c| c.base == base && c.code == code)
+                .flat_map(|c| c.probabilities.iter().copied())
+                .collect();
+            if probabilities.is_empty() {
+                return None;
+            }
+            let modified = probabilities
+                .iter()
+                .filter(|p| **p >= MODIFIED_THRESHOLD)
+                .count();
+            Some((*mod_type, modified as f64 / probabilities.len() as f64))
+        })
+        .collect()
+}
+
+/// The mean ML probability, scaled from 0-255 to 0-1, of the calls of the requested modification
+/// types, or of all calls if no types are requested, None without calls
+pub fn mean_probability(mm: &str, ml: &[u8], mod_types: &[ModType]) -> Option<f64> {
+    let probabilities: Vec<u8> = parse_mod_calls(mm, ml)
+        .into_iter()
+        .filter(|c| {
+            mod_types.is_empty()
+                || mod_types
+                    .iter()
+                    .any(|t| t.base_and_code() == (c.base, c.code.as_str()))
+        })
+        .flat_map(|c| c.probabilities)
+        .collect();
+    if probabilities.is_empty() {
+        return None;
+    }
+    let sum: u64 = probabilities.iter().map(|p| *p as u64).sum();
+    Some(sum as f64 / probabilities.len() as f64 / 255.0)
+}
+
+/// Gets the MM and ML tags, also accepting the older Mm and Ml tags
+pub fn get_mm_ml_tags(record: &bam::Record) -> Option<(String, Vec<u8>)> {
+    let mm = match record.aux(b"MM").or_else(|_| record.aux(b"Mm")) {
+        Ok(Aux::String(mm)) => mm.to_string(),
+        _ => return None,
+    };
+    let ml = match record.aux(b"ML").or_else(|_| record.aux(b"Ml")) {
+        Ok(Aux::ArrayU8(ml)) => ml.iter().collect(),
+        _ => return None,
+    };
+    Some((mm, ml))
+}
+
+#[test]
+fn test_parse_mod_calls() {
+    let calls = parse_mod_calls("C+mh?,1,0;A+a.,2;", &[200, 10, 100, 150, 250]);
+    assert_eq!(calls.len(), 3);
+    assert_eq!(calls[0].probabilities, vec![200, 100]);
+    assert_eq!(calls[1].probabilities, vec![10, 150]);
+    // the two skipped A's of the implicit mode are unmodified
+    assert_eq!(calls[2].probabilities, vec![250, 0, 0]);
+}
+
+#[test]
+fn test_parse_mod_calls_implicit() {
+    let calls = parse_mod_calls("C+m,5,12;", &[200, 250]);
+    assert_eq!(calls.len(), 1);
+    let unmodified = calls[0].probabilities.iter().filter(|p| **p == 0).count();
+    assert_eq!(unmodified, 17);
+    assert_eq!(calls[0].probabilities.len(), 19);
+    assert_eq!(
+        mod_fractions("C+m,5,12;", &[200, 250], &[ModType::FiveMC]),
+        vec![(ModType::FiveMC, 2.0 / 19.0)]
+    );
+    // the skipped C's of the explicit mode are not called
+    let calls = parse_mod_calls("C+m?,5,12;", &[200, 250]);
+    assert_eq!(calls[0].probabilities, vec![200, 250]);
+}
+
+#[test]
+fn test_mod_fractions() {
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    let mut record = bam::Record::new();
+    record.set(
+        b"modified",
+        Some(&CigarString(vec![Cigar::Match(8)])),
+        b"CACGTACG",
+        &[30; 8],
+    );
+    record
+        .push_aux(b"MM", Aux::String("C+m,0,0,0;C+h,0;A+a,1;"))
+        .unwrap();
+    record
+        .push_aux(b"ML", Aux::ArrayU8((&[255u8, 10, 200, 60, 255][..]).into()))
+        .unwrap();
+    let (mm, ml) = get_mm_ml_tags(&record).unwrap();
+    let fractions = mod_fractions(
+        &mm,
+        &ml,
+        &[ModType::FiveMC, ModType::SixMA, ModType::FiveFC],
+    );
+    assert_eq!(
+        fractions,
+        vec![(ModType::FiveMC, 2.0 / 3.0), (ModType::SixMA, 0.5)]
+    );
+}
+
+#[test]
+fn test_mean_probability() {
+    let (mm, ml) = ("C+m,0,0,0;A+a?,1;", [255u8, 0, 51, 255]);
+    // (255 + 0 + 51 + 255) / 4 / 255
+    assert!((mean_probability(mm, &ml, &[]).unwrap() - 0.55).abs() < 1e-9);
+    // (255 + 0 + 51) / 3 / 255
+    assert!((mean_probability(mm, &ml, &[ModType::FiveMC]).unwrap() - 0.4).abs() < 1e-9);
+    assert_eq!(mean_probability(mm, &ml, &[ModType::SixMA]), Some(1.0));
+    // the skipped A of the implicit mode is an unmodified call
+    assert_eq!(
+        mean_probability("A+a,1;", &[255], &[ModType::SixMA]),
+        Some(0.5)
+    );
+    assert_eq!(mean_probability(mm, &ml, &[ModType::FiveHmC]), None);
+}
@@ -0,0 +1,791 @@
+use arrow::array::{Array, BooleanArray, StringArray, UInt8Array};
+use arrow::compute::{cast, filter_record_batch};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::writer::{FileWriter, IpcWriteOptions, StreamWriter};
+use arrow::record_batch::RecordBatch;
+use clap::ValueEnum;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Uncompressed Arrow IPC file
+    Arrow,
+    /// Uncompressed Arrow IPC stream, which can be read while it is written, to stdout with -o -
+    ArrowStream,
+    /// Feather V2 file, which is an Arrow IPC file with LZ4 compression as written by pandas
+    Feather,
+    /// Parquet file with snappy compression
+    Parquet,
+    /// Tab-separated text file with a header, nulls are written as empty fields or the --na-string
+    Tsv,
+    /// JSON object per line, nulls are written as null or the --na-string
+    Jsonl,
+}
+
+impl OutputFormat {
+    /// The format of an output file with this extension
+    pub fn from_extension(path: &str) -> Option<Self> {
+        match std::path::Path::new(path).extension()?.to_str()? {
+            "arrow" => Some(OutputFormat::Arrow),
+            "arrows" => Some(OutputFormat::ArrowStream),
+            "feather" => Some(OutputFormat::Feather),
+            "parquet" => Some(OutputFormat::Parquet),
+            "tsv" => Some(OutputFormat::Tsv),
+            "jsonl" => Some(OutputFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionBy {
+    /// A partition per contig, with unmapped reads in the default partition
+    Contig,
+}
+
+impl PartitionBy {
+    /// The column which is used to partition the reads, and is not written itself
+    pub fn column_name(&self) -> &'static str {
+        match self {
+            PartitionBy::Contig => "contig",
+        }
+    }
+}
+
+/// The partition of reads without value, as named by Hive
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// A batch is written when reaching either a number of rows, or an estimated size in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchSize {
+    Rows(usize),
+    Bytes(usize),
+}
+
+impl BatchSize {
+    pub fn is_full(&self, rows: usize, bytes: usize) -> bool {
+        match self {
+            BatchSize::Rows(max) => rows >= *max,
+            BatchSize::Bytes(max) => bytes >= *max,
+        }
+    }
+}
+
+impl FromStr for BatchSize {
+    type Err = String;
+
+    /// Parses a number of rows (e.g. 100000) or of bytes with a unit (e.g. 256MB)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(digits);
+        let number: usize = number
+            .parse()
+            .map_err(|_| format!("Invalid batch size {s}"))?;
+        if number == 0 {
+            return Err("Batch size should be at least 1".to_string());
+        }
+        let multiplier = match unit.to_ascii_uppercase().as_str() {
+            "" => return Ok(BatchSize::Rows(number)),
+            "B" => 1,
+            "KB" => 1_000,
+            "MB" => 1_000_000,
+            "GB" => 1_000_000_000,
+            _ => {
+                return Err(format!(
+                    "Invalid batch size unit {unit}, expected B, KB, MB or GB"
+                ))
+            }
+        };
+        Ok(BatchSize::Bytes(number * multiplier))
+    }
+}
+
+/// The string columns which are dictionary-encoded in parquet output, which has a dictionary per row group
+/// while arrow files only allow the same dictionary in every batch
+const PARQUET_DICTIONARY_COLUMNS: [&str; 1] = ["barcode"];
+
+/// The schema of parquet output, with the PARQUET_DICTIONARY_COLUMNS dictionary-encoded
+fn parquet_schema(schema: &Schema) -> Schema {
+    Schema::new(
+        schema
+            .fields()
+            .iter()
+            .map(|field| {
+                if PARQUET_DICTIONARY_COLUMNS.contains(&field.name().as_str()) {
+                    Field::new_dict(
+                        field.name(),
+                        DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+                        field.is_nullable(),
+                        0,
+                        false,
+                    )
+                } else {
+                    field.as_ref().clone()
+                }
+            })
+            .collect::<Vec<Field>>(),
+    )
+}
+
+/// Converts the batch to the parquet_schema
+fn parquet_batch(batch: &RecordBatch) -> RecordBatch {
+    let schema = Arc::new(parquet_schema(&batch.schema()));
+    let arrays = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(array, field)| {
+            cast(array, field.data_type()).expect("Failure dictionary-encoding a parquet column")
+        })
+        .collect();
+    RecordBatch::try_new(schema, arrays).expect("Failure creating parquet record batch")
+}
+
+/// Writes record batches to the output file as they are produced
+pub enum OutputWriter {
+    Arrow(FileWriter<BufWriter<File>>),
+    ArrowStream(StreamWriter<BufWriter<Box<dyn Write + Send>>>),
+    Parquet(ArrowWriter<File>),
+    Tsv(Box<arrow::csv::Writer<File>>),
+    Jsonl(JsonlWriter),
+    PartitionedParquet(PartitionedParquetWriter),
+    MapqSplit(MapqSplitWriter),
+}
+
+/// The number of partition files which are open at once, each buffering its row group
+const MAX_OPEN_PARTITIONS: usize = 256;
+
+/// Escapes the characters of a partition value which Hive does not allow in a directory name
+/// as % with the uppercase hex code, e.g. chrUn/1 as chrUn%2F1
+fn hive_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_ascii_control()
+            || matches!(
+                c,
+                '"' | '#' | '%' | '\'' | '*' | '/' | ':' | '=' | '?' | '\\' | '{' | '[' | ']' | '^'
+            )
+        {
+            escaped.push_str(&format!("%{:02X}", c as u8));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Writes a Hive-style partitioned parquet dataset, with a file per value of the partition column
+/// as directory/column=value/part.parquet
+/// with more than MAX_OPEN_PARTITIONS partitions, the least recently written file is closed
+/// and a later batch of its partition is written to the next file, as part-1.parquet etc
+pub struct PartitionedParquetWriter {
+    directory: PathBuf,
+    column: &'static str,
+    row_group_size: usize,
+    max_open: usize,
+    /// The open writer per partition, with the number of the batch last written to it
+    writers: HashMap<String, (ArrowWriter<File>, usize)>,
+    /// The number of files created per partition
+    files: HashMap<String, usize>,
+    batches: usize,
+}
+
+impl PartitionedParquetWriter {
+    fn write(&mut self, batch: &RecordBatch) {
+        self.batches += 1;
+        let batch = &parquet_batch(batch);
+        let index = batch
+            .schema()
+            .index_of(self.column)
+            .expect("Partition column missing from record batch");
+        let values = batch
+            .column(index)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .expect("Partition column is not a string column")
+            .clone();
+        let projection: Vec<usize> = (0..batch.num_columns()).filter(|i| *i != index).collect();
+        let mut partitions: Vec<Option<&str>> = vec![];
+        for value in values.iter() {
+            if !partitions.contains(&value) {
+                partitions.push(value);
+            }
+        }
+        for partition in partitions {
+            let mask: BooleanArray = values.iter().map(|v| Some(v == partition)).collect();
+            let rows = filter_record_batch(batch, &mask)
+                .and_then(|rows| rows.project(&projection))
+                .expect("Failure selecting the reads of a partition");
+            let name = partition.unwrap_or(DEFAULT_PARTITION);
+            if !self.writers.contains_key(name) {
+                if self.writers.len() >= self.max_open {
+                    self.close_least_recent();
+                }
+                let writer = self.new_file(name, &rows.schema());
+                self.writers.insert(name.to_string(), (writer, 0));
+            }
+            let (writer, last_batch) = self.writers.get_mut(name).unwrap();
+            writer.write(&rows).expect("write parquet batch error");
+            *last_batch = self.batches;
+        }
+    }
+
+    /// Creates the next file of the partition, of which the first is part.parquet
+    fn new_file(&mut self, name: &str, schema: &Arc<Schema>) -> ArrowWriter<File> {
+        let directory = self
+            .directory
+            .join(format!("{}={}", self.column, hive_escape(name)));
+        std::fs::create_dir_all(&directory).expect("create parquet partition directory error");
+        let files = self.files.entry(name.to_string()).or_insert(0);
+        let filename = match *files {
+            0 => String::from("part.parquet"),
+            n => format!("part-{n}.parquet"),
+        };
+        *files += 1;
+        let buffer = File::create(directory.join(filename)).expect("create parquet file error");
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(self.row_group_size)
+            .build();
+        ArrowWriter::try_new(buffer, schema.clone(), Some(properties))
+            .expect("create parquet file writer error")
+    }
+
+    fn close_least_recent(&mut self) {
+        let name = self
+            .writers
+            .iter()
+            .min_by_key(|(_, (_, last_batch))| *last_batch)
+            .map(|(name, _)| name.clone())
+            .expect("No open partition to close");
+        let (writer, _) = self.writers.remove(&name).unwrap();
+        writer.close().expect("finish write parquet error");
+    }
+
+    fn finish(self) {
+        for (writer, _) in self.writers.into_values() {
+            writer.close().expect("finish write parquet error");
+        }
+    }
+}
+
+/// The ranges of mapping qualities split at the lowest mapping quality of each bucket after the first,
+/// e.g. 1,31,61 gives 0-0, 1-30, 31-60 and 61-255
+pub fn mapq_buckets(edges: &[u8]) -> Vec<(u8, u8)> {
+    if edges.contains(&0) {
+        panic!("The mapping quality buckets can not be split at 0, the first bucket always starts at 0");
+    }
+    if edges.windows(2).any(|pair| pair[0] >= pair[1]) {
+        panic!("The mapping quality buckets should be split at increasing mapping qualities");
+    }
+    let mut starts = vec![0];
+    starts.extend(edges);
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| (*start, starts.get(i + 1).map_or(u8::MAX, |next| next - 1)))
+        .collect()
+}
+
+/// The output file of a mapping quality bucket, with .mapq<min>-<max> before the extension
+pub fn mapq_bucket_path(path: &str, (min, max): (u8, u8)) -> String {
+    let path = std::path::Path::new(path);
+    let bucket = format!("mapq{min}-{max}");
+    match path.extension() {
+        Some(extension) => path.with_extension(format!("{bucket}.{}", extension.to_string_lossy())),
+        None => path.with_extension(bucket),
+    }
+    .to_string_lossy()
+    .to_string()
+}
+
+/// Routes the reads to the writer of the bucket of their mapping quality
+pub struct MapqSplitWriter {
+    buckets: Vec<((u8, u8), OutputWriter)>,
+}
+
+impl MapqSplitWriter {
+    fn write(&mut self, batch: &RecordBatch) {
+        let mapqs = batch
+            .column_by_name("mapQ")
+            .expect("mapQ column missing from record batch")
+            .as_any()
+            .downcast_ref::<UInt8Array>()
+            .expect("mapQ column is not a UInt8 column")
+            .clone();
+        for ((min, max), writer) in &mut self.buckets {
+            let mask: BooleanArray = mapqs
+                .iter()
+                .map(|mapq| mapq.map(|mapq| (*min..=*max).contains(&mapq)))
+                .collect();
+            let rows = filter_record_batch(batch, &mask)
+                .expect("Failure selecting the reads of a mapping quality bucket");
+            if rows.num_rows() > 0 {
+                writer.write(&rows);
+            }
+        }
+    }
+}
+
+/// Writes every row as a JSON object with the fields in schema order, including nulls
+pub struct JsonlWriter {
+    writer: BufWriter<File>,
+    field_names: Vec<String>,
+    na_string: Option<String>,
+}
+
+impl JsonlWriter {
+    fn write(&mut self, batch: &RecordBatch) {
+        let rows = arrow::json::writer::record_batches_to_json_rows(&[batch])
+            .expect("convert batch to json error");
+        let na = match &self.na_string {
+            Some(na_string) => serde_json::Value::String(na_string.clone()),
+            None => serde_json::Value::Null,
+        };
+        for row in rows {
+            let fields: Vec<String> = self
+                .field_names
+                .iter()
+                .map(|name| {
+                    format!(
+                        "{}:{}",
+                        serde_json::Value::String(name.clone()),
+                        row.get(name).unwrap_or(&na)
+                    )
+                })
+                .collect();
+            writeln!(self.writer, "{{{}}}", fields.join(",")).expect("write jsonl row error");
+        }
+    }
+}
+
+impl OutputWriter {
+    /// Writes an arrow file through a buffer of buffer_size bytes
+    pub fn new_arrow(
+        filename: &str,
+        schema: &Schema,
+        write_options: IpcWriteOptions,
+        buffer_size: usize,
+    ) -> Self {
+        let buffer = BufWriter::with_capacity(
+            buffer_size,
+            File::create(filename).expect("create arrow file error"),
+        );
+        OutputWriter::Arrow(
+            FileWriter::try_new_with_options(buffer, schema, write_options)
+                .expect("create arrow file writer error"),
+        )
+    }
+
+    /// Writes an arrow stream to the file, or to stdout if the filename is -
+    pub fn new_arrow_stream(
+        filename: &str,
+        schema: &Schema,
+        write_options: IpcWriteOptions,
+        buffer_size: usize,
+    ) -> Self {
+        let output: Box<dyn Write + Send> = if filename == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(File::create(filename).expect("create arrow stream file error"))
+        };
+        OutputWriter::ArrowStream(
+            StreamWriter::try_new_with_options(
+                BufWriter::with_capacity(buffer_size, output),
+                schema,
+                write_options,
+            )
+            .expect("create arrow stream writer error"),
+        )
+    }
+
+    /// Writes a parquet file with row groups of at most row_group_size rows
+    pub fn new_parquet(filename: &str, schema: &Schema, row_group_size: usize) -> Self {
+        let buffer = File::create(filename).expect("create parquet file error");
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(row_group_size)
+            .build();
+        OutputWriter::Parquet(
+            ArrowWriter::try_new(buffer, Arc::new(parquet_schema(schema)), Some(properties))
+                .expect("create parquet file writer error"),
+        )
+    }
+
+    /// Writes a parquet file per partition in the directory
+    pub fn new_partitioned_parquet(
+        directory: &str,
+        partition_by: PartitionBy,
+        row_group_size: usize,
+    ) -> Self {
+        std::fs::create_dir_all(directory).expect("create parquet dataset directory error");
+        OutputWriter::PartitionedParquet(PartitionedParquetWriter {
+            directory: PathBuf::from(directory),
+            column: partition_by.column_name(),
+            row_group_size,
+            max_open: MAX_OPEN_PARTITIONS,
+            writers: HashMap::new(),
+            files: HashMap::new(),
+            batches: 0,
+        })
+    }
+
+    /// Writes the reads of each mapping quality bucket with its own writer
+    pub fn new_mapq_split(buckets: Vec<((u8, u8), OutputWriter)>) -> Self {
+        OutputWriter::MapqSplit(MapqSplitWriter { buckets })
+    }
+
+    /// Writes a tsv file, or appends to the first resume_size bytes of an existing file without header
+    pub fn new_tsv(
+        filename: &str,
+        schema: &Schema,
+        na_string: Option<&str>,
+        resume_size: Option<u64>,
+    ) -> Self {
+        let buffer = create_or_resume(filename, resume_size);
+        let mut writer = arrow::csv::WriterBuilder::new()
+            .with_delimiter(b'\t')
+            .with_null(na_string.unwrap_or_default().to_string())
+            .has_headers(resume_size.is_none())
+            .build(buffer);
+        // writing an empty batch makes sure the header is present, even without reads
+        writer
+            .write(&RecordBatch::new_empty(Arc::new(schema.clone())))
+            .expect("write tsv header error");
+        OutputWriter::Tsv(Box::new(writer))
+    }
+
+    /// Writes a jsonl file, or appends to the first resume_size bytes of an existing file
+    pub fn new_jsonl(
+        filename: &str,
+        schema: &Schema,
+        na_string: Option<&str>,
+        resume_size: Option<u64>,
+    ) -> Self {
+        let buffer = create_or_resume(filename, resume_size);
+        OutputWriter::Jsonl(JsonlWriter {
+            writer: BufWriter::new(buffer),
+            field_names: schema.fields().iter().map(|f| f.name().clone()).collect(),
+            na_string: na_string.map(|s| s.to_string()),
+        })
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) {
+        match self {
+            OutputWriter::Arrow(writer) => writer.write(batch).expect("write arrow batch error"),
+            OutputWriter::ArrowStream(writer) => {
+                writer.write(batch).expect("write arrow stream batch error")
+            }
+            OutputWriter::Parquet(writer) => writer
+                .write(&parquet_batch(batch))
+                .expect("write parquet batch error"),
+            OutputWriter::Tsv(writer) => writer.write(batch).expect("write tsv batch error"),
+            OutputWriter::Jsonl(writer) => writer.write(batch),
+            OutputWriter::PartitionedParquet(writer) => writer.write(batch),
+            OutputWriter::MapqSplit(writer) => writer.write(batch),
+        }
+    }
+
+    /// Writes the buffered rows to the output file, which is only possible for arrow, tsv and jsonl output
+    /// as parquet files are written per row group, arrow files are incomplete until finished
+    /// while a reader of an arrow stream gets every flushed batch
+    pub fn flush(&mut self) {
+        match self {
+            OutputWriter::Arrow(writer) => writer.get_mut().flush().expect("flush arrow error"),
+            OutputWriter::ArrowStream(writer) => {
+                writer.get_mut().flush().expect("flush arrow stream error")
+            }
+            OutputWriter::Tsv(_) => (), // the tsv writer flushes every batch
+            OutputWriter::Jsonl(writer) => writer.writer.flush().expect("flush jsonl error"),
+            OutputWriter::MapqSplit(writer) => {
+                for (_, writer) in &mut writer.buckets {
+                    writer.flush();
+                }
+            }
+            _ => panic!("Only arrow, tsv and jsonl output can be flushed"),
+        }
+    }
+
+    pub fn finish(self) {
+        match self {
+            OutputWriter::Arrow(writer) => {
+                writer
+                    .into_inner()
+                    .expect("finish write arrow error")
+                    .into_inner()
+                    .expect("finish write arrow error")
+                    .sync_all()
+                    .expect("finish write arrow error");
+            }
+            OutputWriter::ArrowStream(writer) => {
+                writer
+                    .into_inner()
+                    .expect("finish write arrow stream error")
+                    .flush()
+                    .expect("finish write arrow stream error");
+            }
+            OutputWriter::Parquet(writer) => {
+                writer.close().expect("finish write parquet error");
+            }
+            OutputWriter::Tsv(writer) => {
+                writer
+                    .into_inner()
+                    .sync_all()
+                    .expect("finish write tsv error");
+            }
+            OutputWriter::Jsonl(writer) => {
+                writer
+                    .writer
+                    .into_inner()
+                    .expect("finish write jsonl error")
+                    .sync_all()
+                    .expect("finish write jsonl error");
+            }
+            OutputWriter::PartitionedParquet(writer) => writer.finish(),
+            OutputWriter::MapqSplit(writer) => {
+                for (_, writer) in writer.buckets {
+                    writer.finish();
+                }
+            }
+        }
+    }
+}
+
+/// Creates the file, or truncates an existing file to resume_size bytes to append to it
+/// which discards anything written after the last checkpoint
+fn create_or_resume(filename: &str, resume_size: Option<u64>) -> File {
+    match resume_size {
+        Some(size) => {
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(filename)
+                .unwrap_or_else(|e| panic!("Failure opening {filename} to resume: {e}"));
+            if file.metadata().expect("read output size error").len() < size {
+                panic!(
+                    "{filename} is smaller than recorded in the checkpoint, and can not be resumed"
+                );
+            }
+            file.set_len(size).expect("truncate output error");
+            file.seek(SeekFrom::End(0)).expect("seek output error");
+            file
+        }
+        None => File::create(filename).expect("create output file error"),
+    }
+}
+
+#[test]
+fn test_mapq_buckets() {
+    assert_eq!(
+        mapq_buckets(&[1, 31, 61]),
+        [(0, 0), (1, 30), (31, 60), (61, 255)]
+    );
+    assert_eq!(mapq_buckets(&[]), [(0, 255)]);
+    assert_eq!(
+        mapq_bucket_path("out/reads.arrow", (1, 30)),
+        "out/reads.mapq1-30.arrow"
+    );
+    assert_eq!(mapq_bucket_path("reads", (0, 0)), "reads.mapq0-0");
+}
+
+#[test]
+fn test_batch_size() {
+    assert_eq!("1000".parse(), Ok(BatchSize::Rows(1000)));
+    assert_eq!("256MB".parse(), Ok(BatchSize::Bytes(256_000_000)));
+    assert_eq!("20kb".parse(), Ok(BatchSize::Bytes(20_000)));
+    assert!("0".parse::<BatchSize>().is_err());
+    assert!("12XB".parse::<BatchSize>().is_err());
+}
+
+#[test]
+fn test_flush() {
+    use arrow::array::{ArrayRef, UInt64Array};
+    use arrow::ipc::reader::StreamReader;
+    let batch = RecordBatch::try_from_iter([(
+        "lengths",
+        Arc::new(UInt64Array::from(vec![1, 2, 3])) as ArrayRef,
+    )])
+    .unwrap();
+    let mut writer = OutputWriter::new_arrow(
+        "test-flush.arrow",
+        &batch.schema(),
+        IpcWriteOptions::default(),
+        1 << 20,
+    );
+    // the batches written so far, read as the stream of messages after the magic of the arrow file
+    let written = || -> usize {
+        let mut file = File::open("test-flush.arrow").unwrap();
+        if file.metadata().unwrap().len() == 0 {
+            return 0;
+        }
+        file.seek(SeekFrom::Start(8)).unwrap();
+        StreamReader::try_new(file, None)
+            .unwrap()
+            .map(|batch| batch.unwrap().num_rows())
+            .sum()
+    };
+    writer.write(&batch);
+    writer.flush();
+    assert_eq!(written(), 3);
+    writer.write(&batch.slice(0, 1));
+    writer.flush();
+    assert_eq!(written(), 4);
+    writer.finish();
+    // the rows of a jsonl file are buffered until flushed
+    let mut writer = OutputWriter::new_jsonl("test-flush.jsonl", &batch.schema(), None, None);
+    let lines = || {
+        std::fs::read_to_string("test-flush.jsonl")
+            .unwrap()
+            .lines()
+            .count()
+    };
+    writer.write(&batch);
+    assert_eq!(lines(), 0);
+    writer.flush();
+    assert_eq!(lines(), 3);
+    writer.finish();
+}
+
+#[test]
+fn test_parquet_barcode_dictionary() {
+    use arrow::array::{ArrayRef, DictionaryArray};
+    use arrow::datatypes::Int32Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    let batch = |barcodes: Vec<Option<&str>>| {
+        RecordBatch::try_from_iter([("barcode", Arc::new(StringArray::from(barcodes)) as ArrayRef)])
+            .unwrap()
+    };
+    let first = batch(vec![Some("AAAA-1"), None, Some("AAAA-1")]);
+    let mut writer = OutputWriter::new_parquet("test-barcodes.parquet", &first.schema(), 2);
+    writer.write(&first);
+    writer.write(&batch(vec![Some("CCCC-1")]));
+    writer.finish();
+    let reader =
+        ParquetRecordBatchReaderBuilder::try_new(File::open("test-barcodes.parquet").unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+    let mut barcodes = vec![];
+    for batch in reader {
+        let batch = batch.unwrap();
+        let column = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<DictionaryArray<Int32Type>>()
+            .expect("barcode is not dictionary-encoded");
+        let values = column
+            .values()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        barcodes.extend(
+            column
+                .keys()
+                .iter()
+                .map(|key| key.map(|key| values.value(key as usize).to_string())),
+        );
+    }
+    assert_eq!(
+        barcodes,
+        [Some("AAAA-1"), None, Some("AAAA-1"), Some("CCCC-1")].map(|b| b.map(String::from))
+    );
+}
+
+#[test]
+fn test_partitioned_parquet() {
+    use arrow::array::{ArrayRef, UInt64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    let _ = std::fs::remove_dir_all("test-partitions.parquet");
+    let batch = RecordBatch::try_from_iter([
+        (
+            "lengths",
+            Arc::new(UInt64Array::from(vec![1, 2, 3, 4])) as ArrayRef,
+        ),
+        (
+            "contig",
+            Arc::new(StringArray::from(vec![
+                Some("chr1"),
+                Some("chr2"),
+                None,
+                Some("chr1"),
+            ])) as _,
+        ),
+    ])
+    .unwrap();
+    let mut writer =
+        OutputWriter::new_partitioned_parquet("test-partitions.parquet", PartitionBy::Contig, 10);
+    writer.write(&batch);
+    writer.write(&batch.slice(0, 1));
+    writer.finish();
+    let lengths = |partition: &str| -> Vec<u64> {
+        let file = File::open(format!("test-partitions.parquet/{partition}/part.parquet")).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        reader
+            .flat_map(|batch| {
+                let batch = batch.unwrap();
+                assert_eq!(batch.num_columns(), 1);
+                let column = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<UInt64Array>()
+                    .unwrap()
+                    .clone();
+                column.values().to_vec()
+            })
+            .collect()
+    };
+    assert_eq!(lengths("contig=chr1"), [1, 4, 1]);
+    assert_eq!(lengths("contig=chr2"), [2]);
+    assert_eq!(lengths("contig=__HIVE_DEFAULT_PARTITION__"), [3]);
+}
+
+#[test]
+fn test_partitioned_parquet_escape_and_limit() {
+    use arrow::array::{ArrayRef, UInt64Array};
+    let _ = std::fs::remove_dir_all("test-partitions-escaped.parquet");
+    let batch = |lengths: Vec<u64>, contigs: Vec<&str>| {
+        RecordBatch::try_from_iter([
+            ("lengths", Arc::new(UInt64Array::from(lengths)) as ArrayRef),
+            ("contig", Arc::new(StringArray::from(contigs)) as _),
+        ])
+        .unwrap()
+    };
+    let mut writer = OutputWriter::new_partitioned_parquet(
+        "test-partitions-escaped.parquet",
+        PartitionBy::Contig,
+        10,
+    );
+    if let OutputWriter::PartitionedParquet(writer) = &mut writer {
+        writer.max_open = 1;
+    }
+    writer.write(&batch(vec![1, 2], vec!["chrUn/1", "HLA-A*01:01"]));
+    writer.write(&batch(vec![3], vec!["chrUn/1"]));
+    writer.finish();
+    let files = |partition: &str| -> Vec<String> {
+        let mut files: Vec<String> =
+            std::fs::read_dir(format!("test-partitions-escaped.parquet/{partition}"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+        files.sort();
+        files
+    };
+    // the partition of chrUn/1 was closed for HLA-A*01:01, and continued in a second file
+    assert_eq!(
+        files("contig=chrUn%2F1"),
+        ["part-1.parquet", "part.parquet"]
+    );
+    assert_eq!(files("contig=HLA-A%2A01%3A01"), ["part.parquet"]);
+    assert_eq!(hive_escape("a=b%"), "a%3Db%25");
+}
@@ -0,0 +1,103 @@
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads which are started once for the whole run, and run the jobs sent to them
+/// such as building the arrays of the columns of every record batch
+pub struct ThreadPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::spawn(move || loop {
+                    // the lock is released before running the job, so that the other threads can take the next
+                    let job = receiver
+                        .lock()
+                        .expect("Failure receiving a job of the thread pool")
+                        .recv();
+                    match job {
+                        Ok(job) => job(),
+                        // the pool was dropped
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Runs the jobs on the threads of the pool, and returns their results in the order of the jobs
+    pub fn map<T, F>(&self, jobs: impl IntoIterator<Item = F>) -> Vec<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (results_sender, results) = mpsc::channel();
+        let mut count = 0;
+        for (i, job) in jobs.into_iter().enumerate() {
+            let results_sender = results_sender.clone();
+            self.sender
+                .as_ref()
+                .expect("Thread pool is stopped")
+                .send(Box::new(move || {
+                    // the receiver is only gone if another job of this map panicked
+                    let _ = results_sender.send((i, job()));
+                }))
+                .expect("Failure sending a job to the thread pool");
+            count += 1;
+        }
+        drop(results_sender);
+        let mut ordered: Vec<Option<T>> = (0..count).map(|_| None).collect();
+        // the results end early if a job panicked, as its sender is dropped without result
+        for (i, result) in results {
+            ordered[i] = Some(result);
+        }
+        ordered
+            .into_iter()
+            .map(|result| result.expect("Failure running a job of the thread pool"))
+            .collect()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // closing the channel stops the threads after their last job
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[test]
+fn test_thread_pool() {
+    let pool = ThreadPool::new(3);
+    assert_eq!(pool.threads(), 3);
+    for _ in 0..10 {
+        let squares = pool.map((0..20u64).map(|i| move || i * i));
+        assert_eq!(squares, (0..20u64).map(|i| i * i).collect::<Vec<_>>());
+    }
+    // the same threads run the jobs of every map
+    let threads: std::collections::HashSet<_> = pool
+        .map((0..100).map(|_| || std::thread::current().id()))
+        .into_iter()
+        .collect();
+    assert!(threads.len() <= 3);
+    assert!(pool.map(Vec::<fn() -> u8>::new()).is_empty());
+}
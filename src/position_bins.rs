@@ -0,0 +1,182 @@
+use arrow::array::{ArrayRef, StringArray, UInt32Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::writer::{FileWriter, IpcWriteOptions};
+use arrow::record_batch::RecordBatch;
+use log::warn;
+use rust_htslib::bam;
+use rust_htslib::bam::record::Cigar;
+use std::fs::File;
+use std::sync::Arc;
+
+use crate::error_detail::{alignment_errors, ErrorType};
+
+/// The number of rows per record batch written to the position bins file
+const BATCH_SIZE: usize = 100_000;
+
+/// The number of substitutions in each of `bins` equally sized segments of the aligned reference span
+/// without introns, in the orientation of the read so that the first bin is the start of the read
+/// None without MD tag
+pub fn mismatch_bins(record: &bam::Record, bins: usize) -> Option<Vec<u64>> {
+    let errors = alignment_errors(record)?;
+    let start = record.pos();
+    // the reference start of every intron, with the intron bases up to its end
+    let mut introns = vec![];
+    let mut ref_pos = start;
+    let mut skipped = 0;
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::RefSkip(len) => {
+                ref_pos += *len as i64;
+                skipped += *len as i64;
+                introns.push((ref_pos, skipped));
+            }
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) | Cigar::Del(len) => {
+                ref_pos += *len as i64
+            }
+            _ => (),
+        }
+    }
+    let aligned_length = (record.cigar().end_pos() - start - skipped).max(1) as usize;
+    let mut counts = vec![0; bins];
+    for error in errors
+        .iter()
+        .filter(|e| e.error_type == ErrorType::Substitution)
+    {
+        let skipped_before = introns
+            .iter()
+            .take_while(|(intron_end, _)| *intron_end <= error.ref_pos)
+            .last()
+            .map_or(0, |(_, skipped)| *skipped);
+        let offset = (error.ref_pos - start - skipped_before) as usize;
+        let bin = (offset * bins / aligned_length).min(bins - 1);
+        if record.is_reverse() {
+            counts[bins - 1 - bin] += 1;
+        } else {
+            counts[bin] += 1;
+        }
+    }
+    Some(counts)
+}
+
+/// Writes the mismatches per position bin of all reads to an arrow file, in batches
+pub struct PositionBinWriter {
+    writer: FileWriter<File>,
+    schema: Arc<Schema>,
+    bins: usize,
+    names: Vec<String>,
+    counts: Vec<u64>,
+    warned: bool,
+}
+
+impl PositionBinWriter {
+    pub fn new(filename: &str, bins: usize, write_options: IpcWriteOptions) -> Self {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("read_name", DataType::Utf8, false),
+            Field::new("bin", DataType::UInt32, false),
+            Field::new("mismatches", DataType::UInt64, false),
+        ]));
+        let buffer = File::create(filename).expect("create position bins file error");
+        let writer = FileWriter::try_new_with_options(buffer, &schema, write_options)
+            .expect("create position bins file writer error");
+        PositionBinWriter {
+            writer,
+            schema,
+            bins,
+            names: vec![],
+            counts: vec![],
+            warned: false,
+        }
+    }
+
+    pub fn add(&mut self, record: &bam::Record) {
+        let Some(counts) = mismatch_bins(record, self.bins) else {
+            if !self.warned {
+                warn!("Reads without MD tag are missing from the position bins output");
+                self.warned = true;
+            }
+            return;
+        };
+        let name = String::from_utf8_lossy(record.qname()).to_string();
+        self.names.extend(std::iter::repeat_n(name, self.bins));
+        self.counts.extend(counts);
+        if self.counts.len() >= BATCH_SIZE {
+            self.write_batch();
+        }
+    }
+
+    fn write_batch(&mut self) {
+        let bins = self.bins as u32;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(&self.names)),
+            Arc::new(UInt32Array::from_iter_values(
+                (0..self.counts.len() as u32).map(|i| i % bins),
+            )),
+            Arc::new(UInt64Array::from_iter_values(self.counts.iter().copied())),
+        ];
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)
+            .expect("Failure creating position bins record batch");
+        self.writer
+            .write(&batch)
+            .expect("write position bins batch error");
+        self.names.clear();
+        self.counts.clear();
+    }
+
+    pub fn finish(mut self) {
+        if !self.counts.is_empty() {
+            self.write_batch();
+        }
+        self.writer
+            .finish()
+            .expect("finish write position bins error");
+    }
+}
+
+#[test]
+fn test_mismatch_bins() {
+    use rust_htslib::bam::record::{Aux, CigarString};
+    let mut record = bam::Record::new();
+    // 20 aligned reference bases from position 100, with substitutions at 101, 112 and 119
+    // and a deletion at 110 which is not counted
+    record.set(
+        b"mismatches",
+        Some(&CigarString(vec![
+            Cigar::SoftClip(3),
+            Cigar::Match(10),
+            Cigar::Del(1),
+            Cigar::Match(9),
+        ])),
+        &[b'A'; 22],
+        &[30; 22],
+    );
+    record.set_pos(100);
+    record.push_aux(b"MD", Aux::String("1C8^A1G6T0")).unwrap();
+    assert_eq!(mismatch_bins(&record, 4), Some(vec![1, 0, 1, 1]));
+    assert_eq!(mismatch_bins(&record, 1), Some(vec![3]));
+    record.remove_aux(b"MD").unwrap();
+    assert_eq!(mismatch_bins(&record, 4), None);
+}
+
+#[test]
+fn test_mismatch_bins_reverse_intron() {
+    use rust_htslib::bam::record::{Aux, CigarString};
+    let mut record = bam::Record::new();
+    // 20 aligned reference bases from position 100 with an intron of 1000 bases after the first 10
+    // and substitutions at 101 and 1112, the second and the thirteenth aligned base
+    record.set(
+        b"reverse",
+        Some(&CigarString(vec![
+            Cigar::Match(10),
+            Cigar::RefSkip(1000),
+            Cigar::Match(10),
+        ])),
+        &[b'A'; 20],
+        &[30; 20],
+    );
+    record.set_pos(100);
+    record.push_aux(b"MD", Aux::String("1C10G7")).unwrap();
+    assert_eq!(mismatch_bins(&record, 4), Some(vec![1, 0, 1, 0]));
+    // the start of a reverse read is the end of its alignment
+    record.set_reverse();
+    assert_eq!(mismatch_bins(&record, 4), Some(vec![0, 1, 0, 1]));
+}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// The stages of the extraction timed with --profile
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading, decompressing and filtering the reads
+    Decode,
+    /// Calculating the metrics of the reads from the CIGAR, sequence and tags
+    Metrics,
+    /// Building the record batches from the metrics
+    Batches,
+    /// Writing the record batches to the outputs
+    Write,
+}
+
+impl Stage {
+    const ALL: [Stage; 4] = [Stage::Decode, Stage::Metrics, Stage::Batches, Stage::Write];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Metrics => "metrics",
+            Stage::Batches => "batches",
+            Stage::Write => "write",
+        }
+    }
+}
+
+/// The time spent per stage, summed over the threads processing inputs with --file-parallelism
+#[derive(Debug, Default)]
+pub struct Profile {
+    nanos: [AtomicU64; Stage::ALL.len()],
+}
+
+impl Profile {
+    /// Adds the time since start to the stage
+    pub fn add(&self, stage: Stage, start: Instant) {
+        self.nanos[stage as usize].fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Times f as part of the stage if profiling, without reading the clock otherwise
+    pub fn time<T>(profile: Option<&Profile>, stage: Stage, f: impl FnOnce() -> T) -> T {
+        match profile {
+            Some(profile) => {
+                let start = Instant::now();
+                let result = f();
+                profile.add(stage, start);
+                result
+            }
+            None => f(),
+        }
+    }
+
+    /// A table with the seconds and percentage of the total time per stage
+    pub fn report(&self) -> String {
+        let nanos: Vec<u64> = self
+            .nanos
+            .iter()
+            .map(|nanos| nanos.load(Ordering::Relaxed))
+            .collect();
+        let total = nanos.iter().sum::<u64>().max(1);
+        let mut report = String::from("stage\tseconds\tpercentage\n");
+        for (stage, nanos) in Stage::ALL.iter().zip(nanos) {
+            report.push_str(&format!(
+                "{}\t{:.3}\t{:.1}\n",
+                stage.name(),
+                nanos as f64 / 1e9,
+                100.0 * nanos as f64 / total as f64
+            ));
+        }
+        report
+    }
+}
+
+#[test]
+fn test_profile_report() {
+    let profile = Profile::default();
+    let start = Instant::now();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    profile.add(Stage::Metrics, start);
+    assert_eq!(Profile::time(Some(&profile), Stage::Write, || 42), 42);
+    let report = profile.report();
+    let stages: Vec<&str> = report
+        .lines()
+        .map(|line| line.split('\t').next().unwrap())
+        .collect();
+    assert_eq!(stages, ["stage", "decode", "metrics", "batches", "write"]);
+    let metrics: Vec<&str> = report.lines().nth(2).unwrap().split('\t').collect();
+    assert!(metrics[1].parse::<f64>().unwrap() >= 0.01);
+    assert!(metrics[2].parse::<f64>().unwrap() > 90.0);
+}
@@ -0,0 +1,156 @@
+use log::{info, warn};
+use rust_htslib::bam::{self, Read};
+use std::fs::File;
+use std::io::Read as _;
+use std::thread;
+use std::time::Duration;
+
+use crate::Cli;
+
+/// Delay before the first retry, doubled for every subsequent attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Without REF_PATH, htslib downloads CRAM references from the EBI server
+/// pointing REF_PATH to the working directory avoids that
+pub fn disable_ref_download() {
+    match std::env::var("REF_PATH") {
+        Ok(ref_path) if !ref_path.is_empty() => {
+            info!("Using REF_PATH {ref_path} to find CRAM references")
+        }
+        _ => std::env::set_var("REF_PATH", "."),
+    }
+}
+
+/// Checks that the FASTA reference can be used for random access by htslib:
+/// it should be uncompressed or compressed with bgzip, not with plain gzip
+/// a bgzip block is a gzip member with a BC extra subfield
+/// a bgzipped reference also needs its .fai and .gzi index, which htslib otherwise tries to build next to it
+pub fn check_reference(path: &str) -> Result<(), String> {
+    let mut header = [0u8; 14];
+    let mut file =
+        File::open(path).map_err(|e| format!("Failure opening reference {path}: {e}"))?;
+    let read = file
+        .read(&mut header)
+        .map_err(|e| format!("Failure reading reference {path}: {e}"))?;
+    let gzip = read >= 2 && header[..2] == [0x1f, 0x8b];
+    let bgzip = read == header.len() && header[3] & 4 != 0 && header[12..14] == *b"BC";
+    if gzip && !bgzip {
+        return Err(format!(
+            "Reference {path} is compressed with gzip rather than bgzip, which htslib requires for random access\n\
+             Recompress it with: gunzip -c {path} | bgzip > reference.fa.gz"
+        ));
+    }
+    if bgzip {
+        let missing: Vec<String> = [".fai", ".gzi"]
+            .iter()
+            .map(|extension| format!("{path}{extension}"))
+            .filter(|index| !std::path::Path::new(index).exists())
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "Reference {path} is compressed with bgzip, but its index {} is missing\n\
+                 Index it with: samtools faidx {path}",
+                missing.join(" and ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Calls f until it succeeds, with at most `retries` retries and an exponential backoff
+pub fn retry_with_backoff<T, E: std::fmt::Display>(
+    retries: u32,
+    initial_backoff: Duration,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut backoff = initial_backoff;
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!("Attempt {attempt} of {retries} failed: {e}\nRetrying in {backoff:?}");
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Decoding the first record of a CRAM file requires the reference
+/// which may have to be fetched over the network, so this is retried with --ref-retries
+/// afterwards the reference is available in the REF_CACHE for the actual extraction
+pub fn probe_reference(cram_path: &str, args: &Cli) {
+    retry_with_backoff(args.ref_retries, INITIAL_BACKOFF, || {
+        let mut cram = bam::Reader::from_path(cram_path)?;
+        if let Some(reference) = &args.reference {
+            cram.set_reference(reference)?;
+        }
+        let mut record = bam::Record::new();
+        match cram.read(&mut record) {
+            Some(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    })
+    .unwrap_or_else(|e| panic!("Failure decoding CRAM file {cram_path}: {e}"));
+}
+
+#[test]
+fn test_retry_with_backoff() {
+    let mut calls = 0;
+    let result = retry_with_backoff(3, Duration::from_millis(1), || {
+        calls += 1;
+        if calls < 3 {
+            Err(format!("failure {calls}"))
+        } else {
+            Ok(calls)
+        }
+    });
+    assert_eq!(result, Ok(3));
+}
+
+#[test]
+fn test_retry_with_backoff_gives_up() {
+    let mut calls = 0;
+    let result: Result<(), String> = retry_with_backoff(1, Duration::from_millis(1), || {
+        calls += 1;
+        Err(format!("failure {calls}"))
+    });
+    assert_eq!(result, Err("failure 2".to_string()));
+}
+
+#[test]
+fn test_check_reference() {
+    let write = |filename: &str, content: &[u8]| {
+        std::fs::write(filename, content).unwrap();
+        check_reference(filename)
+    };
+    assert!(write("test-reference.fa", b">chr1\nACGT\n").is_ok());
+    // the empty bgzip block at the end of every bgzip file
+    let bgzip_eof = [
+        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02,
+        0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    // the index of a previous run is removed first
+    for index in [
+        "test-reference-bgzip.fa.gz.fai",
+        "test-reference-bgzip.fa.gz.gzi",
+    ] {
+        let _ = std::fs::remove_file(index);
+    }
+    let error = write("test-reference-bgzip.fa.gz", &bgzip_eof).unwrap_err();
+    assert!(error.contains("test-reference-bgzip.fa.gz.fai and test-reference-bgzip.fa.gz.gzi"));
+    std::fs::write("test-reference-bgzip.fa.gz.fai", b"").unwrap();
+    std::fs::write("test-reference-bgzip.fa.gz.gzi", b"").unwrap();
+    assert!(check_reference("test-reference-bgzip.fa.gz").is_ok());
+    // a gzip header without extra field
+    let gzip = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x03, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let error = write("test-reference-gzip.fa.gz", &gzip).unwrap_err();
+    assert!(error.contains("bgzip"));
+    assert!(check_reference("test-reference-missing.fa").is_err());
+}
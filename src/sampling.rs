@@ -0,0 +1,80 @@
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use twox_hash::XxHash64;
+
+/// Sampling rates per read length bin, as (minimum length, rate) sorted by minimum length
+/// reads shorter than the first bin are all kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct LengthSampling {
+    bins: Vec<(u64, f64)>,
+}
+
+impl LengthSampling {
+    /// The sampling rate of reads of this length
+    fn rate(&self, length: u64) -> f64 {
+        self.bins
+            .iter()
+            .rev()
+            .find(|(min_length, _)| length >= *min_length)
+            .map_or(1.0, |(_, rate)| *rate)
+    }
+
+    /// Whether to keep the read, based on a fixed-seed hash of its name
+    /// so that the same reads are sampled in every run, also with another build
+    pub fn keep(&self, name: &[u8], length: u64) -> bool {
+        let mut hasher = XxHash64::with_seed(0);
+        name.hash(&mut hasher);
+        (hasher.finish() as f64 / u64::MAX as f64) < self.rate(length)
+    }
+}
+
+impl FromStr for LengthSampling {
+    type Err = String;
+
+    /// Parses comma-separated minimum length and rate pairs, e.g. 0:0.1,10000:0.5,50000:1
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bins = s
+            .split(',')
+            .map(|bin| {
+                let (min_length, rate) = bin
+                    .split_once(':')
+                    .ok_or_else(|| format!("Expected min_length:rate, got {bin}"))?;
+                let min_length = min_length
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid minimum length {min_length}"))?;
+                let rate = rate
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid sampling rate {rate}"))?;
+                if !(0.0..=1.0).contains(&rate) {
+                    return Err(format!("Sampling rate {rate} should be between 0 and 1"));
+                }
+                Ok((min_length, rate))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        bins.sort_by_key(|(min_length, _)| *min_length);
+        Ok(LengthSampling { bins })
+    }
+}
+
+#[test]
+fn test_parse_length_sampling() {
+    let sampling: LengthSampling = "10000:0.5,0:0.1".parse().unwrap();
+    assert_eq!(sampling.bins, vec![(0, 0.1), (10000, 0.5)]);
+    assert_eq!(sampling.rate(9999), 0.1);
+    assert_eq!(sampling.rate(10000), 0.5);
+    assert!("0:1.5".parse::<LengthSampling>().is_err());
+    assert!("1000".parse::<LengthSampling>().is_err());
+}
+
+#[test]
+fn test_length_sampling_rates() {
+    let sampling: LengthSampling = "1000:0.1,5000:0.5,20000:1".parse().unwrap();
+    for (length, rate) in [(500, 1.0), (2000, 0.1), (8000, 0.5), (30000, 1.0)] {
+        let kept = (0..10_000)
+            .filter(|i| sampling.keep(format!("read_{length}_{i}").as_bytes(), length))
+            .count();
+        assert!((kept as f64 / 10_000.0 - rate).abs() < 0.02);
+    }
+}
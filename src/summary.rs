@@ -0,0 +1,315 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::File;
+
+use crate::metrics::ReadMetrics;
+
+/// Running mean, variance, minimum and maximum, using Welford's algorithm
+/// so that no values have to be kept in memory
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunningStats {
+    count: u64,
+    mean: f64,
+    m2: f64,
+    min: f64,
+    max: f64,
+}
+
+impl RunningStats {
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        if self.count == 1 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.mean)
+    }
+
+    /// The sample variance
+    pub fn variance(&self) -> Option<f64> {
+        (self.count > 1).then(|| self.m2 / (self.count - 1) as f64)
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "mean": self.mean(),
+            "stdev": self.variance().map(f64::sqrt),
+            "min": self.min(),
+            "max": self.max(),
+        })
+    }
+}
+
+/// Summary statistics of the written reads, updated as reads are processed
+#[derive(Debug, Default)]
+pub struct Summary {
+    identities: RunningStats,
+    lengths: RunningStats,
+    aligned_lengths: RunningStats,
+    mapqs: RunningStats,
+    clamped_identities: u64,
+    bases: u64,
+    aligned_bases: u64,
+    /// The sum of the identities multiplied by the read lengths, and of the lengths of these reads
+    weighted_identities: f64,
+    identity_bases: u64,
+    /// The fragment lengths with --fragment-lengths, once per pair, kept in memory for the median
+    fragment_lengths: Vec<u64>,
+}
+
+impl Summary {
+    pub fn add(&mut self, read: &ReadMetrics) {
+        // NaN is written for a missing identity with --on-missing-identity nan
+        if let Some(identity) = read.identity.filter(|identity| !identity.is_nan()) {
+            self.identities.add(identity);
+            self.weighted_identities += identity * read.length as f64;
+            self.identity_bases += read.length;
+        }
+        self.lengths.add(read.length as f64);
+        if let Some(aligned_length) = read.aligned_length {
+            self.aligned_lengths.add(aligned_length as f64);
+        }
+        self.mapqs.add(read.mapq as f64);
+        self.clamped_identities += read.identity_clamped as u64;
+        self.bases += read.length;
+        self.aligned_bases += read.aligned_length.unwrap_or(0);
+    }
+
+    /// Adds the fragment length of a pair, which both mates have
+    pub fn add_fragment(&mut self, fragment_length: u64) {
+        self.fragment_lengths.push(fragment_length);
+    }
+
+    /// The number of reads of which the identity was outside the --identity-range
+    pub fn clamped_identities(&self) -> u64 {
+        self.clamped_identities
+    }
+
+    /// The total number of bases and of aligned bases
+    pub fn bases(&self) -> (u64, u64) {
+        (self.bases, self.aligned_bases)
+    }
+
+    /// The summary, with the total numbers of bases with --count-bases
+    pub fn to_json(&self, count_bases: bool) -> serde_json::Value {
+        let mut identities = self.identities.to_json();
+        // the mean identity per base instead of per read
+        identities["length_weighted_mean"] = json!((self.identity_bases > 0)
+            .then(|| self.weighted_identities / self.identity_bases as f64));
+        let mut json = json!({
+            "reads": self.lengths.count(),
+            "identities": identities,
+            "lengths": self.lengths.to_json(),
+            "aligned_lengths": self.aligned_lengths.to_json(),
+            "mapQ": self.mapqs.to_json(),
+            "clamped_identities": self.clamped_identities,
+        });
+        if !self.fragment_lengths.is_empty() {
+            let mut sorted = self.fragment_lengths.clone();
+            sorted.sort_unstable();
+            json["fragment_lengths"] = json!({
+                "fragments": sorted.len(),
+                "mean": sorted.iter().sum::<u64>() as f64 / sorted.len() as f64,
+                "median": median(&sorted),
+            });
+        }
+        if count_bases {
+            json["total_bases"] = json!(self.bases);
+            json["total_aligned_bases"] = json!(self.aligned_bases);
+        }
+        json
+    }
+
+    pub fn save(&self, filename: &str, count_bases: bool) {
+        let file = File::create(filename).expect("create summary file error");
+        serde_json::to_writer_pretty(file, &self.to_json(count_bases))
+            .expect("write summary error");
+    }
+}
+
+/// Read length statistics which require all lengths, kept in memory
+#[derive(Debug, Default)]
+pub struct LengthStats {
+    lengths: Vec<u64>,
+    /// The length per read name and whether it is of the primary alignment
+    unique: HashMap<Vec<u8>, (u64, bool)>,
+}
+
+impl LengthStats {
+    pub fn add(&mut self, length: u64) {
+        self.lengths.push(length);
+    }
+
+    /// Adds an alignment, counting each read only once
+    /// using the length of its primary alignment, or of its longest other alignment
+    pub fn add_unique(&mut self, name: &[u8], length: u64, primary: bool) {
+        match self.unique.get_mut(name) {
+            Some(read) => {
+                if primary || (!read.1 && length > read.0) {
+                    *read = (length, primary);
+                }
+            }
+            None => {
+                self.unique.insert(name.to_vec(), (length, primary));
+            }
+        }
+    }
+
+    pub fn into_json(mut self) -> serde_json::Value {
+        self.lengths
+            .extend(self.unique.values().map(|(length, _)| *length));
+        self.lengths.sort_unstable();
+        let total: u64 = self.lengths.iter().sum();
+        json!({
+            "reads": self.lengths.len(),
+            "total_bases": total,
+            "mean": (!self.lengths.is_empty()).then(|| total as f64 / self.lengths.len() as f64),
+            "median": median(&self.lengths),
+            "N50": n50(&self.lengths, total),
+        })
+    }
+
+    pub fn save(self, filename: &str) {
+        let file = File::create(filename).expect("create length stats file error");
+        serde_json::to_writer_pretty(file, &self.into_json()).expect("write length stats error");
+    }
+}
+
+/// The median of sorted values
+fn median(sorted: &[u64]) -> Option<f64> {
+    let mid = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((sorted[mid - 1] + sorted[mid]) as f64 / 2.0),
+        _ => Some(sorted[mid] as f64),
+    }
+}
+
+/// The length such that reads at least this long contain half of the total bases
+fn n50(sorted: &[u64], total: u64) -> Option<u64> {
+    let mut cumulative = 0;
+    sorted.iter().rev().copied().find(|length| {
+        cumulative += length;
+        cumulative * 2 >= total
+    })
+}
+
+#[test]
+fn test_running_stats() {
+    let values: Vec<f64> = (0..10_000)
+        .map(|i| ((i * 7919) % 1013) as f64 * 1.5 + 1e6)
+        .collect();
+    let mut stats = RunningStats::default();
+    values.iter().for_each(|v| stats.add(*v));
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance =
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+    assert!((stats.mean().unwrap() - mean).abs() < 1e-6);
+    assert!((stats.variance().unwrap() - variance).abs() / variance < 1e-9);
+    assert_eq!(
+        stats.min().unwrap(),
+        values.iter().cloned().fold(f64::INFINITY, f64::min)
+    );
+    assert_eq!(
+        stats.max().unwrap(),
+        values.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+    );
+}
+
+#[test]
+fn test_empty_running_stats() {
+    let stats = RunningStats::default();
+    assert_eq!(stats.mean(), None);
+    assert_eq!(stats.variance(), None);
+}
+
+#[test]
+fn test_length_stats() {
+    let mut stats = LengthStats::default();
+    for length in [2, 3, 4, 5, 6, 7, 8, 9, 10, 0] {
+        stats.add(length);
+    }
+    let json = stats.into_json();
+    // 10 + 9 + 8 = 27, which is half of the 54 bases
+    assert_eq!(json["N50"], 8);
+    assert_eq!(json["total_bases"], 54);
+    assert_eq!(json["median"], 5.5);
+    assert_eq!(json["mean"], 5.4);
+    assert_eq!(
+        LengthStats::default().into_json()["N50"],
+        serde_json::Value::Null
+    );
+}
+
+#[test]
+fn test_unique_length_stats() {
+    let mut stats = LengthStats::default();
+    // a hard-clipped supplementary alignment before and after the primary
+    stats.add_unique(b"read1", 400, false);
+    stats.add_unique(b"read1", 1000, true);
+    stats.add_unique(b"read1", 600, false);
+    // a read of which only supplementary alignments are present
+    stats.add_unique(b"read2", 300, false);
+    stats.add_unique(b"read2", 500, false);
+    let json = stats.into_json();
+    assert_eq!(json["reads"], 2);
+    assert_eq!(json["total_bases"], 1500);
+}
+
+#[test]
+fn test_length_weighted_identity() {
+    let mut summary = Summary::default();
+    for (identity, length) in [(80.0, 100), (99.0, 100_000), (90.0, 1000)] {
+        summary.add(&ReadMetrics {
+            identity: Some(identity),
+            length,
+            ..Default::default()
+        });
+    }
+    let identities = &summary.to_json(false)["identities"];
+    let mean = identities["mean"].as_f64().unwrap();
+    let weighted = identities["length_weighted_mean"].as_f64().unwrap();
+    assert!((mean - 269.0 / 3.0).abs() < 1e-9);
+    assert!((weighted - (8000.0 + 9_900_000.0 + 90_000.0) / 101_100.0).abs() < 1e-9);
+    // the long read with the highest identity dominates
+    assert!(weighted > mean);
+    assert_eq!(
+        Summary::default().to_json(false)["identities"]["length_weighted_mean"],
+        serde_json::Value::Null
+    );
+}
+
+#[test]
+fn test_clamped_identities() {
+    let mut summary = Summary::default();
+    for clamped in [false, true, true] {
+        summary.add(&ReadMetrics {
+            identity: Some(100.0),
+            identity_clamped: clamped,
+            ..Default::default()
+        });
+    }
+    assert_eq!(summary.to_json(false)["clamped_identities"], 2);
+    assert_eq!(summary.to_json(false).get("total_bases"), None);
+}
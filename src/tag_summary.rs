@@ -0,0 +1,76 @@
+use rust_htslib::bam::{record::Aux, Read};
+use std::collections::BTreeMap;
+
+use crate::Cli;
+
+/// The SAM type of an aux field, with the element type of arrays
+/// integers are stored in the smallest BAM type fitting the value, which varies between reads
+fn type_code(aux: &Aux) -> &'static str {
+    match aux {
+        Aux::Char(_) => "A",
+        Aux::I8(_) | Aux::U8(_) | Aux::I16(_) | Aux::U16(_) | Aux::I32(_) | Aux::U32(_) => "i",
+        Aux::Float(_) | Aux::Double(_) => "f",
+        Aux::String(_) => "Z",
+        Aux::HexByteArray(_) => "H",
+        Aux::ArrayI8(_) => "B:c",
+        Aux::ArrayU8(_) => "B:C",
+        Aux::ArrayI16(_) => "B:s",
+        Aux::ArrayU16(_) => "B:S",
+        Aux::ArrayI32(_) => "B:i",
+        Aux::ArrayU32(_) => "B:I",
+        Aux::ArrayFloat(_) => "B:f",
+    }
+}
+
+/// Counts the reads having each aux tag, per type, among the first `reads` reads of every file
+/// returns (tag, type, count) sorted by tag and type
+/// the files are opened as for the extraction, so that cram files are decoded with the --reference
+pub fn tag_summary(
+    bam_paths: &[String],
+    reads: usize,
+    args: &Cli,
+) -> Vec<(String, &'static str, usize)> {
+    let mut counts = BTreeMap::new();
+    for bam_path in bam_paths {
+        let mut bam = crate::open_reader(bam_path, args);
+        for read in bam.records().take(reads) {
+            let read = read.expect("Failure parsing Bam file");
+            for aux in read.aux_iter() {
+                let (tag, value) = aux.expect("Failure parsing aux data");
+                let tag = String::from_utf8_lossy(tag).to_string();
+                *counts.entry((tag, type_code(&value))).or_insert(0) += 1;
+            }
+        }
+    }
+    counts
+        .into_iter()
+        .map(|((tag, type_code), count)| (tag, type_code, count))
+        .collect()
+}
+
+/// Prints the tag summary as a tab-separated table
+pub fn print_tag_summary(summary: &[(String, &'static str, usize)]) {
+    println!("tag\ttype\treads");
+    for (tag, type_code, count) in summary {
+        println!("{tag}\t{type_code}\t{count}");
+    }
+}
+
+#[test]
+fn test_tag_summary() {
+    use clap::Parser;
+    let args = Cli::parse_from([
+        "make_arrow",
+        "test-data/small-test-phased.bam",
+        "--tag-summary",
+        "100",
+        "--threads",
+        "2",
+    ]);
+    let summary = tag_summary(&args.inputs(), 100, &args);
+    let nm = summary.iter().find(|(tag, _, _)| tag == "NM").unwrap();
+    assert_eq!(nm.1, "i");
+    assert_eq!(nm.2, 100);
+    // the test data has no de tags, which minimap2 would add
+    assert!(!summary.iter().any(|(tag, _, _)| tag == "de"));
+}
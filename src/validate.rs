@@ -0,0 +1,109 @@
+use rust_htslib::bam::{self, Read};
+use std::fs::File;
+use std::io::{Read as _, Seek, SeekFrom};
+
+use crate::Cli;
+
+/// The number of reads of which the NM and de tags are checked
+const SAMPLED_READS: usize = 1000;
+
+/// The empty BGZF block which ends every complete BAM file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Whether the file starts with a BGZF block header, of a gzip member with the BC extra subfield
+fn is_bgzf(file: &mut File) -> std::io::Result<bool> {
+    let mut start = [0u8; 14];
+    if file.read(&mut start)? < start.len() {
+        return Ok(false);
+    }
+    Ok(start[..4] == BGZF_EOF[..4] && start[12..14] == *b"BC")
+}
+
+/// A BAM file without EOF block is likely truncated, in which case the reads after
+/// the last complete block are silently missing
+/// only BGZF-compressed files are checked, so not SAM or CRAM files nor stdin
+pub fn truncation_problem(bam_path: &str) -> Option<String> {
+    if bam_path == "-" || bam_path.ends_with(".cram") {
+        return None;
+    }
+    let has_eof = File::open(bam_path).and_then(|mut file| {
+        if !is_bgzf(&mut file)? {
+            return Ok(true);
+        }
+        if file.metadata()?.len() < BGZF_EOF.len() as u64 {
+            return Ok(false);
+        }
+        let mut end = [0u8; BGZF_EOF.len()];
+        file.seek(SeekFrom::End(-(BGZF_EOF.len() as i64)))?;
+        file.read_exact(&mut end)?;
+        Ok(end == BGZF_EOF)
+    });
+    match has_eof {
+        Ok(false) => Some(format!(
+            "{bam_path} has no EOF block and may be truncated, reads at the end of the file could be missing"
+        )),
+        _ => None,
+    }
+}
+
+/// Checks that the input can be processed with the arguments, without extracting metrics:
+/// the file can be opened and is not truncated, the index is present if regions are requested,
+/// the reference is available to decode CRAM files and sampled reads have an NM or de tag
+/// returns the problems found, which is empty if the input is valid
+pub fn validate(bam_path: &str, args: &Cli) -> Vec<String> {
+    let mut problems: Vec<String> = truncation_problem(bam_path).into_iter().collect();
+    let mut bam = match bam::Reader::from_path(bam_path) {
+        Ok(bam) => bam,
+        Err(e) => return vec![format!("Failure opening {bam_path}: {e}")],
+    };
+    if let Some(reference) = &args.reference {
+        if let Err(e) = crate::reference::check_reference(reference) {
+            return vec![e];
+        }
+        if let Err(e) = bam.set_reference(reference) {
+            return vec![format!("Failure setting reference {reference}: {e}")];
+        }
+    }
+    if args.region.is_some() || args.regions_bed.is_some() || args.per_contig_window.is_some() {
+        match bam::IndexedReader::from_path(bam_path) {
+            Ok(mut indexed) => {
+                if let Some(region) = &args.region {
+                    if let Err(e) = indexed.fetch(region.as_str()) {
+                        problems.push(format!("Failure fetching region {region}: {e}"));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!(
+                "A region is requested but {bam_path} can not be opened with its index: {e}"
+            )),
+        }
+    }
+    // decoding CRAM records requires the reference
+    let mut untagged = 0;
+    let mut sampled = 0;
+    for read in bam.records().take(SAMPLED_READS) {
+        let read = match read {
+            Ok(read) => read,
+            Err(e) => {
+                problems.push(format!("Failure decoding reads of {bam_path}: {e}"));
+                break;
+            }
+        };
+        if read.is_unmapped() || read.cigar_len() == 0 {
+            continue;
+        }
+        sampled += 1;
+        if read.aux(b"NM").is_err() && read.aux(b"de").is_err() {
+            untagged += 1;
+        }
+    }
+    if untagged > 0 {
+        problems.push(format!(
+            "{untagged} of {sampled} sampled reads have neither an NM nor a de tag"
+        ));
+    }
+    problems
+}
@@ -0,0 +1,88 @@
+use arrow::ipc::reader::StreamReader;
+use rust_htslib::bam::{self, Read};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The size of the empty BGZF block which marks the end of a bam file
+const BGZF_EOF_LENGTH: usize = 28;
+
+/// Writes the first reads of the test file to a smaller bam file
+fn write_test_bam(path: &str, reads: usize) {
+    let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+    let mut writer = bam::Writer::from_path(
+        path,
+        &bam::Header::from_template(reader.header()),
+        bam::Format::Bam,
+    )
+    .unwrap();
+    for read in reader.records().take(reads) {
+        writer.write(&read.unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn test_arrow_stream_stdout() {
+    write_test_bam("test-arrow-stream.bam", 35);
+    let bam = std::fs::read("test-arrow-stream.bam").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_make_arrow"))
+        .args([
+            "-",
+            "-o",
+            "-",
+            "--format",
+            "arrow-stream",
+            "--flush-each-batch",
+            "--batch-size",
+            "10",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // the input is complete apart from its end of file marker, so the extraction keeps running
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(&bam[..bam.len() - BGZF_EOF_LENGTH])
+        .unwrap();
+    stdin.flush().unwrap();
+    let mut reader = StreamReader::try_new(child.stdout.take().unwrap(), None).unwrap();
+    let mut rows = 0;
+    for _ in 0..2 {
+        rows += reader.next().unwrap().unwrap().num_rows();
+    }
+    assert_eq!(rows, 20);
+    assert!(child.try_wait().unwrap().is_none());
+    stdin
+        .write_all(&bam[bam.len() - BGZF_EOF_LENGTH..])
+        .unwrap();
+    drop(stdin);
+    for batch in reader {
+        rows += batch.unwrap().num_rows();
+    }
+    // the 13 secondary alignments of the 35 reads are filtered
+    assert_eq!(rows, 22);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_log_file() {
+    write_test_bam("test-log-file.bam", 20);
+    let output = Command::new(env!("CARGO_BIN_EXE_make_arrow"))
+        .args([
+            "test-log-file.bam",
+            "-o",
+            "test-log-file.arrow",
+            "--log-file",
+            "test-log-file.log",
+            "--threads",
+            "100000",
+        ])
+        .env("RUST_LOG", "warn")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // the warning of the clamped threads is written to the log file instead of stderr
+    let log = std::fs::read_to_string("test-log-file.log").unwrap();
+    assert!(log.contains("Requested 100000 threads"));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Requested 100000 threads"));
+}